@@ -2,8 +2,11 @@ extern crate structopt;
 
 extern crate linkle;
 
+use failure::Backtrace;
 use linkle::error::ResultExt;
+use linkle::utils::to_hex;
 use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 use structopt::StructOpt;
@@ -50,6 +53,30 @@ enum Opt {
         /// Sets the output file to use.
         output_file: String,
     },
+    /// Convert an NRO, NSO or KIP file back into an ELF file.
+    #[structopt(name = "elf")]
+    Elf {
+        /// Sets the input NRO, NSO or KIP file to use.
+        input_file: String,
+        /// Sets the output file to use.
+        output_file: String,
+    },
+    /// Create an NPDM file from a JSON file.
+    #[structopt(name = "npdm")]
+    Npdm {
+        /// Sets the input NPDM JSON file to use.
+        input_file: String,
+        /// Sets the output file to use.
+        output_file: String,
+    },
+    /// Extract the NPDM JSON of a KIP file.
+    #[structopt(name = "kip_npdm")]
+    KipNpdm {
+        /// Sets the input KIP file to use.
+        input_file: String,
+        /// Sets the output NPDM JSON file to use.
+        output_file: String,
+    },
     /// Create a PFS0 or NSP file from a directory.
     #[structopt(name = "pfs0"/*, raw(alias = "nsp")*/)]
     Pfs0 {
@@ -66,6 +93,255 @@ enum Opt {
         /// Sets the output directory to extract the PFS0 into.
         output_directory: String,
     },
+    /// Create an HFS0 file from a directory.
+    #[structopt(name = "hfs0")]
+    Hfs0 {
+        /// Sets the input directory to use.
+        input_directory: String,
+        /// Sets the output file to use.
+        output_file: String,
+    },
+    /// Extract an HFS0 file.
+    #[structopt(name = "hfs0_extract")]
+    Hfs0Extract {
+        /// Sets the input HFS0 to use.
+        input_file: String,
+        /// Sets the output directory to extract the HFS0 into.
+        output_directory: String,
+    },
+    /// Extract the partitions of an XCI file.
+    #[structopt(name = "xci_extract")]
+    XciExtract {
+        /// Sets the input XCI to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the partitions into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Create an INI1 file from KIPs, or from ELFs and their NPDM JSON.
+    #[structopt(name = "ini1")]
+    Ini1 {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Adds a KIP file.
+        #[structopt(parse(from_os_str), long = "kip", raw(number_of_values = "1"))]
+        kip_files: Vec<PathBuf>,
+        /// Adds a KIP built from this ELF file, paired with the --npdm at the
+        /// same position.
+        #[structopt(long = "elf", raw(number_of_values = "1"))]
+        elf_files: Vec<String>,
+        /// Sets the NPDM JSON file to use for the --elf at the same position.
+        #[structopt(long = "npdm", raw(number_of_values = "1"))]
+        npdm_files: Vec<String>,
+    },
+    /// Extract the KIPs of an INI1 file.
+    #[structopt(name = "ini1_extract")]
+    Ini1Extract {
+        /// Sets the input INI1 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the KIPs into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+    },
+    /// Extract the warmboot firmware, the NX bootloader and the secure monitor
+    /// of a package1.
+    #[structopt(name = "package1_extract")]
+    Package1Extract {
+        /// Sets the input package1 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the package1 into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract the kernel, the INI1 and the KIPs of a package2.
+    #[structopt(name = "package2_extract")]
+    Package2Extract {
+        /// Sets the input package2 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the package2 into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Rebuild a package2, replacing the KIPs with the same name as the given
+    /// ones.
+    #[structopt(name = "package2")]
+    Package2 {
+        /// Sets the input package2 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Sets the KIPs to replace.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        kip_files: Vec<PathBuf>,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Decrypt a NAX0 file from the SD card.
+    #[structopt(name = "nax0_extract")]
+    Nax0Extract {
+        /// Sets the input NAX0 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Sets the path of the file on the SD card, relative to
+        /// Nintendo/Contents or Nintendo/save. Defaults to the part of the
+        /// input path after these folders.
+        #[structopt(long = "sd-path")]
+        sd_path: Option<String>,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract the files of a save data image.
+    #[structopt(name = "save_extract")]
+    SaveExtract {
+        /// Sets the input save data to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the save data into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract a RomFS file.
+    #[structopt(name = "romfs_extract")]
+    RomfsExtract {
+        /// Sets the input RomFS to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the RomFS into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+    },
+    /// Create a program NCA from an ExeFS directory and an optional RomFS directory.
+    #[structopt(name = "nca")]
+    Nca {
+        /// Sets the ExeFS directory to use. It must contain main and main.npdm.
+        #[structopt(parse(from_os_str))]
+        exefs_directory: PathBuf,
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Sets the title ID of the NCA, in hexadecimal.
+        #[structopt(short = "t", long = "title-id", parse(try_from_str = "parse_title_id"))]
+        title_id: u64,
+        /// Sets the directory to use as RomFS.
+        #[structopt(parse(from_os_str), long = "romfs-path")]
+        romfs: Option<PathBuf>,
+        /// Sets the key generation to encrypt the NCA with.
+        #[structopt(short = "g", long = "key-generation", default_value = "0")]
+        key_generation: u8,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Create a CNMT file and its .cnmt.xml from a set of NCAs.
+    #[structopt(name = "cnmt")]
+    Cnmt {
+        /// Sets the output file to use. The XML is written next to it, with an
+        /// additional .xml extension.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Sets the NCAs making up the title.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        nca_files: Vec<PathBuf>,
+        /// Sets the type of the title: Application, Patch, AddOnContent,
+        /// SystemProgram or SystemData.
+        #[structopt(long = "type", default_value = "Application")]
+        meta_type: String,
+        /// Sets the title ID, in hexadecimal. Defaults to the title ID of the
+        /// first NCA.
+        #[structopt(short = "t", long = "title-id", parse(try_from_str = "parse_title_id"))]
+        title_id: Option<u64>,
+        /// Sets the version of the title.
+        #[structopt(long = "title-version", default_value = "0")]
+        version: u32,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Create an installable NSP from an ELF file.
+    #[structopt(name = "nsp")]
+    Nsp {
+        /// Sets the input file to use.
+        input_file: String,
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Sets the NPDM JSON to use. Its title ID is used for the title.
+        #[structopt(long = "npdm-path")]
+        npdm: String,
+        /// Sets the NACP JSON to use.
+        #[structopt(long = "nacp-path")]
+        nacp: String,
+        /// Sets the icon to use. It must be a 256x256 JPEG.
+        #[structopt(parse(from_os_str), long = "icon-path")]
+        icon: Option<PathBuf>,
+        /// Sets the directory to use as RomFS.
+        #[structopt(parse(from_os_str), long = "romfs-path")]
+        romfs: Option<PathBuf>,
+        /// Sets the key generation to encrypt the NCAs with.
+        #[structopt(short = "g", long = "key-generation", default_value = "0")]
+        key_generation: u8,
+        /// Encrypts the program and control NCAs with a title key, and adds
+        /// the matching ticket.
+        #[structopt(long = "ticket")]
+        ticket: bool,
+        /// Sets the certificate chain to ship along with the ticket.
+        #[structopt(parse(from_os_str), long = "cert-path")]
+        cert: Option<PathBuf>,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
     /// Create a NACP file from a JSON file.
     #[structopt(name = "nacp")]
     Nacp {
@@ -102,6 +378,68 @@ enum Opt {
         /// Show console unique keys along with non-console-unique keys.
         #[structopt(long = "console-unique")]
         show_console_unique: bool,
+
+        /// Report whether each key was loaded, derived, or is missing, and
+        /// which parents blocked its derivation.
+        #[structopt(long = "report")]
+        report: bool,
+
+        /// Print the report as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Only report the keys of master key revisions up to this one, in
+        /// hex. Defaults to the latest revision with a known key.
+        #[structopt(long = "revision", parse(try_from_str = "parse_hex"))]
+        revision: Option<usize>,
+
+        /// Print the keys and title keys as JSON.
+        #[structopt(
+            long = "export-json",
+            raw(conflicts_with_all = r#"&["report", "json", "minimal"]"#)
+        )]
+        export_json: bool,
+
+        /// Fail if a well-known key of the keyset has a wrong value.
+        #[structopt(
+            long = "check",
+            raw(
+                conflicts_with_all = r#"&["minimal", "show_console_unique", "report", "json", "revision", "export_json", "split"]"#
+            )
+        )]
+        check: bool,
+
+        /// Print the keyset of a Mariko unit, without the keys of the Erista
+        /// boot chain.
+        #[structopt(
+            long = "mariko",
+            raw(conflicts_with_all = r#"&["json", "export_json", "report", "split", "check"]"#)
+        )]
+        mariko: bool,
+
+        /// Write the keys to this directory, split between the common keys,
+        /// console.keys for the console unique keys, and title.keys.
+        #[structopt(
+            parse(from_os_str),
+            long = "split",
+            raw(
+                conflicts_with_all = r#"&["show_console_unique", "report", "json", "export_json"]"#
+            )
+        )]
+        split: Option<PathBuf>,
+    },
+    /// Print the title keys found in a set of tickets, in title.keys format.
+    #[structopt(name = "titlekeys")]
+    Titlekeys {
+        /// Sets the input tickets to use.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        input_files: Vec<PathBuf>,
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
     },
 }
 
@@ -177,10 +515,73 @@ fn create_kip(
             .map_err(|err| (err, output_file))?,
         &npdm,
     )
+    .with_path(output_file)?;
+    Ok(())
+}
+
+fn create_elf(input_path: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    use linkle::format::nxo::{Kip1File, NroFile, NsoFile};
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let mut magic = [0; 0x14];
+    input_file
+        .read_exact(&mut magic)
+        .map_err(|err| (err, input_path))?;
+    input_file
+        .seek(SeekFrom::Start(0))
+        .map_err(|err| (err, input_path))?;
+
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut out_file = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    match (&magic[0..4], &magic[0x10..0x14]) {
+        (b"NSO0", _) => NsoFile::from_reader(input_file)
+            .with_path(input_path)?
+            .write_elf(&mut out_file),
+        (b"KIP1", _) => Kip1File::from_reader(input_file)
+            .with_path(input_path)?
+            .write_elf(&mut out_file),
+        (_, b"NRO0") => NroFile::from_reader(input_file)
+            .with_path(input_path)?
+            .write_elf(&mut out_file),
+        _ => {
+            println!("{} is not an NRO, NSO or KIP file", input_path);
+            process::exit(1)
+        }
+    }
     .map_err(|err| (err, output_file))?;
     Ok(())
 }
 
+fn create_npdm(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    let npdm = linkle::format::npdm::Npdm::from_file(input_file).with_path(input_file)?;
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut out_file = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    npdm.write(&mut out_file).with_path(output_file)?;
+    Ok(())
+}
+
+fn extract_kip_npdm(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    let kip = linkle::format::nxo::Kip1File::from_reader(
+        File::open(input_file).map_err(|err| (err, input_file))?,
+    )
+    .with_path(input_file)?;
+    let npdm = linkle::format::nxo::KipNpdm::from_kip1(&kip).with_path(input_file)?;
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let out_file = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    serde_json::to_writer_pretty(out_file, &npdm)?;
+    Ok(())
+}
+
 fn create_pfs0(input_directory: &str, output_file: &str) -> Result<(), linkle::error::Error> {
     let mut pfs0 = linkle::format::pfs0::Pfs0::from_directory(&input_directory)?;
     let mut option = OpenOptions::new();
@@ -194,18 +595,18 @@ fn create_pfs0(input_directory: &str, output_file: &str) -> Result<(), linkle::e
     Ok(())
 }
 
-fn extract_pfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::error::Error> {
-    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
-    let pfs0 = linkle::format::pfs0::Pfs0::from_reader(input_file).with_path(input_path)?;
+fn extract_files(
+    files: impl Iterator<Item = std::io::Result<linkle::format::pfs0::Pfs0File>>,
+    path: &Path,
+) -> Result<(), linkle::error::Error> {
     let mut option = OpenOptions::new();
     let output_option = option.write(true).create(true).truncate(true);
-    let path = Path::new(output_directory);
     match std::fs::create_dir(path) {
         Ok(()) => (),
         Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
         Err(err) => return Err((err, path).into()),
     }
-    for file in pfs0.files() {
+    for file in files {
         let mut file = file?;
         let name = path.join(file.file_name());
         println!("Writing {}", file.file_name());
@@ -215,6 +616,518 @@ fn extract_pfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::
     Ok(())
 }
 
+fn extract_pfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let pfs0 = linkle::format::pfs0::Pfs0::from_reader(input_file).with_path(input_path)?;
+    extract_files(pfs0.files(), Path::new(output_directory))
+}
+
+fn create_hfs0(input_directory: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    let mut hfs0 = linkle::format::hfs0::Hfs0::from_directory(&input_directory)
+        .map_err(|err| (err, input_directory))?;
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    hfs0.write_hfs0(
+        &mut output_option
+            .open(output_file)
+            .map_err(|err| (err, output_file))?,
+    )
+    .map_err(|err| (err, output_file))?;
+    Ok(())
+}
+
+fn extract_hfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let mut hfs0 = linkle::format::hfs0::Hfs0::from_reader(input_file).with_path(input_path)?;
+    hfs0.verify().with_path(input_path)?;
+    extract_files(hfs0.files(), Path::new(output_directory))
+}
+
+fn extract_xci(
+    input_path: &Path,
+    output_directory: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let xci = linkle::format::xci::Xci::from_reader(input_file, &keys).with_path(input_path)?;
+    match std::fs::create_dir(output_directory) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, output_directory).into()),
+    }
+    for name in xci.partition_names() {
+        let mut partition = xci.open_partition(name).with_path(input_path)?;
+        partition.verify().with_path(input_path)?;
+        extract_files(partition.files(), &output_directory.join(name))?;
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), linkle::error::Error> {
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|err| (err, path))?;
+    output_file.write_all(data).map_err(|err| (err, path))?;
+    Ok(())
+}
+
+/// Whether the name is a plain file name, which can't escape the directory
+/// it is joined to.
+fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(|c| c == '/' || c == '\\')
+}
+
+fn create_ini1(
+    kip_paths: &[PathBuf],
+    elf_paths: &[String],
+    npdm_paths: &[String],
+    output_path: &Path,
+) -> Result<(), linkle::error::Error> {
+    if elf_paths.len() != npdm_paths.len() {
+        return Err(linkle::error::Error::InvalidIni1(
+            "every ELF needs an NPDM",
+            Backtrace::new(),
+        ));
+    }
+    let mut ini1 = linkle::format::ini1::Ini1::new();
+    for kip_path in kip_paths {
+        let kip = std::fs::read(kip_path).map_err(|err| (err, kip_path.as_path()))?;
+        ini1.push_kip(kip).with_path(kip_path)?;
+    }
+    for (elf_path, npdm_path) in elf_paths.iter().zip(npdm_paths) {
+        let mut nxo =
+            linkle::format::nxo::NxoFile::from_elf(elf_path).map_err(|err| (err, elf_path))?;
+        let npdm = serde_json::from_reader(File::open(npdm_path).map_err(|err| (err, npdm_path))?)?;
+        ini1.push_elf(&mut nxo, &npdm).with_path(elf_path)?;
+    }
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .map_err(|err| (err, output_path))?;
+    ini1.write(&mut output_file)
+        .map_err(|err| (err, output_path))?;
+    Ok(())
+}
+
+fn extract_kips(
+    ini1: &linkle::format::ini1::Ini1,
+    output_directory: &Path,
+) -> Result<(), linkle::error::Error> {
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+    for (idx, kip) in ini1.kips().iter().enumerate() {
+        let kip_file = linkle::format::nxo::Kip1File::from_reader(&kip[..])?;
+        // The name comes from the KIP, so fall back to its index if it
+        // isn't safe to use as a file name.
+        let name = if is_plain_file_name(&kip_file.name) {
+            kip_file.name
+        } else {
+            idx.to_string()
+        };
+        write_file(&output_directory.join(format!("{}.kip", name)), kip)?;
+    }
+    Ok(())
+}
+
+fn extract_ini1(input_path: &Path, output_directory: &Path) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let ini1 = linkle::format::ini1::Ini1::from_reader(input_file).with_path(input_path)?;
+    extract_kips(&ini1, output_directory)
+}
+
+fn extract_package1(
+    input_path: &Path,
+    output_directory: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let package1 =
+        linkle::format::package1::Package1::from_reader(input_file, &keys).with_path(input_path)?;
+    println!("Build date: {}", package1.build_date());
+    match package1.key_revision() {
+        Some(key_revision) => {
+            // Before 6.2.0, the master kek is stored in the keyblob of the same
+            // revision instead of being derived from a master_kek_source.
+            let source = if key_revision >= 6 {
+                "master_kek_source"
+            } else {
+                "keyblob"
+            };
+            println!(
+                "Key revision: {:02x} ({}_{:02x})",
+                key_revision, source, key_revision
+            );
+        }
+        None => println!("Mariko package1, unwrapped with the mariko_bek"),
+    }
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+    write_file(&output_directory.join("Warmboot.bin"), package1.warmboot())?;
+    write_file(
+        &output_directory.join("NX_Bootloader.bin"),
+        package1.nx_bootloader(),
+    )?;
+    write_file(
+        &output_directory.join("Secure_Monitor.bin"),
+        package1.secure_monitor(),
+    )?;
+    Ok(())
+}
+
+fn print_package2_signature(package2: &linkle::format::package2::Package2) {
+    if package2.signature_valid() == Some(false) {
+        println!("Warning: the package2 signature is invalid.");
+    }
+}
+
+fn extract_package2(
+    input_path: &Path,
+    output_directory: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let package2 =
+        linkle::format::package2::Package2::from_reader(input_file, &keys).with_path(input_path)?;
+    print_package2_signature(&package2);
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+    write_file(&output_directory.join("Kernel.bin"), package2.kernel())?;
+    let mut ini1 = Vec::new();
+    package2.ini1().write(&mut ini1)?;
+    write_file(&output_directory.join("INI1.bin"), &ini1)?;
+    extract_kips(package2.ini1(), &output_directory.join("INI1"))
+}
+
+fn rebuild_package2(
+    input_path: &Path,
+    output_path: &Path,
+    kip_paths: &[PathBuf],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let mut package2 =
+        linkle::format::package2::Package2::from_reader(input_file, &keys).with_path(input_path)?;
+    print_package2_signature(&package2);
+    for kip_path in kip_paths {
+        let kip = std::fs::read(kip_path).map_err(|err| (err, kip_path.as_path()))?;
+        package2.ini1_mut().replace_kip(kip).with_path(kip_path)?;
+    }
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .map_err(|err| (err, output_path))?;
+    package2
+        .write(&keys, &mut output_file)
+        .with_path(output_path)?;
+    Ok(())
+}
+
+/// Guesses the path of a file on the SD card, which its NAX0 key is derived
+/// from, out of the path it was copied to.
+fn guess_sd_path(path: &Path) -> Option<String> {
+    let components: Vec<_> = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    let start = components.windows(2).rposition(|window| {
+        window[0] == "Nintendo" && (window[1] == "Contents" || window[1] == "save")
+    })?;
+    let sd_path: String = components[start + 2..]
+        .iter()
+        .map(|component| format!("/{}", component))
+        .collect();
+    if sd_path.is_empty() {
+        None
+    } else {
+        Some(sd_path)
+    }
+}
+
+fn extract_nax0(
+    input_path: &Path,
+    output_path: &Path,
+    sd_path: Option<&str>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let sd_path = match sd_path {
+        Some(sd_path) => sd_path.to_string(),
+        None => guess_sd_path(input_path).ok_or_else(|| {
+            linkle::error::Error::InvalidNax0(
+                "can't guess its path on the SD card, use --sd-path",
+                Backtrace::new(),
+            )
+        })?,
+    };
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let mut nax0 = linkle::format::nax0::Nax0::from_reader(input_file, &keys, &sd_path)
+        .with_path(input_path)?;
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .map_err(|err| (err, output_path))?;
+    std::io::copy(&mut nax0, &mut output_file).map_err(|err| (err, input_path))?;
+    Ok(())
+}
+
+fn extract_save(
+    input_path: &Path,
+    output_directory: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let save = linkle::format::save::Save::from_reader(input_file, &keys).with_path(input_path)?;
+    if save.header_mac_valid().is_none() {
+        println!("Warning: save_mac_key is missing, the header CMAC wasn't checked.");
+    }
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+    for file in save.files() {
+        let mut file = file.map_err(|err| (err, input_path))?;
+        if !file.file_name().split('/').all(is_plain_file_name) {
+            return Err(linkle::error::Error::InvalidSave(
+                "file name escapes the output directory",
+                Backtrace::new(),
+            ));
+        }
+        let name = output_directory.join(file.file_name());
+        println!("Writing {}", file.file_name());
+        if let Some(parent) = name.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| (err, parent))?;
+        }
+        let mut out_file = output_option.open(&name).map_err(|err| (err, &name))?;
+        std::io::copy(&mut file, &mut out_file).map_err(|err| (err, input_path))?;
+    }
+    Ok(())
+}
+
+fn extract_romfs(input_path: &Path, output_directory: &Path) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let romfs =
+        linkle::format::romfs::RomFsReader::from_reader(input_file).with_path(input_path)?;
+    romfs.extract(output_directory).with_path(input_path)?;
+    Ok(())
+}
+
+fn parse_title_id(title_id: &str) -> Result<u64, std::num::ParseIntError> {
+    let title_id = title_id.trim_start_matches("0x");
+    u64::from_str_radix(title_id, 16)
+}
+
+fn create_nca(
+    exefs_directory: &Path,
+    romfs_directory: Option<&Path>,
+    title_id: u64,
+    key_generation: u8,
+    is_dev: bool,
+    key_path: Option<&Path>,
+    output_file: &Path,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let exefs = linkle::format::pfs0::Pfs0::from_directory(&exefs_directory.to_string_lossy())
+        .map_err(|err| (err, exefs_directory))?;
+    let romfs = match romfs_directory {
+        Some(romfs_directory) => Some(linkle::format::romfs::RomFs::from_directory(
+            romfs_directory,
+        )?),
+        None => None,
+    };
+
+    let mut nca = linkle::format::nca::NcaBuilder::new_program(title_id, exefs, romfs)?;
+    nca.key_generation = key_generation;
+
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut output = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    nca.write(&keys, &mut output).with_path(output_file)?;
+    Ok(())
+}
+
+fn create_cnmt(
+    nca_files: &[PathBuf],
+    meta_type: &str,
+    title_id: Option<u64>,
+    version: u32,
+    is_dev: bool,
+    key_path: Option<&Path>,
+    output_file: &Path,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+
+    let mut title_ids = vec![];
+    let mut contents = vec![];
+    for nca_file in nca_files {
+        let file = File::open(nca_file).map_err(|err| (err, nca_file.as_path()))?;
+        let nca = linkle::format::nca::Nca::from_reader(&file, &keys).with_path(nca_file)?;
+        title_ids.push(nca.header().title_id);
+        let content = linkle::format::cnmt::PackagedContentInfo::from_nca(&file, &keys)
+            .with_path(nca_file)?;
+        contents.push(content);
+    }
+
+    let title_id = title_id.unwrap_or(title_ids[0]);
+    let meta_type = linkle::format::cnmt::ContentMetaType::from_name(meta_type, title_id)
+        .ok_or_else(|| {
+            linkle::error::Error::InvalidCnmt("unknown content meta type", Backtrace::new())
+        })?;
+    let cnmt =
+        linkle::format::cnmt::PackagedContentMeta::new(title_id, version, meta_type, contents);
+
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut output = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    cnmt.write(&mut output).with_path(output_file)?;
+
+    let mut xml_file = output_file.as_os_str().to_owned();
+    xml_file.push(".xml");
+    let xml_file = Path::new(&xml_file);
+    let mut output = output_option
+        .open(xml_file)
+        .map_err(|err| (err, xml_file))?;
+    cnmt.write_xml(&mut output).map_err(|err| (err, xml_file))?;
+    Ok(())
+}
+
+/// Writes the NCA to memory, and adds it to the NSP under its content ID.
+fn push_nca(
+    nsp: &mut linkle::format::pfs0::Pfs0,
+    nca: &mut linkle::format::nca::NcaBuilder,
+    keys: &linkle::pki::Keys,
+) -> Result<linkle::format::cnmt::PackagedContentInfo, linkle::error::Error> {
+    let mut data = Cursor::new(vec![]);
+    nca.write(keys, &mut data)?;
+    let content = linkle::format::cnmt::PackagedContentInfo::from_nca(&mut data, keys)?;
+    let extension = match content.content_type {
+        linkle::format::cnmt::ContentType::Meta => "cnmt.nca",
+        _ => "nca",
+    };
+    let name = format!("{}.{}", to_hex(&content.content_id), extension);
+    nsp.push_file(name, data)?;
+    Ok(content)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_nsp(
+    input_file: &str,
+    npdm_file: &str,
+    nacp_file: &str,
+    icon_file: Option<&Path>,
+    romfs_dir: Option<&Path>,
+    key_generation: u8,
+    ticket: bool,
+    cert_file: Option<&Path>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+    output_file: &Path,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let npdm = linkle::format::npdm::Npdm::from_file(npdm_file).with_path(npdm_file)?;
+    let title_id = npdm.title_id.0;
+    let title_key = if ticket {
+        Some(linkle::format::nca::generate_key()?)
+    } else {
+        None
+    };
+
+    // ExeFS
+    let mut nso = vec![];
+    linkle::format::nxo::NxoFile::from_elf(input_file)
+        .map_err(|err| (err, input_file))?
+        .write_nso(&mut nso)
+        .map_err(|err| (err, input_file))?;
+    let mut npdm_data = vec![];
+    npdm.write(&mut npdm_data).with_path(npdm_file)?;
+    let mut exefs = linkle::format::pfs0::Pfs0::empty();
+    exefs.push_file(String::from("main"), Cursor::new(nso))?;
+    exefs.push_file(String::from("main.npdm"), Cursor::new(npdm_data))?;
+
+    let romfs = match romfs_dir {
+        Some(romfs_dir) => Some(linkle::format::romfs::RomFs::from_directory(romfs_dir)?),
+        None => None,
+    };
+
+    let mut nacp_data = vec![];
+    linkle::format::nacp::NacpFile::from_file(nacp_file)
+        .map_err(|err| (err, nacp_file))?
+        .write(&mut nacp_data)
+        .map_err(|err| (err, nacp_file))?;
+    let icon = match icon_file {
+        Some(icon_file) => Some(std::fs::read(icon_file).map_err(|err| (err, icon_file))?),
+        None => None,
+    };
+
+    let mut program = linkle::format::nca::NcaBuilder::new_program(title_id, exefs, romfs)?;
+    let mut control = linkle::format::nca::NcaBuilder::new_control(title_id, nacp_data, icon);
+
+    let mut nsp = linkle::format::pfs0::Pfs0::empty();
+    let mut contents = vec![];
+    for nca in [&mut program, &mut control].iter_mut() {
+        nca.key_generation = key_generation;
+        nca.title_key = title_key;
+        contents.push(push_nca(&mut nsp, nca, &keys)?);
+    }
+
+    let meta_type = linkle::format::cnmt::ContentMetaType::from_name("Application", title_id)
+        .expect("Application to be a valid content meta type");
+    let cnmt = linkle::format::cnmt::PackagedContentMeta::new(
+        title_id,
+        npdm.version.unwrap_or(0),
+        meta_type,
+        contents,
+    );
+    let mut meta = linkle::format::nca::NcaBuilder::new_meta(&cnmt)?;
+    meta.key_generation = key_generation;
+    push_nca(&mut nsp, &mut meta, &keys)?;
+
+    if let Some(title_key) = title_key {
+        let rights_id = program.rights_id();
+        let ticket = linkle::format::ticket::Ticket::new_common(
+            rights_id,
+            &title_key,
+            program.master_key_revision(),
+            &keys,
+        )?;
+        let mut data = vec![];
+        ticket.write(&mut data)?;
+        nsp.push_file(format!("{}.tik", to_hex(&rights_id)), Cursor::new(data))?;
+        if let Some(cert_file) = cert_file {
+            let data = std::fs::read(cert_file).map_err(|err| (err, cert_file))?;
+            nsp.push_file(format!("{}.cert", to_hex(&rights_id)), Cursor::new(data))?;
+        }
+    }
+
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut output = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    nsp.write_pfs0(&mut output)
+        .map_err(|err| (err, output_file))?;
+    Ok(())
+}
+
 fn create_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
     let mut nacp = linkle::format::nacp::NacpFile::from_file(&input_file)?;
     let mut option = OpenOptions::new();
@@ -240,20 +1153,133 @@ fn create_romfs(input_directory: &Path, output_file: &Path) -> Result<(), linkle
     Ok(())
 }
 
+fn load_keys(
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<linkle::pki::Keys, linkle::error::Error> {
+    if is_dev {
+        linkle::pki::Keys::new_dev(key_path)
+    } else {
+        linkle::pki::Keys::new_retail(key_path)
+    }
+}
+
 fn print_keys(
     is_dev: bool,
     key_path: Option<&Path>,
     console_unique: bool,
     minimal: bool,
+    export_json: bool,
+    mariko: bool,
 ) -> Result<(), linkle::error::Error> {
-    let keys = if is_dev {
-        linkle::pki::Keys::new_dev(key_path).unwrap()
-    } else {
-        linkle::pki::Keys::new_retail(key_path).unwrap()
-    };
+    let keys = load_keys(is_dev, key_path)?;
 
-    keys.write(&mut std::io::stdout(), console_unique, minimal)
-        .unwrap();
+    if export_json {
+        return keys.write_json(&mut std::io::stdout(), console_unique);
+    }
+    if mariko {
+        keys.write_mariko(&mut std::io::stdout(), console_unique, minimal)?;
+        return Ok(());
+    }
+    keys.write(&mut std::io::stdout(), console_unique, minimal)?;
+    Ok(())
+}
+
+fn split_keys(
+    is_dev: bool,
+    key_path: Option<&Path>,
+    minimal: bool,
+    output_directory: &Path,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+
+    let path = output_directory.join(if is_dev { "dev.keys" } else { "prod.keys" });
+    let mut file = File::create(&path).map_err(|err| (err, path.as_path()))?;
+    keys.write(&mut file, false, minimal)
+        .map_err(|err| (err, path.as_path()))?;
+
+    let path = output_directory.join("console.keys");
+    let mut file = File::create(&path).map_err(|err| (err, path.as_path()))?;
+    keys.write_console_unique(&mut file, minimal)
+        .map_err(|err| (err, path.as_path()))?;
+
+    let path = output_directory.join("title.keys");
+    let mut file = File::create(&path).map_err(|err| (err, path.as_path()))?;
+    keys.write_title_keys(&mut file)
+        .map_err(|err| (err, path.as_path()))?;
+    Ok(())
+}
+
+fn check_keys(is_dev: bool, key_path: Option<&Path>) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    keys.check_fingerprints()
+}
+
+fn parse_hex(value: &str) -> Result<usize, std::num::ParseIntError> {
+    usize::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+fn print_keys_report(
+    is_dev: bool,
+    key_path: Option<&Path>,
+    console_unique: bool,
+    json: bool,
+    revision: Option<usize>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let reports = keys.report();
+    let revision = revision.unwrap_or_else(|| {
+        reports
+            .iter()
+            .filter(|key| key.status != linkle::pki::KeyStatus::Missing)
+            .filter_map(|key| key.index)
+            .max()
+            .unwrap_or(0)
+    });
+    let reports: Vec<_> = reports
+        .into_iter()
+        .filter(|key| console_unique || !key.console_unique)
+        .filter(|key| key.index.map_or(true, |index| index <= revision))
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+    for key in &reports {
+        match key.status {
+            linkle::pki::KeyStatus::Loaded => println!("{}: loaded", key.name),
+            linkle::pki::KeyStatus::Derived => println!("{}: derived", key.name),
+            linkle::pki::KeyStatus::Missing if key.missing_parents.is_empty() => {
+                println!("{}: missing", key.name)
+            }
+            linkle::pki::KeyStatus::Missing => {
+                let needs: Vec<String> = key
+                    .missing_parents
+                    .iter()
+                    .map(|parents| parents.join(" and "))
+                    .collect();
+                println!("{}: missing, needs {}", key.name, needs.join(", or "));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_title_keys(
+    input_paths: &[PathBuf],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    for input_path in input_paths {
+        let input_file = File::open(input_path).map_err(|err| (err, input_path.as_path()))?;
+        let ticket =
+            linkle::format::ticket::Ticket::from_reader(input_file).with_path(input_path)?;
+        let title_key = ticket.title_key(&keys).with_path(input_path)?;
+        println!("{} = {}", to_hex(&ticket.rights_id), to_hex(&title_key));
+    }
     Ok(())
 }
 
@@ -286,6 +1312,18 @@ fn process_args(app: &Opt) {
             ref npdm_file,
             ref output_file,
         } => create_kip(input_file, npdm_file, output_file),
+        Opt::Elf {
+            ref input_file,
+            ref output_file,
+        } => create_elf(input_file, output_file),
+        Opt::Npdm {
+            ref input_file,
+            ref output_file,
+        } => create_npdm(input_file, output_file),
+        Opt::KipNpdm {
+            ref input_file,
+            ref output_file,
+        } => extract_kip_npdm(input_file, output_file),
         Opt::Pfs0 {
             ref input_directory,
             ref output_file,
@@ -294,6 +1332,137 @@ fn process_args(app: &Opt) {
             ref input_file,
             ref output_directory,
         } => extract_pfs0(input_file, output_directory),
+        Opt::Hfs0 {
+            ref input_directory,
+            ref output_file,
+        } => create_hfs0(input_directory, output_file),
+        Opt::Hfs0Extract {
+            ref input_file,
+            ref output_directory,
+        } => extract_hfs0(input_file, output_directory),
+        Opt::XciExtract {
+            ref input_file,
+            ref output_directory,
+            dev,
+            ref keyfile,
+        } => extract_xci(input_file, output_directory, *dev, to_opt_ref(keyfile)),
+        Opt::Ini1 {
+            ref output_file,
+            ref kip_files,
+            ref elf_files,
+            ref npdm_files,
+        } => create_ini1(kip_files, elf_files, npdm_files, output_file),
+        Opt::Ini1Extract {
+            ref input_file,
+            ref output_directory,
+        } => extract_ini1(input_file, output_directory),
+        Opt::Package1Extract {
+            ref input_file,
+            ref output_directory,
+            dev,
+            ref keyfile,
+        } => extract_package1(input_file, output_directory, *dev, to_opt_ref(keyfile)),
+        Opt::Package2Extract {
+            ref input_file,
+            ref output_directory,
+            dev,
+            ref keyfile,
+        } => extract_package2(input_file, output_directory, *dev, to_opt_ref(keyfile)),
+        Opt::Package2 {
+            ref input_file,
+            ref output_file,
+            ref kip_files,
+            dev,
+            ref keyfile,
+        } => rebuild_package2(
+            input_file,
+            output_file,
+            kip_files,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::Nax0Extract {
+            ref input_file,
+            ref output_file,
+            ref sd_path,
+            dev,
+            ref keyfile,
+        } => extract_nax0(
+            input_file,
+            output_file,
+            to_opt_ref(sd_path),
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::SaveExtract {
+            ref input_file,
+            ref output_directory,
+            dev,
+            ref keyfile,
+        } => extract_save(input_file, output_directory, *dev, to_opt_ref(keyfile)),
+        Opt::RomfsExtract {
+            ref input_file,
+            ref output_directory,
+        } => extract_romfs(input_file, output_directory),
+        Opt::Nca {
+            ref exefs_directory,
+            ref output_file,
+            title_id,
+            ref romfs,
+            key_generation,
+            dev,
+            ref keyfile,
+        } => create_nca(
+            exefs_directory,
+            to_opt_ref(romfs),
+            *title_id,
+            *key_generation,
+            *dev,
+            to_opt_ref(keyfile),
+            output_file,
+        ),
+        Opt::Cnmt {
+            ref output_file,
+            ref nca_files,
+            ref meta_type,
+            title_id,
+            version,
+            dev,
+            ref keyfile,
+        } => create_cnmt(
+            nca_files,
+            meta_type,
+            *title_id,
+            *version,
+            *dev,
+            to_opt_ref(keyfile),
+            output_file,
+        ),
+        Opt::Nsp {
+            ref input_file,
+            ref output_file,
+            ref npdm,
+            ref nacp,
+            ref icon,
+            ref romfs,
+            key_generation,
+            ticket,
+            ref cert,
+            dev,
+            ref keyfile,
+        } => create_nsp(
+            input_file,
+            npdm,
+            nacp,
+            to_opt_ref(icon),
+            to_opt_ref(romfs),
+            *key_generation,
+            *ticket,
+            to_opt_ref(cert),
+            *dev,
+            to_opt_ref(keyfile),
+            output_file,
+        ),
         Opt::Nacp {
             ref input_file,
             ref output_file,
@@ -307,7 +1476,42 @@ fn process_args(app: &Opt) {
             ref keyfile,
             show_console_unique,
             minimal,
-        } => print_keys(*dev, to_opt_ref(keyfile), *show_console_unique, *minimal),
+            report,
+            json,
+            export_json,
+            revision,
+            check,
+            ref split,
+            mariko,
+        } => {
+            if *check {
+                check_keys(*dev, to_opt_ref(keyfile))
+            } else if let Some(split) = split {
+                split_keys(*dev, to_opt_ref(keyfile), *minimal, split)
+            } else if *report || *json {
+                print_keys_report(
+                    *dev,
+                    to_opt_ref(keyfile),
+                    *show_console_unique,
+                    *json,
+                    *revision,
+                )
+            } else {
+                print_keys(
+                    *dev,
+                    to_opt_ref(keyfile),
+                    *show_console_unique,
+                    *minimal,
+                    *export_json,
+                    *mariko,
+                )
+            }
+        }
+        Opt::Titlekeys {
+            ref input_files,
+            dev,
+            ref keyfile,
+        } => print_title_keys(input_files, *dev, to_opt_ref(keyfile)),
     };
 
     if let Err(e) = res {
@@ -25,12 +25,56 @@ pub enum Error {
     Ini(#[cause] ini::ini::Error, Backtrace),
     #[display(fmt = "Key derivation error: {}", _0)]
     Crypto(String, Backtrace),
+    #[display(fmt = "Missing key {}.", _0)]
+    MissingKey(String, Backtrace),
+    #[display(fmt = "Wrong value for the keys {}.", _0)]
+    WrongKey(String, Backtrace),
     #[display(fmt = "Invalid keyblob {}: {}.", _1, _0)]
     MacError(cmac::crypto_mac::MacError, usize, Backtrace),
     #[display(fmt = "Invalid PFS0: {}.", _0)]
     InvalidPfs0(&'static str, Backtrace),
+    #[display(fmt = "Invalid NRO: {}.", _0)]
+    InvalidNro(&'static str, Backtrace),
+    #[display(fmt = "Invalid NSO: {}.", _0)]
+    InvalidNso(&'static str, Backtrace),
+    #[display(fmt = "Hash mismatch in the {} segment of the NSO.", _0)]
+    NsoHashMismatch(&'static str, Backtrace),
+    #[display(fmt = "Invalid KIP1: {}.", _0)]
+    InvalidKip1(&'static str, Backtrace),
+    #[display(fmt = "Invalid BLZ compressed data: {}.", _0)]
+    InvalidBlz(&'static str, Backtrace),
     #[display(fmt = "Failed to convert filename to UTF8: {}.", _0)]
     Utf8Conversion(String, #[cause] Utf8Error, Backtrace),
+    #[display(fmt = "Invalid {} kernel capability: {} {}.", _0, _1, _2)]
+    InvalidKernelCapability(&'static str, &'static str, &'static str, Backtrace),
+    #[display(fmt = "Invalid NCA: {}.", _0)]
+    InvalidNca(&'static str, Backtrace),
+    #[display(fmt = "Hash mismatch in section {} of the NCA.", _0)]
+    NcaIntegrity(usize, Backtrace),
+    #[display(fmt = "Invalid NPDM: {}.", _0)]
+    InvalidNpdm(&'static str, Backtrace),
+    #[display(fmt = "Invalid RomFS: {}.", _0)]
+    InvalidRomFs(&'static str, Backtrace),
+    #[display(fmt = "Invalid CNMT: {}.", _0)]
+    InvalidCnmt(&'static str, Backtrace),
+    #[display(fmt = "Invalid HFS0: {}.", _0)]
+    InvalidHfs0(&'static str, Backtrace),
+    #[display(fmt = "Hash mismatch for {} in the HFS0.", _0)]
+    Hfs0HashMismatch(String, Backtrace),
+    #[display(fmt = "Invalid INI1: {}.", _0)]
+    InvalidIni1(&'static str, Backtrace),
+    #[display(fmt = "Invalid NAX0: {}.", _0)]
+    InvalidNax0(&'static str, Backtrace),
+    #[display(fmt = "Invalid package1: {}.", _0)]
+    InvalidPackage1(&'static str, Backtrace),
+    #[display(fmt = "Invalid package2: {}.", _0)]
+    InvalidPackage2(&'static str, Backtrace),
+    #[display(fmt = "Invalid save data: {}.", _0)]
+    InvalidSave(&'static str, Backtrace),
+    #[display(fmt = "Invalid ticket: {}.", _0)]
+    InvalidTicket(&'static str, Backtrace),
+    #[display(fmt = "Invalid XCI: {}.", _0)]
+    InvalidXci(&'static str, Backtrace),
     #[display(fmt = "Can't handles symlinks in romfs: {}", "_0.display()")]
     RomFsSymlink(PathBuf, Backtrace),
     #[display(fmt = "Unknown file type at {}", "_0.display()")]
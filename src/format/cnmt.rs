@@ -0,0 +1,607 @@
+//! PackagedContentMeta (CNMT), describing the contents making up a title.
+//!
+//! The CNMT lives in a PFS0 inside the meta NCA of a title, under the name
+//! `<Type>_<title id>.cnmt`.
+
+use crate::error::Error;
+use crate::format::nca::{Nca, NcaContentType};
+use crate::pki::Keys;
+use crate::utils::to_hex;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Backtrace;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The type of a title, along with the fields of its extended header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentMetaType {
+    SystemProgram,
+    SystemData,
+    Application {
+        patch_id: u64,
+        required_system_version: u32,
+        required_application_version: u32,
+    },
+    Patch {
+        application_id: u64,
+        required_system_version: u32,
+    },
+    AddOnContent {
+        application_id: u64,
+        required_application_version: u32,
+    },
+}
+
+impl ContentMetaType {
+    /// Creates the type of the given title, deriving the related title IDs
+    /// from it.
+    pub fn from_name(name: &str, title_id: u64) -> Option<ContentMetaType> {
+        match name {
+            "SystemProgram" => Some(ContentMetaType::SystemProgram),
+            "SystemData" => Some(ContentMetaType::SystemData),
+            "Application" => Some(ContentMetaType::Application {
+                patch_id: title_id | 0x800,
+                required_system_version: 0,
+                required_application_version: 0,
+            }),
+            "Patch" => Some(ContentMetaType::Patch {
+                application_id: title_id & !0x800,
+                required_system_version: 0,
+            }),
+            "AddOnContent" => Some(ContentMetaType::AddOnContent {
+                application_id: (title_id & !0xFFF).wrapping_sub(0x1000),
+                required_application_version: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentMetaType::SystemProgram => "SystemProgram",
+            ContentMetaType::SystemData => "SystemData",
+            ContentMetaType::Application { .. } => "Application",
+            ContentMetaType::Patch { .. } => "Patch",
+            ContentMetaType::AddOnContent { .. } => "AddOnContent",
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            ContentMetaType::SystemProgram => 0x01,
+            ContentMetaType::SystemData => 0x02,
+            ContentMetaType::Application { .. } => 0x80,
+            ContentMetaType::Patch { .. } => 0x81,
+            ContentMetaType::AddOnContent { .. } => 0x82,
+        }
+    }
+
+    fn extended_header_size(&self) -> u16 {
+        match self {
+            ContentMetaType::SystemProgram | ContentMetaType::SystemData => 0,
+            ContentMetaType::Application { .. } | ContentMetaType::AddOnContent { .. } => 0x10,
+            ContentMetaType::Patch { .. } => 0x18,
+        }
+    }
+
+    fn read_extended_header<R: Read>(
+        meta_type: u8,
+        extended_header_size: u16,
+        f: &mut R,
+    ) -> Result<(ContentMetaType, u32), Error> {
+        let mut extended_data_size = 0;
+        let res = match meta_type {
+            0x01 => ContentMetaType::SystemProgram,
+            0x02 => ContentMetaType::SystemData,
+            0x80 => {
+                let patch_id = f.read_u64::<LittleEndian>()?;
+                let required_system_version = f.read_u32::<LittleEndian>()?;
+                let required_application_version = f.read_u32::<LittleEndian>()?;
+                ContentMetaType::Application {
+                    patch_id,
+                    required_system_version,
+                    required_application_version,
+                }
+            }
+            0x81 => {
+                let application_id = f.read_u64::<LittleEndian>()?;
+                let required_system_version = f.read_u32::<LittleEndian>()?;
+                extended_data_size = f.read_u32::<LittleEndian>()?;
+                f.read_u64::<LittleEndian>()?;
+                ContentMetaType::Patch {
+                    application_id,
+                    required_system_version,
+                }
+            }
+            0x82 => {
+                let application_id = f.read_u64::<LittleEndian>()?;
+                let required_application_version = f.read_u32::<LittleEndian>()?;
+                f.read_u32::<LittleEndian>()?;
+                ContentMetaType::AddOnContent {
+                    application_id,
+                    required_application_version,
+                }
+            }
+            _ => {
+                return Err(Error::InvalidCnmt(
+                    "unsupported content meta type",
+                    Backtrace::new(),
+                ))
+            }
+        };
+        if res.extended_header_size() != extended_header_size {
+            return Err(Error::InvalidCnmt(
+                "unexpected extended header size",
+                Backtrace::new(),
+            ));
+        }
+        Ok((res, extended_data_size))
+    }
+
+    fn write_extended_header<W: Write>(
+        &self,
+        extended_data_size: u32,
+        output_writter: &mut W,
+    ) -> io::Result<()> {
+        match *self {
+            ContentMetaType::SystemProgram | ContentMetaType::SystemData => (),
+            ContentMetaType::Application {
+                patch_id,
+                required_system_version,
+                required_application_version,
+            } => {
+                output_writter.write_u64::<LittleEndian>(patch_id)?;
+                output_writter.write_u32::<LittleEndian>(required_system_version)?;
+                output_writter.write_u32::<LittleEndian>(required_application_version)?;
+            }
+            ContentMetaType::Patch {
+                application_id,
+                required_system_version,
+            } => {
+                output_writter.write_u64::<LittleEndian>(application_id)?;
+                output_writter.write_u32::<LittleEndian>(required_system_version)?;
+                output_writter.write_u32::<LittleEndian>(extended_data_size)?;
+                output_writter.write_u64::<LittleEndian>(0)?;
+            }
+            ContentMetaType::AddOnContent {
+                application_id,
+                required_application_version,
+            } => {
+                output_writter.write_u64::<LittleEndian>(application_id)?;
+                output_writter.write_u32::<LittleEndian>(required_application_version)?;
+                output_writter.write_u32::<LittleEndian>(0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+    Meta,
+    Program,
+    Data,
+    Control,
+    HtmlDocument,
+    LegalInformation,
+    DeltaFragment,
+}
+
+impl ContentType {
+    fn from_u8(value: u8) -> Result<ContentType, Error> {
+        match value {
+            0 => Ok(ContentType::Meta),
+            1 => Ok(ContentType::Program),
+            2 => Ok(ContentType::Data),
+            3 => Ok(ContentType::Control),
+            4 => Ok(ContentType::HtmlDocument),
+            5 => Ok(ContentType::LegalInformation),
+            6 => Ok(ContentType::DeltaFragment),
+            _ => Err(Error::InvalidCnmt("unknown content type", Backtrace::new())),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ContentType::Meta => 0,
+            ContentType::Program => 1,
+            ContentType::Data => 2,
+            ContentType::Control => 3,
+            ContentType::HtmlDocument => 4,
+            ContentType::LegalInformation => 5,
+            ContentType::DeltaFragment => 6,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentType::Meta => "Meta",
+            ContentType::Program => "Program",
+            ContentType::Data => "Data",
+            ContentType::Control => "Control",
+            ContentType::HtmlDocument => "HtmlDocument",
+            ContentType::LegalInformation => "LegalInformation",
+            ContentType::DeltaFragment => "DeltaFragment",
+        }
+    }
+
+    /// Manual NCAs may either be HTML documents or legal information. We
+    /// can't tell them apart from the NCA header, so they are considered to
+    /// be HTML documents.
+    pub fn from_nca_content_type(content_type: NcaContentType) -> ContentType {
+        match content_type {
+            NcaContentType::Program => ContentType::Program,
+            NcaContentType::Meta => ContentType::Meta,
+            NcaContentType::Control => ContentType::Control,
+            NcaContentType::Manual => ContentType::HtmlDocument,
+            NcaContentType::Data | NcaContentType::PublicData => ContentType::Data,
+        }
+    }
+}
+
+/// An NCA making up the title.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackagedContentInfo {
+    pub hash: [u8; 0x20],
+    pub content_id: [u8; 0x10],
+    pub size: u64,
+    pub content_type: ContentType,
+    pub id_offset: u8,
+    /// The key generation of the NCA. It is not part of the CNMT, and is
+    /// only reported in the `.cnmt.xml`.
+    pub key_generation: u8,
+}
+
+impl PackagedContentInfo {
+    /// Describes the given NCA. Its content ID is the first half of its
+    /// SHA-256 hash, as done by Nintendo.
+    pub fn from_nca<R: Read + Seek>(mut f: R, keys: &Keys) -> Result<PackagedContentInfo, Error> {
+        let nca = Nca::from_reader(&mut f, keys)?;
+        let content_type = ContentType::from_nca_content_type(nca.header().content_type);
+        let key_generation = nca.header().key_generation;
+
+        f.seek(SeekFrom::Start(0))?;
+        let mut hasher = Sha256::default();
+        let mut buf = vec![0; 0x10000];
+        let mut size = 0;
+        loop {
+            let read = f.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.input(&buf[..read]);
+            size += read as u64;
+        }
+
+        let mut hash = [0; 0x20];
+        hash.copy_from_slice(&hasher.result());
+        let mut content_id = [0; 0x10];
+        content_id.copy_from_slice(&hash[..0x10]);
+        Ok(PackagedContentInfo {
+            hash,
+            content_id,
+            size,
+            content_type,
+            id_offset: 0,
+            key_generation,
+        })
+    }
+}
+
+/// A title referenced by the CNMT, used by system updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentMetaInfo {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: u8,
+    pub attributes: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackagedContentMeta {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+    pub required_download_system_version: u32,
+    pub contents: Vec<PackagedContentInfo>,
+    pub content_metas: Vec<ContentMetaInfo>,
+    /// Patches carry extended data (fragment sets for deltas, history), which
+    /// is kept as is.
+    pub extended_data: Vec<u8>,
+    pub digest: [u8; 0x20],
+}
+
+impl PackagedContentMeta {
+    /// Creates the CNMT of a title made of the given contents.
+    pub fn new(
+        title_id: u64,
+        version: u32,
+        meta_type: ContentMetaType,
+        contents: Vec<PackagedContentInfo>,
+    ) -> PackagedContentMeta {
+        // The digest is opaque to the system, any unique value will do.
+        let mut hasher = Sha256::default();
+        for content in &contents {
+            hasher.input(&content.hash);
+        }
+        let mut digest = [0; 0x20];
+        digest.copy_from_slice(&hasher.result());
+
+        PackagedContentMeta {
+            title_id,
+            version,
+            meta_type,
+            attributes: 0,
+            required_download_system_version: 0,
+            contents,
+            content_metas: vec![],
+            extended_data: vec![],
+            digest,
+        }
+    }
+
+    pub fn from_reader<R: Read>(mut f: R) -> Result<PackagedContentMeta, Error> {
+        let title_id = f.read_u64::<LittleEndian>()?;
+        let version = f.read_u32::<LittleEndian>()?;
+        let meta_type = f.read_u8()?;
+        f.read_u8()?;
+        let extended_header_size = f.read_u16::<LittleEndian>()?;
+        let content_count = f.read_u16::<LittleEndian>()?;
+        let content_meta_count = f.read_u16::<LittleEndian>()?;
+        let attributes = f.read_u8()?;
+        let mut reserved = [0; 3];
+        f.read_exact(&mut reserved)?;
+        let required_download_system_version = f.read_u32::<LittleEndian>()?;
+        f.read_u32::<LittleEndian>()?;
+
+        let (meta_type, extended_data_size) =
+            ContentMetaType::read_extended_header(meta_type, extended_header_size, &mut f)?;
+
+        let mut contents = Vec::with_capacity(content_count.into());
+        for _ in 0..content_count {
+            let mut hash = [0; 0x20];
+            f.read_exact(&mut hash)?;
+            let mut content_id = [0; 0x10];
+            f.read_exact(&mut content_id)?;
+            let size_low = f.read_u32::<LittleEndian>()?;
+            let size_high = f.read_u16::<LittleEndian>()?;
+            let content_type = ContentType::from_u8(f.read_u8()?)?;
+            let id_offset = f.read_u8()?;
+            contents.push(PackagedContentInfo {
+                hash,
+                content_id,
+                size: u64::from(size_low) | u64::from(size_high) << 32,
+                content_type,
+                id_offset,
+                key_generation: 0,
+            });
+        }
+
+        let mut content_metas = Vec::with_capacity(content_meta_count.into());
+        for _ in 0..content_meta_count {
+            let title_id = f.read_u64::<LittleEndian>()?;
+            let version = f.read_u32::<LittleEndian>()?;
+            let meta_type = f.read_u8()?;
+            let attributes = f.read_u8()?;
+            f.read_u16::<LittleEndian>()?;
+            content_metas.push(ContentMetaInfo {
+                title_id,
+                version,
+                meta_type,
+                attributes,
+            });
+        }
+
+        let mut extended_data = vec![0; extended_data_size as usize];
+        f.read_exact(&mut extended_data)?;
+        let mut digest = [0; 0x20];
+        f.read_exact(&mut digest)?;
+
+        Ok(PackagedContentMeta {
+            title_id,
+            version,
+            meta_type,
+            attributes,
+            required_download_system_version,
+            contents,
+            content_metas,
+            extended_data,
+            digest,
+        })
+    }
+
+    /// The name of the CNMT file in the meta NCA.
+    pub fn file_name(&self) -> String {
+        format!("{}_{:016x}.cnmt", self.meta_type.name(), self.title_id)
+    }
+
+    pub fn write<W: Write>(&self, output_writter: &mut W) -> Result<(), Error> {
+        if self.contents.len() > 0xFFFF || self.content_metas.len() > 0xFFFF {
+            return Err(Error::InvalidCnmt("too many records", Backtrace::new()));
+        }
+        let is_patch = matches!(self.meta_type, ContentMetaType::Patch { .. });
+        if !self.extended_data.is_empty() && !is_patch {
+            return Err(Error::InvalidCnmt(
+                "only patches can have extended data",
+                Backtrace::new(),
+            ));
+        }
+
+        output_writter.write_u64::<LittleEndian>(self.title_id)?;
+        output_writter.write_u32::<LittleEndian>(self.version)?;
+        output_writter.write_u8(self.meta_type.to_u8())?;
+        output_writter.write_u8(0)?;
+        output_writter.write_u16::<LittleEndian>(self.meta_type.extended_header_size())?;
+        output_writter.write_u16::<LittleEndian>(self.contents.len() as u16)?;
+        output_writter.write_u16::<LittleEndian>(self.content_metas.len() as u16)?;
+        output_writter.write_u8(self.attributes)?;
+        output_writter.write_all(&[0; 3])?;
+        output_writter.write_u32::<LittleEndian>(self.required_download_system_version)?;
+        output_writter.write_u32::<LittleEndian>(0)?;
+
+        self.meta_type
+            .write_extended_header(self.extended_data.len() as u32, output_writter)?;
+
+        for content in &self.contents {
+            if content.size >= 1 << 48 {
+                return Err(Error::InvalidCnmt("content is too big", Backtrace::new()));
+            }
+            output_writter.write_all(&content.hash)?;
+            output_writter.write_all(&content.content_id)?;
+            output_writter.write_u32::<LittleEndian>(content.size as u32)?;
+            output_writter.write_u16::<LittleEndian>((content.size >> 32) as u16)?;
+            output_writter.write_u8(content.content_type.to_u8())?;
+            output_writter.write_u8(content.id_offset)?;
+        }
+
+        for content_meta in &self.content_metas {
+            output_writter.write_u64::<LittleEndian>(content_meta.title_id)?;
+            output_writter.write_u32::<LittleEndian>(content_meta.version)?;
+            output_writter.write_u8(content_meta.meta_type)?;
+            output_writter.write_u8(content_meta.attributes)?;
+            output_writter.write_u16::<LittleEndian>(0)?;
+        }
+
+        output_writter.write_all(&self.extended_data)?;
+        output_writter.write_all(&self.digest)?;
+        Ok(())
+    }
+
+    /// Writes the `.cnmt.xml` describing the title, as found alongside the
+    /// NCAs in NSPs.
+    pub fn write_xml<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        writeln!(output_writter, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(output_writter, "<ContentMeta>")?;
+        writeln!(output_writter, "  <Type>{}</Type>", self.meta_type.name())?;
+        writeln!(output_writter, "  <Id>0x{:016x}</Id>", self.title_id)?;
+        writeln!(output_writter, "  <Version>{}</Version>", self.version)?;
+        writeln!(
+            output_writter,
+            "  <RequiredDownloadSystemVersion>{}</RequiredDownloadSystemVersion>",
+            self.required_download_system_version
+        )?;
+        for content in &self.contents {
+            writeln!(output_writter, "  <Content>")?;
+            writeln!(
+                output_writter,
+                "    <Type>{}</Type>",
+                content.content_type.name()
+            )?;
+            writeln!(
+                output_writter,
+                "    <Id>{}</Id>",
+                to_hex(&content.content_id)
+            )?;
+            writeln!(output_writter, "    <Size>{}</Size>", content.size)?;
+            writeln!(output_writter, "    <Hash>{}</Hash>", to_hex(&content.hash))?;
+            writeln!(
+                output_writter,
+                "    <KeyGeneration>{}</KeyGeneration>",
+                content.key_generation
+            )?;
+            writeln!(output_writter, "  </Content>")?;
+        }
+        writeln!(
+            output_writter,
+            "  <Digest>{}</Digest>",
+            to_hex(&self.digest)
+        )?;
+        let key_generation_min = self
+            .contents
+            .iter()
+            .map(|content| content.key_generation)
+            .max()
+            .unwrap_or(0);
+        writeln!(
+            output_writter,
+            "  <KeyGenerationMin>{}</KeyGenerationMin>",
+            key_generation_min
+        )?;
+        match self.meta_type {
+            ContentMetaType::SystemProgram | ContentMetaType::SystemData => (),
+            ContentMetaType::Application {
+                patch_id,
+                required_system_version,
+                required_application_version,
+            } => {
+                writeln!(
+                    output_writter,
+                    "  <RequiredSystemVersion>{}</RequiredSystemVersion>",
+                    required_system_version
+                )?;
+                writeln!(output_writter, "  <PatchId>0x{:016x}</PatchId>", patch_id)?;
+                writeln!(
+                    output_writter,
+                    "  <RequiredApplicationVersion>{}</RequiredApplicationVersion>",
+                    required_application_version
+                )?;
+            }
+            ContentMetaType::Patch {
+                application_id,
+                required_system_version,
+            } => {
+                writeln!(
+                    output_writter,
+                    "  <RequiredSystemVersion>{}</RequiredSystemVersion>",
+                    required_system_version
+                )?;
+                writeln!(
+                    output_writter,
+                    "  <ApplicationId>0x{:016x}</ApplicationId>",
+                    application_id
+                )?;
+            }
+            ContentMetaType::AddOnContent {
+                application_id,
+                required_application_version,
+            } => {
+                writeln!(
+                    output_writter,
+                    "  <RequiredApplicationVersion>{}</RequiredApplicationVersion>",
+                    required_application_version
+                )?;
+                writeln!(
+                    output_writter,
+                    "  <ApplicationId>0x{:016x}</ApplicationId>",
+                    application_id
+                )?;
+            }
+        }
+        writeln!(output_writter, "</ContentMeta>")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cnmt_roundtrip() {
+        let content = PackagedContentInfo {
+            hash: [0x11; 0x20],
+            content_id: [0x11; 0x10],
+            size: 0x1_2345_6789,
+            content_type: ContentType::Program,
+            id_offset: 0,
+            key_generation: 0,
+        };
+        let meta_type = ContentMetaType::from_name("Patch", 0x0100_0000_0000_1800).unwrap();
+        assert_eq!(
+            meta_type,
+            ContentMetaType::Patch {
+                application_id: 0x0100_0000_0000_1000,
+                required_system_version: 0,
+            }
+        );
+        let mut cnmt =
+            PackagedContentMeta::new(0x0100_0000_0000_1800, 0x10000, meta_type, vec![content]);
+        cnmt.extended_data = vec![1, 2, 3, 4];
+
+        let mut data = vec![];
+        cnmt.write(&mut data).unwrap();
+        assert_eq!(data.len(), 0x20 + 0x18 + 0x38 + 4 + 0x20);
+        assert_eq!(PackagedContentMeta::from_reader(&data[..]).unwrap(), cnmt);
+    }
+}
@@ -0,0 +1,268 @@
+//! HFS0, the hashed variant of PFS0 used by gamecard images.
+
+use crate::error::Error;
+use crate::format::pfs0::{write_partition, Pfs0File, Pfs0Meta};
+use crate::utils::{ReadRange, TryClone};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Backtrace;
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// The size of the region hashed at the start of each file, when writing.
+const HASHED_REGION_SIZE: u64 = 0x200;
+
+/// A file entry of an HFS0 header.
+#[derive(Debug, Clone)]
+pub(crate) struct Hfs0Entry {
+    pub(crate) name: String,
+    /// Offset of the file, relative to the start of the HFS0.
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    pub(crate) hashed_region_size: u32,
+    pub(crate) hash: [u8; 0x20],
+}
+
+/// Reads the HFS0 header, returning the file entries along with the size of
+/// the header.
+pub(crate) fn read_header<R: Read + Seek>(f: &mut R) -> Result<(Vec<Hfs0Entry>, u64), Error> {
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    if &magic != b"HFS0" {
+        return Err(Error::InvalidHfs0("magic is wrong", Backtrace::new()));
+    }
+
+    let filecount = f.read_u32::<LittleEndian>()?;
+    let string_table_size = f.read_u32::<LittleEndian>()?;
+    let _zero = f.read_u32::<LittleEndian>()?;
+
+    let string_table_offset = 0x10 + u64::from(filecount) * 0x40;
+    let data_offset = string_table_offset + u64::from(string_table_size);
+
+    // Check the header fits before allocating its tables.
+    let position = f.seek(SeekFrom::Current(0))?;
+    let file_size = f.seek(SeekFrom::End(0))?;
+    if position - 0x10 + data_offset > file_size {
+        return Err(Error::InvalidHfs0(
+            "header is bigger than the file",
+            Backtrace::new(),
+        ));
+    }
+    f.seek(SeekFrom::Start(position))?;
+
+    let mut entries = Vec::with_capacity(filecount as usize);
+    let mut name_offsets = Vec::with_capacity(filecount as usize);
+    for _ in 0..filecount {
+        let offset = data_offset + f.read_u64::<LittleEndian>()?;
+        let size = f.read_u64::<LittleEndian>()?;
+        name_offsets.push(f.read_u32::<LittleEndian>()?);
+        let hashed_region_size = f.read_u32::<LittleEndian>()?;
+        let _zero = f.read_u64::<LittleEndian>()?;
+        let mut hash = [0; 0x20];
+        f.read_exact(&mut hash)?;
+        entries.push(Hfs0Entry {
+            name: String::new(),
+            offset,
+            size,
+            hashed_region_size,
+            hash,
+        });
+    }
+
+    let mut string_table = vec![0; string_table_size as usize];
+    f.read_exact(&mut string_table)?;
+    for (entry, name_offset) in entries.iter_mut().zip(name_offsets) {
+        let mut name = string_table
+            .get(name_offset as usize..)
+            .ok_or_else(|| Error::InvalidHfs0("file name out of bounds", Backtrace::new()))?;
+        let mut buf = Vec::new();
+        name.read_until(b'\0', &mut buf)?;
+        if buf.last() == Some(&0) {
+            buf.pop();
+        }
+        entry.name = String::from_utf8(buf)?;
+    }
+    Ok((entries, data_offset))
+}
+
+struct Hfs0FileMeta {
+    file: Pfs0Meta,
+    hashed_region_size: u32,
+    hash: [u8; 0x20],
+}
+
+pub struct Hfs0 {
+    files: Vec<Hfs0FileMeta>,
+}
+
+impl Hfs0 {
+    pub fn from_directory(input: &str) -> io::Result<Self> {
+        let path = PathBuf::from(input);
+        let mut files = Vec::new();
+        for entry_res in std::fs::read_dir(path)? {
+            let entry = entry_res?;
+            let entry_path = &entry.path();
+            if entry_path.is_dir() {
+                println!("Ignoring directory \"{}\"", entry_path.display());
+            } else {
+                files.push(Hfs0FileMeta {
+                    file: Pfs0Meta::HostPath(entry_path.clone()),
+                    hashed_region_size: 0,
+                    hash: [0; 0x20],
+                });
+            }
+        }
+        Ok(Hfs0 { files })
+    }
+
+    pub fn from_reader<R: Read + Seek + TryClone + 'static>(mut f: R) -> Result<Self, Error> {
+        f.seek(SeekFrom::Start(0))?;
+        let (entries, _) = read_header(&mut f)?;
+        let mut files = Vec::with_capacity(entries.len());
+        for entry in entries {
+            files.push(Hfs0FileMeta {
+                file: Pfs0Meta::SubFile {
+                    file: Box::new(ReadRange::new(f.try_clone()?, entry.offset, entry.size)),
+                    name: entry.name,
+                    size: entry.size,
+                },
+                hashed_region_size: entry.hashed_region_size,
+                hash: entry.hash,
+            });
+        }
+        Ok(Hfs0 { files })
+    }
+
+    /// Checks the hash of the hashed region of every file.
+    pub fn verify(&mut self) -> Result<(), Error> {
+        for meta in self.files.iter_mut() {
+            if let Pfs0Meta::SubFile { file, name, .. } = &mut meta.file {
+                let mut data = vec![0; meta.hashed_region_size as usize];
+                file.seek(SeekFrom::Start(0))?;
+                file.read_exact(&mut data)?;
+                let mut hasher = Sha256::default();
+                hasher.input(&data);
+                if hasher.result()[..] != meta.hash[..] {
+                    return Err(Error::Hfs0HashMismatch(name.clone(), Backtrace::new()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the HFS0. The first 0x200 bytes of each file are hashed.
+    pub fn write_hfs0<T>(&mut self, output_writter: &mut T) -> io::Result<()>
+    where
+        T: Write + Seek,
+    {
+        self.files.sort_by_key(|v| v.file.file_name().to_string());
+        let mut hashes = Vec::with_capacity(self.files.len());
+        let mut files: Vec<&mut Pfs0Meta> = self.files.iter_mut().map(|v| &mut v.file).collect();
+        write_partition(
+            output_writter,
+            *b"HFS0",
+            0x40,
+            &mut files,
+            |file, file_size| {
+                // Hash the start of the file.
+                let hashed_region_size = file_size.min(HASHED_REGION_SIZE);
+                let mut data = vec![0; hashed_region_size as usize];
+                file.seek(SeekFrom::Start(0))?;
+                file.read_exact(&mut data)?;
+                let mut hasher = Sha256::default();
+                hasher.input(&data);
+                let mut hash = [0; 0x20];
+                hash.copy_from_slice(&hasher.result());
+                hashes.push((hashed_region_size as u32, hash));
+
+                let mut tail = Vec::with_capacity(0x2C);
+                tail.write_u32::<LittleEndian>(hashed_region_size as u32)?;
+                tail.write_u64::<LittleEndian>(0)?;
+                tail.write_all(&hash)?;
+                Ok(tail)
+            },
+        )?;
+
+        for (meta, (hashed_region_size, hash)) in self.files.iter_mut().zip(hashes) {
+            meta.hashed_region_size = hashed_region_size;
+            meta.hash = hash;
+        }
+        Ok(())
+    }
+
+    pub fn file_names(&self) -> Vec<&str> {
+        self.files
+            .iter()
+            .map(|meta| meta.file.file_name())
+            .collect()
+    }
+
+    pub fn files(self) -> impl Iterator<Item = io::Result<Pfs0File>> + 'static {
+        self.files.into_iter().map(|meta| meta.file.open())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    fn write(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut hfs0 = Hfs0 {
+            files: files
+                .iter()
+                .map(|(name, data)| Hfs0FileMeta {
+                    file: Pfs0Meta::SubFile {
+                        file: Box::new(Cursor::new(data.clone())),
+                        name: (*name).to_string(),
+                        size: data.len() as u64,
+                    },
+                    hashed_region_size: 0,
+                    hash: [0; 0x20],
+                })
+                .collect(),
+        };
+        let mut out = Cursor::new(Vec::new());
+        hfs0.write_hfs0(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn hfs0_roundtrip() {
+        let files = [
+            ("normal", vec![0x11; 0x123]),
+            ("secure", vec![0x22; 0x1000]),
+        ];
+        let data = write(&files);
+
+        let mut hfs0 = Hfs0::from_reader(Cursor::new(Rc::<[u8]>::from(data))).unwrap();
+        hfs0.verify().unwrap();
+        assert_eq!(hfs0.file_names(), vec!["normal", "secure"]);
+        for (entry, file) in files.iter().zip(hfs0.files()) {
+            let (name, expected) = entry;
+            let mut file = file.unwrap();
+            assert_eq!(file.file_name(), *name);
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(&contents, expected);
+        }
+    }
+
+    #[test]
+    fn hfs0_hash_mismatch() {
+        let mut data = write(&[("secure", vec![0x22; 0x1000])]);
+        // Corrupt the first byte of the file, which is in its hashed region.
+        let len = data.len();
+        data[len - 0x1000] ^= 1;
+        let mut hfs0 = Hfs0::from_reader(Cursor::new(Rc::<[u8]>::from(data))).unwrap();
+        assert!(hfs0.verify().is_err());
+    }
+
+    #[test]
+    fn hfs0_header_bigger_than_the_file() {
+        let mut data = write(&[("secure", vec![0x22; 0x10])]);
+        data[4..8].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        assert!(Hfs0::from_reader(Cursor::new(Rc::<[u8]>::from(data))).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! INI1, the bundle of KIP1s loaded by the kernel at boot.
+
+use crate::error::Error;
+use crate::format::nxo::{KipNpdm, NxoFile};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Backtrace;
+use std::io::{self, Read, Write};
+
+/// The kernel refuses INI1s with more processes than this.
+const INI1_MAX_KIPS: usize = 0x50;
+
+/// An INI1, holding raw KIP1 files.
+#[derive(Debug, Clone, Default)]
+pub struct Ini1 {
+    kips: Vec<Vec<u8>>,
+}
+
+impl Ini1 {
+    pub fn new() -> Ini1 {
+        Ini1::default()
+    }
+
+    pub fn from_reader<R: Read>(mut f: R) -> Result<Ini1, Error> {
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != b"INI1" {
+            return Err(Error::InvalidIni1("magic is wrong", Backtrace::new()));
+        }
+        let size = f.read_u32::<LittleEndian>()? as usize;
+        let count = f.read_u32::<LittleEndian>()? as usize;
+        let _reserved = f.read_u32::<LittleEndian>()?;
+        if count > INI1_MAX_KIPS {
+            return Err(Error::InvalidIni1("too many KIPs", Backtrace::new()));
+        }
+
+        let mut ini1 = Ini1::new();
+        let mut offset = 0x10;
+        for _ in 0..count {
+            let mut kip = vec![0; 0x100];
+            f.read_exact(&mut kip)?;
+            if &kip[..4] != b"KIP1" {
+                return Err(Error::InvalidIni1(
+                    "contains an invalid KIP1",
+                    Backtrace::new(),
+                ));
+            }
+            let kip_size = kip_size(&kip);
+            kip.resize(kip_size, 0);
+            f.read_exact(&mut kip[0x100..])?;
+            offset += kip_size;
+            ini1.kips.push(kip);
+        }
+        // The size may include padding after the KIPs.
+        if offset > size {
+            return Err(Error::InvalidIni1(
+                "KIPs don't fit in its size",
+                Backtrace::new(),
+            ));
+        }
+        Ok(ini1)
+    }
+
+    /// The raw KIP1 files, in order.
+    pub fn kips(&self) -> &[Vec<u8>] {
+        &self.kips
+    }
+
+    /// Adds a KIP1 file, as written by `write_kip1`.
+    pub fn push_kip(&mut self, kip: Vec<u8>) -> Result<(), Error> {
+        check_kip(&kip)?;
+        if self.kips.len() == INI1_MAX_KIPS {
+            return Err(Error::InvalidIni1("too many KIPs", Backtrace::new()));
+        }
+        self.kips.push(kip);
+        Ok(())
+    }
+
+    /// Builds a KIP1 from an ELF and its NPDM, and adds it.
+    pub fn push_elf(&mut self, nxo: &mut NxoFile, npdm: &KipNpdm) -> Result<(), Error> {
+        let mut kip = Vec::new();
+        nxo.write_kip1(&mut kip, npdm)?;
+        self.push_kip(kip)
+    }
+
+    /// Replaces the KIP1 with the same name as the given one, or adds it if
+    /// there is none.
+    pub fn replace_kip(&mut self, kip: Vec<u8>) -> Result<(), Error> {
+        check_kip(&kip)?;
+        match self
+            .kips
+            .iter_mut()
+            .find(|old_kip| kip_name(old_kip) == kip_name(&kip))
+        {
+            Some(old_kip) => *old_kip = kip,
+            None => self.push_kip(kip)?,
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        0x10 + self.kips.iter().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn write<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        output_writter.write_all(b"INI1")?;
+        output_writter.write_u32::<LittleEndian>(self.size() as u32)?;
+        output_writter.write_u32::<LittleEndian>(self.kips.len() as u32)?;
+        output_writter.write_u32::<LittleEndian>(0)?;
+        for kip in &self.kips {
+            output_writter.write_all(kip)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_kip(kip: &[u8]) -> Result<(), Error> {
+    if kip.len() < 0x100 || &kip[..4] != b"KIP1" {
+        return Err(Error::InvalidKip1("magic is wrong", Backtrace::new()));
+    }
+    if kip.len() != kip_size(kip) {
+        return Err(Error::InvalidKip1(
+            "size doesn't match its segments",
+            Backtrace::new(),
+        ));
+    }
+    Ok(())
+}
+
+fn kip_name(kip: &[u8]) -> &[u8] {
+    let name = &kip[4..0x10];
+    let len = name
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or_else(|| name.len());
+    &name[..len]
+}
+
+/// The size of a raw KIP1: its header followed by the text, rodata and data
+/// segments.
+fn kip_size(kip: &[u8]) -> usize {
+    0x100
+        + (0..3)
+            .map(|idx| LittleEndian::read_u32(&kip[0x28 + idx * 0x10..]) as usize)
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kip(name: &[u8], text_size: u32) -> Vec<u8> {
+        let mut kip = vec![0; 0x100 + text_size as usize];
+        kip[..4].copy_from_slice(b"KIP1");
+        kip[4..4 + name.len()].copy_from_slice(name);
+        LittleEndian::write_u32(&mut kip[0x28..], text_size);
+        kip
+    }
+
+    #[test]
+    fn ini1_roundtrip() {
+        let mut ini1 = Ini1::new();
+        ini1.push_kip(kip(b"FS", 0x10)).unwrap();
+        ini1.push_kip(kip(b"Loader", 0x20)).unwrap();
+        ini1.replace_kip(kip(b"FS", 0x30)).unwrap();
+
+        let mut data = Vec::new();
+        ini1.write(&mut data).unwrap();
+        assert_eq!(data.len(), ini1.size());
+        let ini1 = Ini1::from_reader(&data[..]).unwrap();
+        assert_eq!(ini1.kips(), &[kip(b"FS", 0x30), kip(b"Loader", 0x20)][..]);
+    }
+
+    #[test]
+    fn padded_ini1() {
+        let mut ini1 = Ini1::new();
+        ini1.push_kip(kip(b"FS", 0x10)).unwrap();
+
+        let mut data = Vec::new();
+        ini1.write(&mut data).unwrap();
+        let size = LittleEndian::read_u32(&data[4..]) + 0x40;
+        LittleEndian::write_u32(&mut data[4..], size);
+        data.resize(size as usize, 0);
+        let ini1 = Ini1::from_reader(&data[..]).unwrap();
+        assert_eq!(ini1.kips(), &[kip(b"FS", 0x10)][..]);
+
+        LittleEndian::write_u32(&mut data[4..], 0x10 + 0x100);
+        assert!(Ini1::from_reader(&data[..]).is_err());
+    }
+}
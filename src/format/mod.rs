@@ -1,6 +1,16 @@
+pub mod cnmt;
+pub mod hfs0;
+pub mod ini1;
 pub mod nacp;
-mod npdm;
+pub mod nax0;
+pub mod nca;
+pub mod npdm;
 pub mod nxo;
+pub mod package1;
+pub mod package2;
 pub mod pfs0;
 pub mod romfs;
+pub mod save;
+pub mod ticket;
 mod utils;
+pub mod xci;
@@ -0,0 +1,179 @@
+//! NAX0, the encryption of the content and saves stored on the SD card.
+
+use crate::error::Error;
+use crate::pki::{AesXtsKey, Keys};
+use crate::utils::seek_position;
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Backtrace;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const NAX0_HEADER_SIZE: u64 = 0x4000;
+const NAX0_SECTOR_SIZE: u64 = 0x4000;
+
+/// A decrypted stream over a NAX0 file.
+pub struct Nax0<R> {
+    file: R,
+    key: AesXtsKey,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Nax0<R> {
+    /// Opens a NAX0 file. Its key is derived from `path`, the path of the
+    /// file relative to the Nintendo/Contents or Nintendo/save folder of the
+    /// SD card, such as `/registered/000000C5/0123456789abcdef0123456789abcdef.nca`.
+    pub fn from_reader(mut f: R, keys: &Keys, path: &str) -> Result<Nax0<R>, Error> {
+        let mut header = [0; 0x80];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut header)?;
+        if &header[0x20..0x24] != b"NAX0" {
+            return Err(Error::InvalidNax0("magic is wrong", Backtrace::new()));
+        }
+        let size = LittleEndian::read_u64(&header[0x48..]);
+
+        let key = keys
+            .sd_card_keys()?
+            .iter()
+            .filter_map(|sd_card_key| sd_card_key.unwrap_nax0_key(path, &header))
+            .next()
+            .ok_or_else(|| {
+                Error::InvalidNax0(
+                    "header MAC mismatch, the path or the sd_seed is wrong",
+                    Backtrace::new(),
+                )
+            })?;
+
+        Ok(Nax0 {
+            file: f,
+            key,
+            size,
+            pos: 0,
+        })
+    }
+
+    /// The size of the decrypted file.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl<R: Read + Seek> Read for Nax0<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // AES-XTS works on whole sectors, so read the surrounding one.
+        let sector = self.pos / NAX0_SECTOR_SIZE;
+        let sector_offset = sector * NAX0_SECTOR_SIZE;
+        let skip = (self.pos - sector_offset) as usize;
+        let remaining = self.size - sector_offset;
+        let sector_size = std::cmp::min(NAX0_SECTOR_SIZE, (remaining + 0xF) & !0xF) as usize;
+        let mut data = vec![0; sector_size];
+        self.file
+            .seek(SeekFrom::Start(NAX0_HEADER_SIZE + sector_offset))?;
+        self.file.read_exact(&mut data)?;
+        self.key
+            .decrypt(&mut data, sector as usize, NAX0_SECTOR_SIZE as usize);
+
+        let len = std::cmp::min(
+            buf.len(),
+            std::cmp::min(sector_size as u64, remaining) as usize - skip,
+        );
+        buf[..len].copy_from_slice(&data[skip..skip + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R> Seek for Nax0<R> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.size, from)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pki::hmac_sha256;
+    use aes::block_cipher_trait::generic_array::GenericArray;
+    use aes::block_cipher_trait::BlockCipher;
+    use aes::Aes128;
+    use std::io::Cursor;
+
+    const PATH: &str = "/registered/000000C5/0123456789abcdef0123456789abcdef.nca";
+    const KEY: [u8; 0x20] = [0x5A; 0x20];
+
+    /// Builds the header of a NAX0 at `PATH`, whose key is `KEY`, wrapped with
+    /// the sd_card_nca_key of `Keys::test_keys`.
+    fn header(size: u64) -> Vec<u8> {
+        let mut sd_card_key = [0; 0x20];
+        for (idx, byte) in sd_card_key.iter_mut().enumerate() {
+            *byte = 0xC0 + idx as u8;
+        }
+        let keks = hmac_sha256(&sd_card_key[..0x10], PATH.as_bytes());
+
+        let mut header = vec![0; NAX0_HEADER_SIZE as usize];
+        header[0x20..0x24].copy_from_slice(b"NAX0");
+        for idx in 0..2 {
+            let kek = Aes128::new(GenericArray::from_slice(
+                &keks[idx * 0x10..(idx + 1) * 0x10],
+            ));
+            let mut block = GenericArray::clone_from_slice(&KEY[idx * 0x10..(idx + 1) * 0x10]);
+            kek.encrypt_block(&mut block);
+            header[0x28 + idx * 0x10..0x38 + idx * 0x10].copy_from_slice(&block);
+        }
+        LittleEndian::write_u64(&mut header[0x48..], size);
+
+        let mut mac_key = header[0x20..0x80].to_vec();
+        mac_key[8..0x28].copy_from_slice(&KEY);
+        let mac = hmac_sha256(&mac_key, &sd_card_key[0x10..]);
+        header[..0x20].copy_from_slice(&mac);
+        header
+    }
+
+    #[test]
+    fn nax0_key_is_unwrapped() {
+        let keys = Keys::test_keys();
+        let sd_card_key = keys.sd_card_keys().unwrap()[0];
+        let header = header(0);
+
+        let key = sd_card_key.unwrap_nax0_key(PATH, &header).unwrap();
+        assert_eq!(key.to_string(), "5A".repeat(0x20));
+        assert!(sd_card_key
+            .unwrap_nax0_key("/registered/000000C5/other.nca", &header)
+            .is_none());
+    }
+
+    #[test]
+    fn nax0_read_across_sectors() {
+        let keys = Keys::test_keys();
+        let plain: Vec<u8> = (0..0x4100).map(|idx| idx as u8).collect();
+        let mut file = header(plain.len() as u64);
+        let mut data = plain.clone();
+        keys.sd_card_keys().unwrap()[0]
+            .unwrap_nax0_key(PATH, &file)
+            .unwrap()
+            .encrypt(&mut data, 0, NAX0_SECTOR_SIZE as usize);
+        file.extend_from_slice(&data);
+
+        assert!(Nax0::from_reader(Cursor::new(file.clone()), &keys, "/other.nca").is_err());
+        let mut nax0 = Nax0::from_reader(Cursor::new(file), &keys, PATH).unwrap();
+        assert_eq!(nax0.len(), plain.len() as u64);
+
+        let mut buf = [0; 0x20];
+        nax0.seek(SeekFrom::Start(0x3FF0)).unwrap();
+        nax0.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &plain[0x3FF0..0x4010]);
+
+        let mut contents = Vec::new();
+        nax0.seek(SeekFrom::Start(0)).unwrap();
+        nax0.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, plain);
+    }
+}
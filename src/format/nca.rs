@@ -0,0 +1,1117 @@
+use crate::error::Error;
+use crate::format::cnmt::PackagedContentMeta;
+use crate::format::pfs0::Pfs0;
+use crate::format::romfs::{RomFs, RomFsReader};
+use crate::pki::Keys;
+use crate::utils::{align_up, seek_position, to_hex, TryClone};
+use aes::block_cipher_trait::generic_array::GenericArray;
+use aes::Aes128;
+use block_modes::block_padding::ZeroPadding;
+use block_modes::{BlockMode, BlockModeIv, Ctr128};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Backtrace;
+use sha2::{Digest, Sha256};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+/// NCA offsets and sizes in the section table are expressed in media units.
+pub const MEDIA_UNIT_SIZE: u64 = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NcaContentType {
+    Program,
+    Meta,
+    Control,
+    Manual,
+    Data,
+    PublicData,
+}
+
+impl NcaContentType {
+    fn from_u8(value: u8) -> Result<NcaContentType, Error> {
+        match value {
+            0 => Ok(NcaContentType::Program),
+            1 => Ok(NcaContentType::Meta),
+            2 => Ok(NcaContentType::Control),
+            3 => Ok(NcaContentType::Manual),
+            4 => Ok(NcaContentType::Data),
+            5 => Ok(NcaContentType::PublicData),
+            _ => Err(Error::InvalidNca("unknown content type", Backtrace::new())),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            NcaContentType::Program => 0,
+            NcaContentType::Meta => 1,
+            NcaContentType::Control => 2,
+            NcaContentType::Manual => 3,
+            NcaContentType::Data => 4,
+            NcaContentType::PublicData => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NcaFsType {
+    RomFs,
+    Pfs0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NcaEncryptionType {
+    None,
+    Xts,
+    Ctr,
+    Bktr,
+}
+
+/// A level of a hash tree, relative to the start of the section.
+#[derive(Debug, Clone, Copy)]
+pub struct NcaHashLevel {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// The hash info of a PFS0 section: the PFS0 is split in blocks, whose
+/// hashes are stored in a table, itself hashed into the master hash.
+#[derive(Debug, Clone)]
+pub struct Pfs0HashInfo {
+    pub master_hash: [u8; 0x20],
+    pub block_size: u32,
+    pub hash_table: NcaHashLevel,
+    pub pfs0: NcaHashLevel,
+}
+
+/// The hash info of a RomFS section: an IVFC tree of 6 levels, the last one
+/// being the RomFS itself.
+#[derive(Debug, Clone)]
+pub struct IvfcHashInfo {
+    pub levels: [NcaHashLevel; 6],
+    pub block_sizes_log2: [u32; 6],
+    pub master_hash: [u8; 0x20],
+}
+
+#[derive(Debug, Clone)]
+pub enum NcaHashInfo {
+    Pfs0(Pfs0HashInfo),
+    Ivfc(IvfcHashInfo),
+}
+
+#[derive(Debug, Clone)]
+pub struct NcaFsHeader {
+    pub version: u16,
+    pub fs_type: NcaFsType,
+    pub encryption_type: NcaEncryptionType,
+    pub hash_info: NcaHashInfo,
+    /// The upper half of the AES-CTR counter.
+    pub section_ctr: [u8; 8],
+}
+
+impl NcaFsHeader {
+    fn parse(data: &[u8; 0x200]) -> Result<NcaFsHeader, Error> {
+        let version = LittleEndian::read_u16(&data[0..2]);
+        let fs_type = match data[2] {
+            0 => NcaFsType::RomFs,
+            1 => NcaFsType::Pfs0,
+            _ => return Err(Error::InvalidNca("unknown FS type", Backtrace::new())),
+        };
+        let encryption_type = match data[4] {
+            1 => NcaEncryptionType::None,
+            2 => NcaEncryptionType::Xts,
+            3 => NcaEncryptionType::Ctr,
+            4 => NcaEncryptionType::Bktr,
+            _ => {
+                return Err(Error::InvalidNca(
+                    "unknown encryption type",
+                    Backtrace::new(),
+                ))
+            }
+        };
+
+        let hash_data = &data[0x8..0x100];
+        let hash_info = match data[3] {
+            2 => {
+                let mut master_hash = [0; 0x20];
+                master_hash.copy_from_slice(&hash_data[0..0x20]);
+                NcaHashInfo::Pfs0(Pfs0HashInfo {
+                    master_hash,
+                    block_size: LittleEndian::read_u32(&hash_data[0x20..0x24]),
+                    hash_table: NcaHashLevel {
+                        offset: LittleEndian::read_u64(&hash_data[0x28..0x30]),
+                        size: LittleEndian::read_u64(&hash_data[0x30..0x38]),
+                    },
+                    pfs0: NcaHashLevel {
+                        offset: LittleEndian::read_u64(&hash_data[0x38..0x40]),
+                        size: LittleEndian::read_u64(&hash_data[0x40..0x48]),
+                    },
+                })
+            }
+            3 => {
+                if &hash_data[0..4] != b"IVFC" {
+                    return Err(Error::InvalidNca("IVFC magic is wrong", Backtrace::new()));
+                }
+                let mut levels = [NcaHashLevel { offset: 0, size: 0 }; 6];
+                let mut block_sizes_log2 = [0; 6];
+                for (idx, level_data) in hash_data[0x10..0xA0].chunks(0x18).enumerate() {
+                    levels[idx] = NcaHashLevel {
+                        offset: LittleEndian::read_u64(&level_data[0..8]),
+                        size: LittleEndian::read_u64(&level_data[8..0x10]),
+                    };
+                    block_sizes_log2[idx] = LittleEndian::read_u32(&level_data[0x10..0x14]);
+                    if block_sizes_log2[idx] >= 32 {
+                        return Err(Error::InvalidNca(
+                            "IVFC block size is too big",
+                            Backtrace::new(),
+                        ));
+                    }
+                }
+                let mut master_hash = [0; 0x20];
+                master_hash.copy_from_slice(&hash_data[0xC0..0xE0]);
+                NcaHashInfo::Ivfc(IvfcHashInfo {
+                    levels,
+                    block_sizes_log2,
+                    master_hash,
+                })
+            }
+            _ => return Err(Error::InvalidNca("unknown hash type", Backtrace::new())),
+        };
+
+        // The section counter is stored little endian, but used as the big
+        // endian upper half of the counter.
+        let mut section_ctr = [0; 8];
+        section_ctr.copy_from_slice(&data[0x140..0x148]);
+        section_ctr.reverse();
+
+        Ok(NcaFsHeader {
+            version,
+            fs_type,
+            encryption_type,
+            hash_info,
+            section_ctr,
+        })
+    }
+}
+
+/// A section of the NCA, with its location in the NCA file in bytes.
+#[derive(Debug, Clone)]
+pub struct NcaSection {
+    pub start_offset: u64,
+    pub end_offset: u64,
+    pub fs_header: NcaFsHeader,
+}
+
+#[derive(Debug, Clone)]
+pub struct NcaHeader {
+    /// 2 or 3, from the NCA2 or NCA3 magic.
+    pub format_version: u8,
+    pub is_gamecard: bool,
+    pub content_type: NcaContentType,
+    pub key_area_key_index: u8,
+    pub content_size: u64,
+    pub title_id: u64,
+    pub content_index: u32,
+    pub sdk_version: u32,
+    /// Combined key generation, from both key generation fields.
+    pub key_generation: u8,
+    pub signature_key_generation: u8,
+    pub rights_id: [u8; 0x10],
+    pub sections: [Option<NcaSection>; 4],
+    pub encrypted_key_area: [[u8; 0x10]; 4],
+}
+
+impl NcaHeader {
+    /// The master key revision used for the key area.
+    pub fn master_key_revision(&self) -> usize {
+        usize::from(self.key_generation.saturating_sub(1))
+    }
+
+    pub fn has_rights_id(&self) -> bool {
+        self.rights_id.iter().any(|v| *v != 0)
+    }
+}
+
+/// Decrypts a raw NCA header. NCA3 headers are encrypted as a whole, while
+/// NCA2 FS headers are each encrypted as sector 0.
+fn decrypt_header(keys: &Keys, header: &mut [u8; 0xC00]) -> Result<u8, Error> {
+    let header_key = keys.header_key()?;
+    header_key.decrypt(&mut header[..0x400], 0, 0x200);
+    match &header[0x200..0x204] {
+        b"NCA3" => {
+            header_key.decrypt(&mut header[0x400..], 2, 0x200);
+            Ok(3)
+        }
+        b"NCA2" => {
+            for fs_header in header[0x400..].chunks_mut(0x200) {
+                header_key.decrypt(fs_header, 0, 0x200);
+            }
+            Ok(2)
+        }
+        _ => Err(Error::InvalidNca(
+            "magic is wrong, the header_key is likely wrong",
+            Backtrace::new(),
+        )),
+    }
+}
+
+pub struct Nca<R> {
+    file: R,
+    header: NcaHeader,
+    key_area: [[u8; 0x10]; 4],
+    /// The AES-CTR key of the sections: the third key of the key area, or
+    /// the title key for NCAs with a rights ID. None if the title key is
+    /// unknown.
+    section_key: Option<[u8; 0x10]>,
+}
+
+impl<R: Read + Seek> Nca<R> {
+    /// Opens an NCA, decrypting its header with the `header_key` and its key
+    /// area with the key area key of its key generation. NCAs with a rights
+    /// ID use the title key from `keys` instead.
+    pub fn from_reader(mut f: R, keys: &Keys) -> Result<Nca<R>, Error> {
+        let mut raw_header = [0; 0xC00];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut raw_header)?;
+        let format_version = decrypt_header(keys, &mut raw_header)?;
+
+        let mut cursor = Cursor::new(&raw_header[0x204..]);
+        let is_gamecard = cursor.read_u8()? == 1;
+        let content_type = NcaContentType::from_u8(cursor.read_u8()?)?;
+        let key_generation_old = cursor.read_u8()?;
+        let key_area_key_index = cursor.read_u8()?;
+        let content_size = cursor.read_u64::<LittleEndian>()?;
+        let title_id = cursor.read_u64::<LittleEndian>()?;
+        let content_index = cursor.read_u32::<LittleEndian>()?;
+        let sdk_version = cursor.read_u32::<LittleEndian>()?;
+        let key_generation = cursor.read_u8()?;
+        let signature_key_generation = cursor.read_u8()?;
+        let mut rights_id = [0; 0x10];
+        rights_id.copy_from_slice(&raw_header[0x230..0x240]);
+
+        let mut sections = [None, None, None, None];
+        for (idx, section) in sections.iter_mut().enumerate() {
+            let entry = &raw_header[0x240 + idx * 0x10..0x250 + idx * 0x10];
+            let start_offset = u64::from(LittleEndian::read_u32(&entry[0..4])) * MEDIA_UNIT_SIZE;
+            let end_offset = u64::from(LittleEndian::read_u32(&entry[4..8])) * MEDIA_UNIT_SIZE;
+            if start_offset == 0 && end_offset == 0 {
+                continue;
+            }
+            if end_offset < start_offset {
+                return Err(Error::InvalidNca(
+                    "section ends before it starts",
+                    Backtrace::new(),
+                ));
+            }
+
+            let mut fs_header_data = [0; 0x200];
+            fs_header_data.copy_from_slice(&raw_header[0x400 + idx * 0x200..0x600 + idx * 0x200]);
+            let mut hasher = Sha256::default();
+            hasher.input(&fs_header_data[..]);
+            if hasher.result().as_slice() != &raw_header[0x280 + idx * 0x20..0x2A0 + idx * 0x20] {
+                return Err(Error::InvalidNca(
+                    "FS header hash mismatch",
+                    Backtrace::new(),
+                ));
+            }
+            *section = Some(NcaSection {
+                start_offset,
+                end_offset,
+                fs_header: NcaFsHeader::parse(&fs_header_data)?,
+            });
+        }
+
+        let mut encrypted_key_area = [[0; 0x10]; 4];
+        for (idx, key) in encrypted_key_area.iter_mut().enumerate() {
+            key.copy_from_slice(&raw_header[0x300 + idx * 0x10..0x310 + idx * 0x10]);
+        }
+
+        let header = NcaHeader {
+            format_version,
+            is_gamecard,
+            content_type,
+            key_area_key_index,
+            content_size,
+            title_id,
+            content_index,
+            sdk_version,
+            key_generation: key_generation_old.max(key_generation),
+            signature_key_generation,
+            rights_id,
+            sections,
+            encrypted_key_area,
+        };
+
+        // NCAs with a rights ID are encrypted with a title key instead of the
+        // key area. The header can still be read without it.
+        let mut key_area = [[0; 0x10]; 4];
+        let section_key = if header.has_rights_id() {
+            keys.title_key(&header.rights_id).ok()
+        } else {
+            let key_area_key =
+                keys.key_area_key(key_area_key_index, header.master_key_revision())?;
+            for (key, encrypted_key) in key_area.iter_mut().zip(encrypted_key_area.iter()) {
+                *key = key_area_key.decrypt_block(encrypted_key);
+            }
+            Some(key_area[2])
+        };
+
+        Ok(Nca {
+            file: f,
+            header,
+            key_area,
+            section_key,
+        })
+    }
+
+    pub fn header(&self) -> &NcaHeader {
+        &self.header
+    }
+
+    /// The decrypted key area. Index 2 holds the AES-CTR key of the sections.
+    /// It is left zeroed for NCAs with a rights ID.
+    pub fn key_area(&self) -> &[[u8; 0x10]; 4] {
+        &self.key_area
+    }
+
+    pub fn into_inner(self) -> R {
+        self.file
+    }
+}
+
+impl<R: Read + Seek + TryClone> Nca<R> {
+    fn section(&self, idx: usize) -> Result<&NcaSection, Error> {
+        self.header
+            .sections
+            .get(idx)
+            .and_then(|section| section.as_ref())
+            .ok_or_else(|| Error::InvalidNca("section does not exist", Backtrace::new()))
+    }
+
+    /// Opens the decrypted data of a section, without any hash check.
+    pub fn section_reader(&self, idx: usize) -> Result<NcaSectionReader<R>, Error> {
+        let section = self.section(idx)?;
+        let key = match section.fs_header.encryption_type {
+            NcaEncryptionType::None => None,
+            NcaEncryptionType::Ctr => Some(self.section_key.ok_or_else(|| {
+                Error::MissingKey(
+                    format!("title key for rights ID {}", to_hex(&self.header.rights_id)),
+                    Backtrace::new(),
+                )
+            })?),
+            NcaEncryptionType::Xts | NcaEncryptionType::Bktr => {
+                return Err(Error::InvalidNca(
+                    "only unencrypted and AES-CTR sections are supported",
+                    Backtrace::new(),
+                ))
+            }
+        };
+        Ok(NcaSectionReader {
+            file: self.file.try_clone()?,
+            start_offset: section.start_offset,
+            size: section.end_offset - section.start_offset,
+            key,
+            section_ctr: section.fs_header.section_ctr,
+            pos: 0,
+        })
+    }
+
+    /// Opens the filesystem data of a section: the PFS0 or the RomFS. Every
+    /// hash level is checked against the master hash of the FS header, and
+    /// the data blocks are checked as they are read.
+    pub fn open_section(
+        &self,
+        idx: usize,
+    ) -> Result<HashVerifiedReader<NcaSectionReader<R>>, Error> {
+        let section = self.section(idx)?;
+        let mut reader = self.section_reader(idx)?;
+
+        let read_level = |reader: &mut NcaSectionReader<R>, level: &NcaHashLevel| {
+            if level.offset + level.size > reader.size {
+                return Err(Error::InvalidNca(
+                    "hash level is out of the section",
+                    Backtrace::new(),
+                ));
+            }
+            let mut data = vec![0; level.size as usize];
+            reader.seek(SeekFrom::Start(level.offset))?;
+            reader.read_exact(&mut data)?;
+            Ok(data)
+        };
+
+        match &section.fs_header.hash_info {
+            NcaHashInfo::Pfs0(info) => {
+                let hash_table = read_level(&mut reader, &info.hash_table)?;
+                if !verify_blocks(
+                    &hash_table,
+                    &info.master_hash,
+                    hash_table.len() as u64,
+                    false,
+                ) {
+                    return Err(Error::NcaIntegrity(idx, Backtrace::new()));
+                }
+                HashVerifiedReader::new(
+                    reader,
+                    info.pfs0,
+                    u64::from(info.block_size),
+                    hash_table,
+                    false,
+                )
+            }
+            NcaHashInfo::Ivfc(info) => {
+                let mut hashes = info.master_hash.to_vec();
+                for (level, block_size_log2) in
+                    info.levels[..5].iter().zip(info.block_sizes_log2.iter())
+                {
+                    let data = read_level(&mut reader, level)?;
+                    if !verify_blocks(&data, &hashes, 1u64 << block_size_log2, true) {
+                        return Err(Error::NcaIntegrity(idx, Backtrace::new()));
+                    }
+                    hashes = data;
+                }
+                HashVerifiedReader::new(
+                    reader,
+                    info.levels[5],
+                    1u64 << info.block_sizes_log2[5],
+                    hashes,
+                    true,
+                )
+            }
+        }
+    }
+
+    /// Checks every block of a section against its hash tree.
+    pub fn verify_section(&self, idx: usize) -> Result<(), Error> {
+        let mut reader = self.open_section(idx)?;
+        match io::copy(&mut reader, &mut io::sink()) {
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                Err(Error::NcaIntegrity(idx, Backtrace::new()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<R: Read + Seek + TryClone + 'static> Nca<R> {
+    pub fn open_pfs0(&self, idx: usize) -> Result<Pfs0, Error> {
+        Pfs0::from_reader(self.open_section(idx)?)
+    }
+
+    pub fn open_romfs(
+        &self,
+        idx: usize,
+    ) -> Result<RomFsReader<HashVerifiedReader<NcaSectionReader<R>>>, Error> {
+        RomFsReader::from_reader(self.open_section(idx)?)
+    }
+}
+
+/// Checks `data`, split in blocks of `block_size`, against a table of SHA-256
+/// hashes. When `pad_last_block` is set, the last block is hashed as if it
+/// was padded with zeroes to the block size.
+fn verify_blocks(data: &[u8], hashes: &[u8], block_size: u64, pad_last_block: bool) -> bool {
+    if block_size == 0 {
+        return false;
+    }
+    data.chunks(block_size as usize)
+        .enumerate()
+        .all(
+            |(idx, block)| match hashes.get(idx * 0x20..idx * 0x20 + 0x20) {
+                Some(hash) => hash_block(block, block_size, pad_last_block)[..] == *hash,
+                None => false,
+            },
+        )
+}
+
+fn hash_block(block: &[u8], block_size: u64, pad: bool) -> [u8; 0x20] {
+    let mut hasher = Sha256::default();
+    hasher.input(block);
+    if pad && (block.len() as u64) < block_size {
+        hasher.input(&vec![0; block_size as usize - block.len()]);
+    }
+    let mut hash = [0; 0x20];
+    hash.copy_from_slice(hasher.result().as_slice());
+    hash
+}
+
+/// Encrypts or decrypts section data with AES-CTR. `offset` is the offset of
+/// `data` in the NCA, and must be aligned to 16 bytes.
+fn section_ctr_crypt(
+    key: &[u8; 0x10],
+    section_ctr: [u8; 8],
+    offset: u64,
+    data: &mut [u8],
+) -> io::Result<()> {
+    let mut ctr = [0; 0x10];
+    ctr[..8].copy_from_slice(&section_ctr);
+    BigEndian::write_u64(&mut ctr[8..], offset >> 4);
+    let mut crypter = Ctr128::<Aes128, ZeroPadding>::new_fixkey(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(&ctr),
+    );
+    crypter
+        .encrypt_nopad(data)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "AES-CTR encryption failed"))
+}
+
+/// A decrypted stream over an NCA section.
+pub struct NcaSectionReader<R> {
+    file: R,
+    start_offset: u64,
+    size: u64,
+    key: Option<[u8; 0x10]>,
+    section_ctr: [u8; 8],
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for NcaSectionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Don't decrypt more than 1MiB at once.
+        let len = (buf.len() as u64)
+            .min(self.size.saturating_sub(self.pos))
+            .min(0x10_0000) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        // AES-CTR works on 16 bytes blocks, so read the surrounding blocks.
+        let offset = self.start_offset + self.pos;
+        let aligned_offset = offset & !0xF;
+        let skip = (offset - aligned_offset) as usize;
+        let mut data = vec![0; (skip + len + 0xF) & !0xF];
+        self.file.seek(SeekFrom::Start(aligned_offset))?;
+        self.file.read_exact(&mut data)?;
+
+        if let Some(key) = &self.key {
+            section_ctr_crypt(key, self.section_ctr, aligned_offset, &mut data)?;
+        }
+
+        buf[..len].copy_from_slice(&data[skip..skip + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R> Seek for NcaSectionReader<R> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.size, from)?;
+        Ok(self.pos)
+    }
+}
+
+impl<R: TryClone> TryClone for NcaSectionReader<R> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(NcaSectionReader {
+            file: self.file.try_clone()?,
+            start_offset: self.start_offset,
+            size: self.size,
+            key: self.key,
+            section_ctr: self.section_ctr,
+            pos: self.pos,
+        })
+    }
+}
+
+/// A stream over a region of `R` split in blocks, where each block is checked
+/// against a table of SHA-256 hashes when read. Hash mismatches are reported
+/// as `io::ErrorKind::InvalidData` errors.
+pub struct HashVerifiedReader<R> {
+    inner: R,
+    offset: u64,
+    size: u64,
+    block_size: u64,
+    hashes: Rc<Vec<u8>>,
+    pad_last_block: bool,
+    pos: u64,
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> HashVerifiedReader<R> {
+    fn new(
+        inner: R,
+        region: NcaHashLevel,
+        block_size: u64,
+        hashes: Vec<u8>,
+        pad_last_block: bool,
+    ) -> Result<HashVerifiedReader<R>, Error> {
+        if block_size == 0
+            || (region.size + block_size - 1) / block_size * 0x20 > hashes.len() as u64
+        {
+            return Err(Error::InvalidNca(
+                "hash table is too small",
+                Backtrace::new(),
+            ));
+        }
+        Ok(HashVerifiedReader {
+            inner,
+            offset: region.offset,
+            size: region.size,
+            block_size,
+            hashes: Rc::new(hashes),
+            pad_last_block,
+            pos: 0,
+            block: None,
+        })
+    }
+
+    fn load_block(&mut self, block_idx: u64) -> io::Result<()> {
+        if let Some((cached_idx, _)) = &self.block {
+            if *cached_idx == block_idx {
+                return Ok(());
+            }
+        }
+        let block_offset = block_idx * self.block_size;
+        let mut data = vec![0; self.block_size.min(self.size - block_offset) as usize];
+        self.inner
+            .seek(SeekFrom::Start(self.offset + block_offset))?;
+        self.inner.read_exact(&mut data)?;
+
+        let hash_offset = block_idx as usize * 0x20;
+        let hash = hash_block(&data, self.block_size, self.pad_last_block);
+        if hash[..] != self.hashes[hash_offset..hash_offset + 0x20] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Hash mismatch in block {}", block_idx),
+            ));
+        }
+        self.block = Some((block_idx, data));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for HashVerifiedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_idx = self.pos / self.block_size;
+        self.load_block(block_idx)?;
+        let block = &self.block.as_ref().unwrap().1;
+        let block_pos = (self.pos - block_idx * self.block_size) as usize;
+        let len = buf.len().min(block.len() - block_pos);
+        buf[..len].copy_from_slice(&block[block_pos..block_pos + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R> Seek for HashVerifiedReader<R> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.size, from)?;
+        Ok(self.pos)
+    }
+}
+
+impl<R: TryClone> TryClone for HashVerifiedReader<R> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(HashVerifiedReader {
+            inner: self.inner.try_clone()?,
+            offset: self.offset,
+            size: self.size,
+            block_size: self.block_size,
+            hashes: self.hashes.clone(),
+            pad_last_block: self.pad_last_block,
+            pos: self.pos,
+            block: None,
+        })
+    }
+}
+
+/// The block size of the PFS0 hash table.
+const PFS0_HASH_BLOCK_SIZE: u64 = 0x1000;
+
+/// The block size of every IVFC level, as a power of two.
+const IVFC_BLOCK_SIZE_LOG2: u32 = 14;
+
+/// The data of a section to build.
+pub enum NcaSectionData {
+    Pfs0(Pfs0),
+    RomFs(RomFs),
+}
+
+impl NcaSectionData {
+    /// Builds the plaintext section, with its hash layers, and the matching
+    /// FS header.
+    fn build(&mut self, index: usize) -> Result<(Vec<u8>, [u8; 0x200]), Error> {
+        let mut fs_header = [0; 0x200];
+        LittleEndian::write_u16(&mut fs_header[0..2], 2); // Version
+        fs_header[4] = 3; // AES-CTR
+
+        // Generation and secure value, making up the upper half of the
+        // section counter. The secure value only needs to differ between
+        // sections.
+        LittleEndian::write_u32(&mut fs_header[0x144..0x148], index as u32 + 1);
+
+        let mut section = vec![];
+        let hash_info = &mut fs_header[0x8..0x100];
+        let (fs_type, hash_type) = match self {
+            NcaSectionData::Pfs0(pfs0) => {
+                let mut data = Cursor::new(vec![]);
+                pfs0.write_pfs0(&mut data)?;
+                let data = data.into_inner();
+
+                for block in data.chunks(PFS0_HASH_BLOCK_SIZE as usize) {
+                    section.extend_from_slice(&hash_block(block, PFS0_HASH_BLOCK_SIZE, false));
+                }
+                let hash_table_size = section.len() as u64;
+                let pfs0_offset = align_up(hash_table_size, MEDIA_UNIT_SIZE);
+                section.resize(pfs0_offset as usize, 0);
+                section.extend_from_slice(&data);
+
+                hash_info[0..0x20].copy_from_slice(&hash_block(
+                    &section[..hash_table_size as usize],
+                    hash_table_size,
+                    false,
+                ));
+                let mut cursor = Cursor::new(&mut hash_info[0x20..]);
+                cursor.write_u32::<LittleEndian>(PFS0_HASH_BLOCK_SIZE as u32)?;
+                cursor.write_u32::<LittleEndian>(2)?; // Layer count
+                cursor.write_u64::<LittleEndian>(0)?;
+                cursor.write_u64::<LittleEndian>(hash_table_size)?;
+                cursor.write_u64::<LittleEndian>(pfs0_offset)?;
+                cursor.write_u64::<LittleEndian>(data.len() as u64)?;
+                // PFS0, hierarchical SHA-256
+                (1, 2)
+            }
+            NcaSectionData::RomFs(romfs) => {
+                let block_size = 1u64 << IVFC_BLOCK_SIZE_LOG2;
+
+                // Level 5 is the RomFS, and each level holds the hashes of
+                // the blocks of the next one.
+                let mut levels = vec![vec![]; 6];
+                romfs.write(&mut levels[5])?;
+                for idx in (0..5).rev() {
+                    let hashes = levels[idx + 1]
+                        .chunks(block_size as usize)
+                        .flat_map(|block| hash_block(block, block_size, true).to_vec())
+                        .collect();
+                    levels[idx] = hashes;
+                }
+                if levels[0].len() as u64 > block_size {
+                    return Err(Error::InvalidNca("the RomFS is too big", Backtrace::new()));
+                }
+
+                hash_info[0..4].copy_from_slice(b"IVFC");
+                let mut cursor = Cursor::new(&mut hash_info[4..0xA0]);
+                cursor.write_u32::<LittleEndian>(0x20000)?; // Version
+                cursor.write_u32::<LittleEndian>(0x20)?; // Master hash size
+                cursor.write_u32::<LittleEndian>(7)?; // Level count
+                for level in levels.iter() {
+                    let offset = section.len() as u64;
+                    section.extend_from_slice(level);
+                    section.resize(align_up(section.len() as u64, block_size) as usize, 0);
+                    cursor.write_u64::<LittleEndian>(offset)?;
+                    cursor.write_u64::<LittleEndian>(level.len() as u64)?;
+                    cursor.write_u32::<LittleEndian>(IVFC_BLOCK_SIZE_LOG2)?;
+                    cursor.write_u32::<LittleEndian>(0)?; // Reserved
+                }
+                hash_info[0xC0..0xE0].copy_from_slice(&hash_block(&levels[0], block_size, true));
+                // RomFS, IVFC
+                (0, 3)
+            }
+        };
+        fs_header[2] = fs_type;
+        fs_header[3] = hash_type;
+        section.resize(align_up(section.len() as u64, MEDIA_UNIT_SIZE) as usize, 0);
+        Ok((section, fs_header))
+    }
+}
+
+/// Generates a key for the key area, or a title key, from the random number
+/// generator of the OS.
+pub fn generate_key() -> Result<[u8; 0x10], Error> {
+    let mut key = [0; 0x10];
+    getrandom::getrandom(&mut key).map_err(|err| {
+        Error::Crypto(
+            format!("Failed to generate a key: {}", err),
+            Backtrace::new(),
+        )
+    })?;
+    Ok(key)
+}
+
+/// The languages of the control NCA icons, in NACP order.
+const ICON_LANGUAGES: [&str; 15] = [
+    "AmericanEnglish",
+    "BritishEnglish",
+    "Japanese",
+    "French",
+    "German",
+    "LatinAmericanSpanish",
+    "Spanish",
+    "Italian",
+    "Dutch",
+    "CanadianFrench",
+    "Portuguese",
+    "Russian",
+    "Korean",
+    "TraditionalChinese",
+    "SimplifiedChinese",
+];
+
+/// Builds an NCA out of up to 4 sections. The sections are encrypted with
+/// AES-CTR with a generated key, stored in the key area, or with the title
+/// key when set. The header is not signed, as we don't have the private keys.
+pub struct NcaBuilder {
+    pub content_type: NcaContentType,
+    pub title_id: u64,
+    pub key_generation: u8,
+    pub sdk_version: u32,
+    /// Encrypts the sections with this key instead of the key area, and sets
+    /// the rights ID. The title key then has to be shipped in a ticket.
+    pub title_key: Option<[u8; 0x10]>,
+    pub sections: Vec<NcaSectionData>,
+    /// The AES-CTR key to store in the key area, generated when writing if
+    /// None.
+    section_key: Option<[u8; 0x10]>,
+}
+
+impl NcaBuilder {
+    /// Creates a program NCA, from an ExeFS and an optional RomFS.
+    pub fn new_program(
+        title_id: u64,
+        exefs: Pfs0,
+        romfs: Option<RomFs>,
+    ) -> Result<NcaBuilder, Error> {
+        let file_names = exefs.file_names();
+        if !file_names.contains(&"main") || !file_names.contains(&"main.npdm") {
+            return Err(Error::InvalidNca(
+                "the ExeFS must contain main and main.npdm",
+                Backtrace::new(),
+            ));
+        }
+        let mut sections = vec![NcaSectionData::Pfs0(exefs)];
+        if let Some(romfs) = romfs {
+            sections.push(NcaSectionData::RomFs(romfs));
+        }
+        Ok(NcaBuilder::new(NcaContentType::Program, title_id, sections))
+    }
+
+    /// Creates a control NCA, from a NACP and an optional JPEG icon. The icon
+    /// is used for every language.
+    pub fn new_control(title_id: u64, nacp: Vec<u8>, icon: Option<Vec<u8>>) -> NcaBuilder {
+        let mut romfs = RomFs::empty();
+        romfs.push_data(nacp, "control.nacp");
+        if let Some(icon) = icon {
+            for language in ICON_LANGUAGES.iter() {
+                romfs.push_data(icon.clone(), &format!("icon_{}.dat", language));
+            }
+        }
+        NcaBuilder::new(
+            NcaContentType::Control,
+            title_id,
+            vec![NcaSectionData::RomFs(romfs)],
+        )
+    }
+
+    /// Creates a manual NCA, such as the HTML document or the legal
+    /// information of a title, from its RomFS.
+    pub fn new_manual(title_id: u64, romfs: RomFs) -> NcaBuilder {
+        NcaBuilder::new(
+            NcaContentType::Manual,
+            title_id,
+            vec![NcaSectionData::RomFs(romfs)],
+        )
+    }
+
+    /// Creates the meta NCA of a title, holding its CNMT.
+    pub fn new_meta(cnmt: &PackagedContentMeta) -> Result<NcaBuilder, Error> {
+        let mut data = vec![];
+        cnmt.write(&mut data)?;
+        let mut pfs0 = Pfs0::empty();
+        pfs0.push_file(cnmt.file_name(), Cursor::new(data))?;
+        Ok(NcaBuilder::new(
+            NcaContentType::Meta,
+            cnmt.title_id,
+            vec![NcaSectionData::Pfs0(pfs0)],
+        ))
+    }
+
+    fn new(
+        content_type: NcaContentType,
+        title_id: u64,
+        sections: Vec<NcaSectionData>,
+    ) -> NcaBuilder {
+        NcaBuilder {
+            content_type,
+            title_id,
+            key_generation: 0,
+            sdk_version: 0x000C_1100,
+            title_key: None,
+            sections,
+            section_key: None,
+        }
+    }
+
+    pub fn master_key_revision(&self) -> u8 {
+        self.key_generation.saturating_sub(1)
+    }
+
+    /// The rights ID of NCAs using titlekey crypto: the title ID, followed by
+    /// the master key revision.
+    pub fn rights_id(&self) -> [u8; 0x10] {
+        let mut rights_id = [0; 0x10];
+        BigEndian::write_u64(&mut rights_id[..8], self.title_id);
+        rights_id[0xF] = self.master_key_revision();
+        rights_id
+    }
+
+    pub fn write<W: Write>(&mut self, keys: &Keys, output_writter: &mut W) -> Result<(), Error> {
+        if self.sections.is_empty() || self.sections.len() > 4 {
+            return Err(Error::InvalidNca(
+                "an NCA must have between 1 and 4 sections",
+                Backtrace::new(),
+            ));
+        }
+
+        let mut header = [0; 0xC00];
+        let mut sections = vec![];
+        let mut offset = 0xC00;
+        for (idx, section_data) in self.sections.iter_mut().enumerate() {
+            let (section, fs_header) = section_data.build(idx)?;
+
+            let entry = &mut header[0x240 + idx * 0x10..0x250 + idx * 0x10];
+            LittleEndian::write_u32(&mut entry[0..4], (offset / MEDIA_UNIT_SIZE) as u32);
+            offset += section.len() as u64;
+            LittleEndian::write_u32(&mut entry[4..8], (offset / MEDIA_UNIT_SIZE) as u32);
+            entry[8] = 1; // Enabled
+
+            header[0x280 + idx * 0x20..0x2A0 + idx * 0x20]
+                .copy_from_slice(&hash_block(&fs_header, 0x200, false));
+            header[0x400 + idx * 0x200..0x600 + idx * 0x200].copy_from_slice(&fs_header);
+            sections.push(section);
+        }
+
+        // Header
+        header[0x200..0x204].copy_from_slice(b"NCA3");
+        header[0x204] = 0; // Distribution type: download
+        header[0x205] = self.content_type.to_u8();
+        if self.key_generation <= 2 {
+            header[0x206] = self.key_generation;
+        } else {
+            header[0x206] = 2;
+            header[0x220] = self.key_generation;
+        }
+        header[0x207] = 0; // Application key area key
+        LittleEndian::write_u64(&mut header[0x208..0x210], offset);
+        LittleEndian::write_u64(&mut header[0x210..0x218], self.title_id);
+        LittleEndian::write_u32(&mut header[0x21C..0x220], self.sdk_version);
+
+        let key = match self.title_key {
+            Some(title_key) => {
+                header[0x230..0x240].copy_from_slice(&self.rights_id());
+                title_key
+            }
+            None => {
+                // Key area. Only the AES-CTR key is used.
+                let key = match self.section_key {
+                    Some(key) => key,
+                    None => generate_key()?,
+                };
+                let master_key_revision = usize::from(self.master_key_revision());
+                let key_area_key = keys.key_area_key(0, master_key_revision)?;
+                header[0x320..0x330].copy_from_slice(&key_area_key.encrypt_block(&key));
+                key
+            }
+        };
+
+        // Sections, each encrypted at its offset in the NCA.
+        let mut section_offset = 0xC00;
+        for (idx, section) in sections.iter_mut().enumerate() {
+            let mut section_ctr = [0; 8];
+            section_ctr.copy_from_slice(&header[0x540 + idx * 0x200..0x548 + idx * 0x200]);
+            section_ctr.reverse();
+            section_ctr_crypt(&key, section_ctr, section_offset, section)?;
+            section_offset += section.len() as u64;
+        }
+
+        keys.header_key()?.encrypt(&mut header, 0, 0x200);
+        output_writter.write_all(&header)?;
+        for section in sections {
+            output_writter.write_all(&section)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn program_nca_roundtrip() {
+        let keys = Keys::test_keys();
+        let mut exefs = Pfs0::empty();
+        let main = (0..0x2345).map(|idx| idx as u8).collect::<Vec<u8>>();
+        exefs
+            .push_file(String::from("main"), Cursor::new(main.clone()))
+            .unwrap();
+        exefs
+            .push_file(String::from("main.npdm"), Cursor::new(b"META".to_vec()))
+            .unwrap();
+        let mut builder = NcaBuilder::new_program(0x0100_0000_0000_1234, exefs, None).unwrap();
+        builder.key_generation = 3;
+        builder.section_key = Some([0x5A; 0x10]);
+        let mut data = vec![];
+        builder.write(&keys, &mut data).unwrap();
+
+        let nca = Nca::from_reader(Cursor::new(Rc::<[u8]>::from(data)), &keys).unwrap();
+        let header = nca.header();
+        assert_eq!(header.format_version, 3);
+        assert_eq!(header.content_type, NcaContentType::Program);
+        assert_eq!(header.title_id, 0x0100_0000_0000_1234);
+        assert_eq!(header.sdk_version, 0x000C_1100);
+        assert_eq!(header.key_generation, 3);
+        assert!(!header.has_rights_id());
+        assert_eq!(nca.key_area()[2], [0x5A; 0x10]);
+        let section = header.sections[0].as_ref().unwrap();
+        assert_eq!(section.fs_header.fs_type, NcaFsType::Pfs0);
+        assert!(header.sections[1].is_none());
+
+        nca.verify_section(0).unwrap();
+        let mut files = nca
+            .open_pfs0(0)
+            .unwrap()
+            .files()
+            .map(|file| {
+                let mut file = file.unwrap();
+                let mut content = vec![];
+                file.read_to_end(&mut content).unwrap();
+                (String::from(file.file_name()), content)
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                (String::from("main"), main),
+                (String::from("main.npdm"), b"META".to_vec())
+            ]
+        );
+    }
+    #[test]
+    fn manual_nca_roundtrip() {
+        let keys = Keys::test_keys();
+        let mut romfs = RomFs::empty();
+        romfs.push_data(b"<html></html>".to_vec(), "index.html");
+        let mut builder = NcaBuilder::new_manual(0x0100_0000_0000_1234, romfs);
+        builder.key_generation = 3;
+        let mut data = vec![];
+        builder.write(&keys, &mut data).unwrap();
+
+        let nca = Nca::from_reader(Cursor::new(Rc::<[u8]>::from(data)), &keys).unwrap();
+        assert_eq!(nca.header().content_type, NcaContentType::Manual);
+        let section = nca.header().sections[0].as_ref().unwrap();
+        assert_eq!(section.fs_header.fs_type, NcaFsType::RomFs);
+
+        nca.verify_section(0).unwrap();
+        let romfs = nca.open_romfs(0).unwrap();
+        let file = romfs.find_file("index.html").unwrap().unwrap();
+        let mut content = vec![];
+        romfs
+            .open_file(&file)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"<html></html>");
+    }
+}
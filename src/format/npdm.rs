@@ -1,8 +1,15 @@
+use crate::error::Error;
 use crate::format::utils::HexOrNum;
+use crate::utils::align_up;
 use bit_field::BitField;
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Backtrace;
+use serde::de::{Deserializer, Error as _};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Write;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", content = "value")]
@@ -14,6 +21,9 @@ pub enum KernelCapability {
         highest_cpu_id: u8,
         lowest_cpu_id: u8,
     },
+    /// Either a list of syscall names from the built-in table, or a map of
+    /// syscall names to IDs.
+    #[serde(deserialize_with = "deserialize_syscalls")]
     Syscalls(HashMap<String, HexOrNum>),
     Map {
         address: HexOrNum,
@@ -32,7 +42,311 @@ pub enum KernelCapability {
     },
 }
 
+/// The Horizon syscalls, by ID.
+const SYSCALLS: &[(&str, u32)] = &[
+    ("svcSetHeapSize", 0x01),
+    ("svcSetMemoryPermission", 0x02),
+    ("svcSetMemoryAttribute", 0x03),
+    ("svcMapMemory", 0x04),
+    ("svcUnmapMemory", 0x05),
+    ("svcQueryMemory", 0x06),
+    ("svcExitProcess", 0x07),
+    ("svcCreateThread", 0x08),
+    ("svcStartThread", 0x09),
+    ("svcExitThread", 0x0A),
+    ("svcSleepThread", 0x0B),
+    ("svcGetThreadPriority", 0x0C),
+    ("svcSetThreadPriority", 0x0D),
+    ("svcGetThreadCoreMask", 0x0E),
+    ("svcSetThreadCoreMask", 0x0F),
+    ("svcGetCurrentProcessorNumber", 0x10),
+    ("svcSignalEvent", 0x11),
+    ("svcClearEvent", 0x12),
+    ("svcMapSharedMemory", 0x13),
+    ("svcUnmapSharedMemory", 0x14),
+    ("svcCreateTransferMemory", 0x15),
+    ("svcCloseHandle", 0x16),
+    ("svcResetSignal", 0x17),
+    ("svcWaitSynchronization", 0x18),
+    ("svcCancelSynchronization", 0x19),
+    ("svcArbitrateLock", 0x1A),
+    ("svcArbitrateUnlock", 0x1B),
+    ("svcWaitProcessWideKeyAtomic", 0x1C),
+    ("svcSignalProcessWideKey", 0x1D),
+    ("svcGetSystemTick", 0x1E),
+    ("svcConnectToNamedPort", 0x1F),
+    ("svcSendSyncRequestLight", 0x20),
+    ("svcSendSyncRequest", 0x21),
+    ("svcSendSyncRequestWithUserBuffer", 0x22),
+    ("svcSendAsyncRequestWithUserBuffer", 0x23),
+    ("svcGetProcessId", 0x24),
+    ("svcGetThreadId", 0x25),
+    ("svcBreak", 0x26),
+    ("svcOutputDebugString", 0x27),
+    ("svcReturnFromException", 0x28),
+    ("svcGetInfo", 0x29),
+    ("svcFlushEntireDataCache", 0x2A),
+    ("svcFlushDataCache", 0x2B),
+    ("svcMapPhysicalMemory", 0x2C),
+    ("svcUnmapPhysicalMemory", 0x2D),
+    ("svcGetDebugFutureThreadInfo", 0x2E),
+    ("svcGetLastThreadInfo", 0x2F),
+    ("svcGetResourceLimitLimitValue", 0x30),
+    ("svcGetResourceLimitCurrentValue", 0x31),
+    ("svcSetThreadActivity", 0x32),
+    ("svcGetThreadContext3", 0x33),
+    ("svcWaitForAddress", 0x34),
+    ("svcSignalToAddress", 0x35),
+    ("svcSynchronizePreemptionState", 0x36),
+    ("svcGetResourceLimitPeakValue", 0x37),
+    ("svcCreateIoPool", 0x39),
+    ("svcCreateIoRegion", 0x3A),
+    ("svcDumpInfo", 0x3C),
+    ("svcChangeKernelTraceState", 0x3D),
+    ("svcCreateSession", 0x40),
+    ("svcAcceptSession", 0x41),
+    ("svcReplyAndReceiveLight", 0x42),
+    ("svcReplyAndReceive", 0x43),
+    ("svcReplyAndReceiveWithUserBuffer", 0x44),
+    ("svcCreateEvent", 0x45),
+    ("svcMapIoRegion", 0x46),
+    ("svcUnmapIoRegion", 0x47),
+    ("svcMapPhysicalMemoryUnsafe", 0x48),
+    ("svcUnmapPhysicalMemoryUnsafe", 0x49),
+    ("svcSetUnsafeLimit", 0x4A),
+    ("svcCreateCodeMemory", 0x4B),
+    ("svcControlCodeMemory", 0x4C),
+    ("svcSleepSystem", 0x4D),
+    ("svcReadWriteRegister", 0x4E),
+    ("svcSetProcessActivity", 0x4F),
+    ("svcCreateSharedMemory", 0x50),
+    ("svcMapTransferMemory", 0x51),
+    ("svcUnmapTransferMemory", 0x52),
+    ("svcCreateInterruptEvent", 0x53),
+    ("svcQueryPhysicalAddress", 0x54),
+    ("svcQueryIoMapping", 0x55),
+    ("svcCreateDeviceAddressSpace", 0x56),
+    ("svcAttachDeviceAddressSpace", 0x57),
+    ("svcDetachDeviceAddressSpace", 0x58),
+    ("svcMapDeviceAddressSpaceByForce", 0x59),
+    ("svcMapDeviceAddressSpaceAligned", 0x5A),
+    ("svcMapDeviceAddressSpace", 0x5B),
+    ("svcUnmapDeviceAddressSpace", 0x5C),
+    ("svcInvalidateProcessDataCache", 0x5D),
+    ("svcStoreProcessDataCache", 0x5E),
+    ("svcFlushProcessDataCache", 0x5F),
+    ("svcDebugActiveProcess", 0x60),
+    ("svcBreakDebugProcess", 0x61),
+    ("svcTerminateDebugProcess", 0x62),
+    ("svcGetDebugEvent", 0x63),
+    ("svcContinueDebugEvent", 0x64),
+    ("svcGetProcessList", 0x65),
+    ("svcGetThreadList", 0x66),
+    ("svcGetDebugThreadContext", 0x67),
+    ("svcSetDebugThreadContext", 0x68),
+    ("svcQueryDebugProcessMemory", 0x69),
+    ("svcReadDebugProcessMemory", 0x6A),
+    ("svcWriteDebugProcessMemory", 0x6B),
+    ("svcSetHardwareBreakPoint", 0x6C),
+    ("svcGetDebugThreadParam", 0x6D),
+    ("svcGetSystemInfo", 0x6F),
+    ("svcCreatePort", 0x70),
+    ("svcManageNamedPort", 0x71),
+    ("svcConnectToPort", 0x72),
+    ("svcSetProcessMemoryPermission", 0x73),
+    ("svcMapProcessMemory", 0x74),
+    ("svcUnmapProcessMemory", 0x75),
+    ("svcQueryProcessMemory", 0x76),
+    ("svcMapProcessCodeMemory", 0x77),
+    ("svcUnmapProcessCodeMemory", 0x78),
+    ("svcCreateProcess", 0x79),
+    ("svcStartProcess", 0x7A),
+    ("svcTerminateProcess", 0x7B),
+    ("svcGetProcessInfo", 0x7C),
+    ("svcCreateResourceLimit", 0x7D),
+    ("svcSetResourceLimitLimitValue", 0x7E),
+    ("svcCallSecureMonitor", 0x7F),
+];
+
+/// Syscall masks are made of 6 descriptors of 24 syscalls each.
+const SYSCALL_COUNT: u64 = 6 * 24;
+
+/// Returns the ID of a syscall from its name, with or without the `svc`
+/// prefix.
+pub fn syscall_id(name: &str) -> Option<u32> {
+    SYSCALLS
+        .iter()
+        .find(|(svc_name, _)| *svc_name == name || svc_name[3..] == *name)
+        .map(|(_, id)| *id)
+}
+
+/// Returns the name of a syscall from its ID.
+pub fn syscall_name(id: u32) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|(_, svc_id)| *svc_id == id)
+        .map(|(name, _)| *name)
+}
+
+fn deserialize_syscalls<'de, D>(deserializer: D) -> Result<HashMap<String, HexOrNum>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Syscalls {
+        Names(Vec<String>),
+        Ids(HashMap<String, HexOrNum>),
+    }
+
+    let syscalls = match serde::Deserialize::deserialize(deserializer)? {
+        Syscalls::Names(names) => {
+            let mut syscalls = HashMap::new();
+            for name in names {
+                let id = syscall_id(&name)
+                    .ok_or_else(|| D::Error::custom(format!("unknown syscall {}", name)))?;
+                syscalls.insert(name, HexOrNum(u64::from(id)));
+            }
+            syscalls
+        }
+        Syscalls::Ids(syscalls) => {
+            for (name, id) in syscalls.iter() {
+                match syscall_id(name) {
+                    Some(known_id) if u64::from(known_id) != id.0 => {
+                        return Err(D::Error::custom(format!(
+                            "syscall {} has ID {:#04x}, not {:#04x}",
+                            name, known_id, id.0
+                        )));
+                    }
+                    _ => (),
+                }
+            }
+            syscalls
+        }
+    };
+
+    for (name, id) in syscalls.iter() {
+        if id.0 >= SYSCALL_COUNT {
+            return Err(D::Error::custom(format!(
+                "syscall {} has ID {:#x}, the highest supported ID is {:#x}",
+                name,
+                id.0,
+                SYSCALL_COUNT - 1
+            )));
+        }
+    }
+    Ok(syscalls)
+}
+
+fn check_bits(
+    value: u64,
+    bits: u32,
+    capability: &'static str,
+    field: &'static str,
+) -> Result<(), Error> {
+    if value >> bits != 0 {
+        return Err(Error::InvalidKernelCapability(
+            capability,
+            field,
+            match bits {
+                6 => "must be at most 63",
+                10 => "must fit in 10 bits",
+                17 => "must fit in 17 bits",
+                24 => "must fit in 24 bits",
+                _ => "is too big",
+            },
+            Backtrace::new(),
+        ));
+    }
+    Ok(())
+}
+
 impl KernelCapability {
+    /// Checks that every field fits in its descriptor.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            KernelCapability::KernelFlags {
+                highest_thread_priority,
+                lowest_thread_priority,
+                highest_cpu_id,
+                lowest_cpu_id,
+            } => {
+                check_bits(
+                    u64::from(*highest_thread_priority),
+                    6,
+                    "kernel_flags",
+                    "highest_thread_priority",
+                )?;
+                check_bits(
+                    u64::from(*lowest_thread_priority),
+                    6,
+                    "kernel_flags",
+                    "lowest_thread_priority",
+                )?;
+                if lowest_cpu_id > highest_cpu_id {
+                    return Err(Error::InvalidKernelCapability(
+                        "kernel_flags",
+                        "lowest_cpu_id",
+                        "must not be above highest_cpu_id",
+                        Backtrace::new(),
+                    ));
+                }
+            }
+            KernelCapability::Syscalls(syscalls) => {
+                if syscalls.values().any(|id| id.0 >= SYSCALL_COUNT) {
+                    return Err(Error::InvalidKernelCapability(
+                        "syscalls",
+                        "value",
+                        "must only contain IDs below 0x90",
+                        Backtrace::new(),
+                    ));
+                }
+            }
+            KernelCapability::Map { address, size, .. } => {
+                check_bits(address.0, 24, "map", "address")?;
+                check_bits(size.0, 24, "map", "size")?;
+            }
+            KernelCapability::MapPage(page) => check_bits(page.0, 24, "map_page", "value")?,
+            KernelCapability::IrqPair(irq_pair) => {
+                check_bits(u64::from(irq_pair[0]), 10, "irq_pair", "value")?;
+                check_bits(u64::from(irq_pair[1]), 10, "irq_pair", "value")?;
+            }
+            KernelCapability::ApplicationType(app_type) => {
+                if *app_type > 7 {
+                    return Err(Error::InvalidKernelCapability(
+                        "application_type",
+                        "value",
+                        "must be at most 7",
+                        Backtrace::new(),
+                    ));
+                }
+            }
+            KernelCapability::MinKernelVersion(min_kernel) => {
+                check_bits(min_kernel.0, 17, "min_kernel_version", "value")?
+            }
+            KernelCapability::HandleTableSize(handle_table_size) => check_bits(
+                u64::from(*handle_table_size),
+                10,
+                "handle_table_size",
+                "value",
+            )?,
+            KernelCapability::DebugFlags { .. } => (),
+        }
+        Ok(())
+    }
+
+    /// Validates and encodes a list of kernel capabilities into descriptors.
+    pub fn encode_all(caps: &[KernelCapability]) -> Result<Vec<u32>, Error> {
+        let mut descriptors = vec![];
+        for cap in caps {
+            cap.validate()?;
+            descriptors.extend(cap.encode());
+        }
+        Ok(descriptors)
+    }
+
+    /// Encodes this capability into descriptors. The capability must have
+    /// been validated with `validate` first.
     pub fn encode(&self) -> Vec<u32> {
         match self {
             KernelCapability::KernelFlags {
@@ -102,3 +416,438 @@ impl KernelCapability {
         }
     }
 }
+
+impl KernelCapability {
+    /// Decodes a stream of kernel capability descriptors, as found in KIP1
+    /// and NPDM files. Syscall masks are merged into a single `Syscalls`
+    /// entry, in place of the first one. Padding (0xFFFFFFFF) is skipped, and
+    /// unknown descriptors are skipped with a warning.
+    pub fn decode(descriptors: &[u32]) -> Result<Vec<KernelCapability>, Error> {
+        let mut caps = vec![];
+        let mut syscalls_idx = None;
+        let mut syscalls = HashMap::new();
+        let mut descriptors = descriptors.iter();
+        while let Some(&descriptor) = descriptors.next() {
+            // The descriptor type is the number of low bits set.
+            let cap = match (!descriptor).trailing_zeros() {
+                3 => KernelCapability::KernelFlags {
+                    lowest_thread_priority: descriptor.get_bits(4..10) as u8,
+                    highest_thread_priority: descriptor.get_bits(10..16) as u8,
+                    lowest_cpu_id: descriptor.get_bits(16..24) as u8,
+                    highest_cpu_id: descriptor.get_bits(24..32) as u8,
+                },
+                4 => {
+                    let index = descriptor.get_bits(29..32);
+                    for bit in 0..24 {
+                        if descriptor.get_bit(bit + 5) {
+                            let id = index * 24 + bit as u32;
+                            let name = syscall_name(id)
+                                .map(String::from)
+                                .unwrap_or_else(|| format!("syscall_{:#04x}", id));
+                            syscalls.insert(name, HexOrNum(u64::from(id)));
+                        }
+                    }
+                    if syscalls_idx.is_none() {
+                        syscalls_idx = Some(caps.len());
+                    }
+                    continue;
+                }
+                6 => {
+                    let size = match descriptors.next() {
+                        Some(size) if (!size).trailing_zeros() == 6 => *size,
+                        _ => {
+                            return Err(Error::InvalidNpdm(
+                                "Map capability is missing its size descriptor",
+                                Backtrace::new(),
+                            ))
+                        }
+                    };
+                    KernelCapability::Map {
+                        address: HexOrNum(u64::from(descriptor.get_bits(7..31))),
+                        size: HexOrNum(u64::from(size.get_bits(7..31))),
+                        is_ro: descriptor.get_bit(31),
+                        is_io: size.get_bit(31),
+                    }
+                }
+                7 => KernelCapability::MapPage(HexOrNum(u64::from(descriptor.get_bits(8..32)))),
+                11 => KernelCapability::IrqPair([
+                    descriptor.get_bits(12..22) as u16,
+                    descriptor.get_bits(22..32) as u16,
+                ]),
+                13 => KernelCapability::ApplicationType(descriptor.get_bits(14..17) as u16),
+                14 => KernelCapability::MinKernelVersion(HexOrNum(u64::from(
+                    descriptor.get_bits(15..32),
+                ))),
+                15 => KernelCapability::HandleTableSize(descriptor.get_bits(16..26) as u16),
+                16 => KernelCapability::DebugFlags {
+                    allow_debug: descriptor.get_bit(17),
+                    force_debug: descriptor.get_bit(18),
+                },
+                32 => continue,
+                _ => {
+                    println!(
+                        "Warning: Skipping unknown kernel capability {:#010x}.",
+                        descriptor
+                    );
+                    continue;
+                }
+            };
+            caps.push(cap);
+        }
+        if let Some(idx) = syscalls_idx {
+            caps.insert(idx, KernelCapability::Syscalls(syscalls));
+        }
+        Ok(caps)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FsAccessControl {
+    pub permissions: HexOrNum,
+}
+
+/// The JSON description of an NPDM, the process metadata of sysmodules and
+/// applications.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Npdm {
+    pub name: String,
+    pub title_id: HexOrNum,
+    /// Defaults to `title_id` when not set.
+    pub title_id_range_min: Option<HexOrNum>,
+    /// Defaults to `title_id` when not set.
+    pub title_id_range_max: Option<HexOrNum>,
+    pub main_thread_stack_size: HexOrNum,
+    pub main_thread_priority: u8,
+    pub default_cpu_id: u8,
+    pub version: Option<u32>,
+    pub product_code: Option<String>,
+    pub system_resource_size: Option<HexOrNum>,
+    pub signature_key_generation: Option<u32>,
+    pub is_64_bit: bool,
+    pub address_space_type: u8,
+    #[serde(default)]
+    pub optimize_memory_allocation: bool,
+    #[serde(default)]
+    pub is_retail: bool,
+    pub pool_partition: u8,
+    #[serde(default)]
+    pub filesystem_access: FsAccessControl,
+    #[serde(default)]
+    pub service_access: Vec<String>,
+    #[serde(default)]
+    pub service_host: Vec<String>,
+    pub kernel_capabilities: Vec<KernelCapability>,
+}
+
+fn write_padded_string<W: Write>(
+    output_writter: &mut W,
+    value: &str,
+    size: usize,
+    field: &'static str,
+) -> Result<(), Error> {
+    if value.len() > size {
+        return Err(Error::InvalidNpdm(field, Backtrace::new()));
+    }
+    output_writter.write_all(value.as_bytes())?;
+    output_writter.write_all(&vec![0; size - value.len()])?;
+    Ok(())
+}
+
+impl Npdm {
+    pub fn from_file(input: &str) -> Result<Npdm, Error> {
+        let file = File::open(input)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    fn service_access_control(&self) -> Result<Vec<u8>, Error> {
+        let mut sac = vec![];
+        let services = self
+            .service_host
+            .iter()
+            .map(|name| (name, true))
+            .chain(self.service_access.iter().map(|name| (name, false)));
+        for (name, is_host) in services {
+            if name.is_empty() || name.len() > 8 {
+                return Err(Error::InvalidNpdm(
+                    "service names must be between 1 and 8 characters",
+                    Backtrace::new(),
+                ));
+            }
+            // Control byte: the name length minus one, and the top bit is set
+            // for services hosted by the process.
+            sac.push((name.len() as u8 - 1) | if is_host { 0x80 } else { 0 });
+            sac.extend_from_slice(name.as_bytes());
+        }
+        Ok(sac)
+    }
+
+    fn kernel_access_control(&self) -> Result<Vec<u8>, Error> {
+        let mut kac = vec![];
+        for descriptor in KernelCapability::encode_all(&self.kernel_capabilities)? {
+            kac.write_u32::<LittleEndian>(descriptor)?;
+        }
+        Ok(kac)
+    }
+
+    pub fn write<W: Write>(&self, output_writter: &mut W) -> Result<(), Error> {
+        if self.address_space_type > 3 {
+            return Err(Error::InvalidNpdm(
+                "address_space_type must be between 0 and 3",
+                Backtrace::new(),
+            ));
+        }
+        if self.pool_partition > 3 {
+            return Err(Error::InvalidNpdm(
+                "pool_partition must be between 0 and 3",
+                Backtrace::new(),
+            ));
+        }
+        let main_thread_stack_size =
+            u32::try_from(self.main_thread_stack_size.0).map_err(|_| {
+                Error::InvalidNpdm(
+                    "main_thread_stack_size must fit in 32 bits",
+                    Backtrace::new(),
+                )
+            })?;
+        let system_resource_size =
+            u32::try_from(self.system_resource_size.as_ref().map_or(0, |v| v.0)).map_err(|_| {
+                Error::InvalidNpdm("system_resource_size must fit in 32 bits", Backtrace::new())
+            })?;
+
+        let sac = self.service_access_control()?;
+        let kac = self.kernel_access_control()?;
+
+        // ACID layout: header, FS access control, service access control and
+        // kernel access control, each aligned to 0x10.
+        let acid_fac_offset = 0x240u32;
+        let acid_fac_size = 0x2Cu32;
+        let acid_sac_offset = align_up(acid_fac_offset + acid_fac_size, 0x10);
+        let acid_kac_offset = align_up(acid_sac_offset + sac.len() as u32, 0x10);
+        let acid_size = align_up(acid_kac_offset + kac.len() as u32, 0x10);
+
+        // ACI0 layout: the same, with a smaller FS access header.
+        let aci0_fah_offset = 0x40u32;
+        let aci0_fah_size = 0x1Cu32;
+        let aci0_sac_offset = align_up(aci0_fah_offset + aci0_fah_size, 0x10);
+        let aci0_kac_offset = align_up(aci0_sac_offset + sac.len() as u32, 0x10);
+        let aci0_size = aci0_kac_offset + kac.len() as u32;
+
+        let acid_offset = 0x80u32;
+        let aci0_offset = acid_offset + acid_size;
+
+        // META
+        output_writter.write_all(b"META")?;
+        output_writter.write_u32::<LittleEndian>(self.signature_key_generation.unwrap_or(0))?;
+        output_writter.write_u32::<LittleEndian>(0)?; // Reserved
+        output_writter.write_u8(
+            *0u8.set_bit(0, self.is_64_bit)
+                .set_bits(1..4, self.address_space_type)
+                .set_bit(4, self.optimize_memory_allocation),
+        )?;
+        output_writter.write_u8(0)?; // Reserved
+        output_writter.write_u8(self.main_thread_priority)?;
+        output_writter.write_u8(self.default_cpu_id)?;
+        output_writter.write_u32::<LittleEndian>(0)?; // Reserved
+        output_writter.write_u32::<LittleEndian>(system_resource_size)?;
+        output_writter.write_u32::<LittleEndian>(self.version.unwrap_or(0))?;
+        output_writter.write_u32::<LittleEndian>(main_thread_stack_size)?;
+        write_padded_string(
+            output_writter,
+            &self.name,
+            0x10,
+            "name must be at most 16 bytes long",
+        )?;
+        write_padded_string(
+            output_writter,
+            self.product_code.as_ref().map_or("", |v| &**v),
+            0x10,
+            "product_code must be at most 16 bytes long",
+        )?;
+        output_writter.write_all(&[0; 0x30])?; // Reserved
+        output_writter.write_u32::<LittleEndian>(aci0_offset)?;
+        output_writter.write_u32::<LittleEndian>(aci0_size)?;
+        output_writter.write_u32::<LittleEndian>(acid_offset)?;
+        output_writter.write_u32::<LittleEndian>(acid_size)?;
+
+        // ACID. We can't sign it, so the signature and the NCA public key
+        // modulus are left empty.
+        output_writter.write_all(&[0; 0x200])?;
+        output_writter.write_all(b"ACID")?;
+        output_writter.write_u32::<LittleEndian>(acid_size - 0x100)?;
+        output_writter.write_u32::<LittleEndian>(0)?; // Reserved
+        output_writter.write_u32::<LittleEndian>(
+            *0u32
+                .set_bit(0, self.is_retail)
+                .set_bits(2..6, u32::from(self.pool_partition)),
+        )?;
+        output_writter.write_u64::<LittleEndian>(
+            self.title_id_range_min.as_ref().unwrap_or(&self.title_id).0,
+        )?;
+        output_writter.write_u64::<LittleEndian>(
+            self.title_id_range_max.as_ref().unwrap_or(&self.title_id).0,
+        )?;
+        output_writter.write_u32::<LittleEndian>(acid_fac_offset)?;
+        output_writter.write_u32::<LittleEndian>(acid_fac_size)?;
+        output_writter.write_u32::<LittleEndian>(acid_sac_offset)?;
+        output_writter.write_u32::<LittleEndian>(sac.len() as u32)?;
+        output_writter.write_u32::<LittleEndian>(acid_kac_offset)?;
+        output_writter.write_u32::<LittleEndian>(kac.len() as u32)?;
+        output_writter.write_u64::<LittleEndian>(0)?; // Reserved
+
+        // ACID FS access control
+        output_writter.write_u8(1)?; // Version
+        output_writter.write_u8(0)?; // Content owner ID count
+        output_writter.write_u8(0)?; // Save data owner ID count
+        output_writter.write_u8(0)?; // Padding
+        output_writter.write_u64::<LittleEndian>(self.filesystem_access.permissions.0)?;
+        output_writter.write_all(&[0; 0x20])?; // Content and save data owner ID ranges
+        output_writter.write_all(&vec![
+            0;
+            (acid_sac_offset - acid_fac_offset - acid_fac_size)
+                as usize
+        ])?;
+        output_writter.write_all(&sac)?;
+        output_writter.write_all(&vec![
+            0;
+            (acid_kac_offset - acid_sac_offset) as usize
+                - sac.len()
+        ])?;
+        output_writter.write_all(&kac)?;
+        output_writter.write_all(&vec![0; (acid_size - acid_kac_offset) as usize - kac.len()])?;
+
+        // ACI0
+        output_writter.write_all(b"ACI0")?;
+        output_writter.write_all(&[0; 0xC])?; // Reserved
+        output_writter.write_u64::<LittleEndian>(self.title_id.0)?;
+        output_writter.write_u64::<LittleEndian>(0)?; // Reserved
+        output_writter.write_u32::<LittleEndian>(aci0_fah_offset)?;
+        output_writter.write_u32::<LittleEndian>(aci0_fah_size)?;
+        output_writter.write_u32::<LittleEndian>(aci0_sac_offset)?;
+        output_writter.write_u32::<LittleEndian>(sac.len() as u32)?;
+        output_writter.write_u32::<LittleEndian>(aci0_kac_offset)?;
+        output_writter.write_u32::<LittleEndian>(kac.len() as u32)?;
+        output_writter.write_u64::<LittleEndian>(0)?; // Reserved
+
+        // ACI0 FS access header, without content or save data owner info.
+        output_writter.write_u8(1)?; // Version
+        output_writter.write_all(&[0; 3])?; // Padding
+        output_writter.write_u64::<LittleEndian>(self.filesystem_access.permissions.0)?;
+        output_writter.write_u32::<LittleEndian>(aci0_fah_size)?; // Content owner info offset
+        output_writter.write_u32::<LittleEndian>(0)?; // Content owner info size
+        output_writter.write_u32::<LittleEndian>(aci0_fah_size)?; // Save data owner info offset
+        output_writter.write_u32::<LittleEndian>(0)?; // Save data owner info size
+        output_writter.write_all(&vec![
+            0;
+            (aci0_sac_offset - aci0_fah_offset - aci0_fah_size)
+                as usize
+        ])?;
+        output_writter.write_all(&sac)?;
+        output_writter.write_all(&vec![
+            0;
+            (aci0_kac_offset - aci0_sac_offset) as usize
+                - sac.len()
+        ])?;
+        output_writter.write_all(&kac)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kernel_capabilities_roundtrip() {
+        let descriptors = [
+            0x0300_3FF7, // Kernel flags
+            0x0010_17FF, // Irq pair
+            0x02AC_003F, // Map
+            0x8000_103F, // Map size, with the I/O bit
+            0x1FFF_FFEF, // Syscalls 0x00 to 0x17
+            0x2000_01EF, // Syscalls 0x18 to 0x1B
+            0xFFFF_FFFF, // Padding
+            0x0000_FFFF, // Debug flags
+        ];
+        let caps = KernelCapability::decode(&descriptors).unwrap();
+        assert_eq!(caps.len(), 5);
+        match &caps[3] {
+            KernelCapability::Syscalls(syscalls) => assert_eq!(syscalls.len(), 0x1C),
+            cap => panic!("Expected syscalls, got {:?}", cap),
+        }
+
+        let mut encoded = caps.iter().flat_map(|cap| cap.encode()).collect::<Vec<_>>();
+        let mut expected = descriptors.to_vec();
+        encoded.sort();
+        expected.retain(|v| *v != 0xFFFF_FFFF);
+        expected.sort();
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn truncated_map_is_rejected() {
+        assert!(KernelCapability::decode(&[0x02AC_003F]).is_err());
+        assert!(KernelCapability::decode(&[0x02AC_003F, 0x0000_FFFF]).is_err());
+    }
+
+    #[test]
+    fn decoded_syscalls_are_named() {
+        // Syscalls 0x01, 0x0B and 0x21, and 0x80 which has no name.
+        let descriptors = [0x0001_004F, 0x2000_400F, 0xA000_200F];
+        match &KernelCapability::decode(&descriptors).unwrap()[0] {
+            KernelCapability::Syscalls(syscalls) => {
+                assert_eq!(syscalls.len(), 4);
+                assert_eq!(syscalls["svcSetHeapSize"].0, 0x01);
+                assert_eq!(syscalls["svcSleepThread"].0, 0x0B);
+                assert_eq!(syscalls["svcSendSyncRequest"].0, 0x21);
+                assert_eq!(syscalls["syscall_0x80"].0, 0x80);
+            }
+            cap => panic!("Expected syscalls, got {:?}", cap),
+        }
+    }
+
+    #[test]
+    fn syscalls_are_checked() {
+        let caps: Vec<KernelCapability> = serde_json::from_str(
+            r#"[{ "type": "syscalls", "value": ["svcSetHeapSize", "SendSyncRequest"] }]"#,
+        )
+        .unwrap();
+        match &caps[0] {
+            KernelCapability::Syscalls(syscalls) => {
+                assert_eq!(syscalls["svcSetHeapSize"].0, 0x01);
+                assert_eq!(syscalls["SendSyncRequest"].0, 0x21);
+            }
+            cap => panic!("Expected syscalls, got {:?}", cap),
+        }
+
+        for value in &[
+            r#"["svcSetHeapSise"]"#,
+            r#"{ "svcSetHeapSize": "0x02" }"#,
+            r#"{ "svcCustom": 144 }"#,
+        ] {
+            let json = format!(r#"[{{ "type": "syscalls", "value": {} }}]"#, value);
+            assert!(serde_json::from_str::<Vec<KernelCapability>>(&json).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_capabilities_are_rejected() {
+        let caps = [
+            KernelCapability::KernelFlags {
+                highest_thread_priority: 64,
+                lowest_thread_priority: 24,
+                highest_cpu_id: 3,
+                lowest_cpu_id: 0,
+            },
+            KernelCapability::Map {
+                address: HexOrNum(0x1_0000_0000),
+                size: HexOrNum(0x1000),
+                is_ro: false,
+                is_io: true,
+            },
+            KernelCapability::MinKernelVersion(HexOrNum(0x2_0000)),
+        ];
+        for cap in caps.iter() {
+            assert!(cap.validate().is_err());
+        }
+    }
+}
@@ -1,14 +1,23 @@
+use crate::error::Error;
+use crate::format::pfs0::ReadSeek;
 use crate::format::utils::HexOrNum;
 use crate::format::{nacp::NacpFile, npdm::KernelCapability, romfs::RomFs, utils};
+use crate::utils::{ReadRange, TryClone};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use elf;
-use elf::types::{Machine, ProgramHeader, SectionHeader, EM_AARCH64, EM_ARM, PT_LOAD, SHT_NOTE};
+use elf::types::{
+    Machine, ProgFlag, ProgType, ProgramHeader, SectionFlag, SectionHeader, SectionType,
+    ELFCLASS32, ELFCLASS64, ELFDATA2LSB, EM_AARCH64, EM_ARM, ET_DYN, EV_CURRENT, PF_R, PF_W, PF_X,
+    PT_DYNAMIC, PT_LOAD, SHF_ALLOC, SHF_EXECINSTR, SHF_NONE, SHF_WRITE, SHT_DYNAMIC, SHT_DYNSYM,
+    SHT_NOBITS, SHT_NOTE, SHT_NULL, SHT_PROGBITS, SHT_STRTAB,
+};
+use failure::Backtrace;
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std;
 use std::convert::{TryFrom, TryInto};
 use std::fs::File;
-use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 
@@ -39,6 +48,22 @@ pub struct KipNpdm {
     kernel_capabilities: Vec<KernelCapability>,
 }
 
+impl KipNpdm {
+    /// Rebuilds the NPDM JSON of a KIP1, to dump its metadata or rebuild it.
+    pub fn from_kip1(kip: &Kip1File) -> Result<KipNpdm, Error> {
+        Ok(KipNpdm {
+            name: kip.name.clone(),
+            title_id: HexOrNum(kip.title_id),
+            main_thread_stack_size: HexOrNum(u64::from(kip.main_thread_stack_size())),
+            main_thread_priority: kip.main_thread_priority,
+            default_cpu_id: kip.default_cpu_id,
+            process_category: kip.process_category as u8,
+            flags: Some(kip.flags),
+            kernel_capabilities: KernelCapability::decode(&kip.kernel_capabilities)?,
+        })
+    }
+}
+
 fn pad_segment(previous_segment_data: &mut Vec<u8>, offset: usize, segment: &ProgramHeader) {
     let segment_vaddr = segment.vaddr as usize;
     let segment_supposed_start = previous_segment_data.len() + offset;
@@ -417,64 +442,34 @@ impl NxoFile {
             return Ok(());
         }
 
-        // Aset handling
-        output_writter.write_all(b"ASET")?;
-        output_writter.write_u32::<LittleEndian>(0)?; // version
-
-        // Offset to the next available region.
-        let mut offset = 8 + 16 + 16 + 16;
-
-        let icon_len = if let Some(icon) = &icon {
+        let icon = match icon {
             // TODO: Check if icon is a 256x256 JPEG. Convert it if it isn't?
-            let icon_len = Path::new(icon).metadata()?.len();
-            output_writter.write_u64::<LittleEndian>(offset)?;
-            output_writter.write_u64::<LittleEndian>(icon_len)?;
-            icon_len
-        } else {
-            output_writter.write_u64::<LittleEndian>(0)?;
-            output_writter.write_u64::<LittleEndian>(0)?;
-            0
-        };
-
-        offset += icon_len;
-
-        let nacp_len = if let Some(nacp) = &nacp {
-            let nacp_len = nacp.len() as u64;
-            output_writter.write_u64::<LittleEndian>(offset)?;
-            output_writter.write_u64::<LittleEndian>(nacp_len)?;
-            nacp_len
-        } else {
-            output_writter.write_u64::<LittleEndian>(0)?;
-            output_writter.write_u64::<LittleEndian>(0)?;
-            0
-        };
-
-        offset += nacp_len;
-
-        if let Some(romfs) = &romfs {
-            output_writter.write_u64::<LittleEndian>(offset)?;
-            output_writter.write_u64::<LittleEndian>(romfs.len() as u64)?;
-        } else {
-            output_writter.write_u64::<LittleEndian>(0)?;
-            output_writter.write_u64::<LittleEndian>(0)?;
+            Some(icon) => Some((
+                Path::new(icon).metadata()?.len(),
+                Box::new(move |w: &mut dyn Write| io::copy(&mut File::open(icon)?, w))
+                    as AsetWriter,
+            )),
+            None => None,
         };
-
-        if let Some(icon) = icon {
-            assert_eq!(
-                io::copy(&mut File::open(icon)?, output_writter)?,
-                icon_len,
-                "Icon changed while building."
-            );
-        }
-
-        if let Some(mut nacp) = nacp {
-            nacp.write(output_writter)?;
-        }
-
-        if let Some(romfs) = romfs {
-            romfs.write(output_writter)?;
-        }
-        Ok(())
+        let nacp = nacp.map(|mut nacp| {
+            (
+                nacp.len() as u64,
+                Box::new(move |mut w: &mut dyn Write| {
+                    nacp.write(&mut w)?;
+                    Ok(nacp.len() as u64)
+                }) as AsetWriter,
+            )
+        });
+        let romfs = romfs.map(|romfs| {
+            (
+                romfs.len() as u64,
+                Box::new(move |w: &mut dyn Write| {
+                    romfs.write(w)?;
+                    Ok(romfs.len() as u64)
+                }) as AsetWriter,
+            )
+        });
+        write_aset(output_writter, 0, [icon, nacp, romfs])
     }
 
     pub fn write_nso<T>(&mut self, output_writter: &mut T) -> std::io::Result<()>
@@ -628,10 +623,18 @@ impl NxoFile {
         Ok(())
     }
 
-    pub fn write_kip1<T>(&mut self, output_writer: &mut T, npdm: &KipNpdm) -> std::io::Result<()>
+    pub fn write_kip1<T>(&mut self, output_writer: &mut T, npdm: &KipNpdm) -> Result<(), Error>
     where
         T: Write,
     {
+        let caps = KernelCapability::encode_all(&npdm.kernel_capabilities)?;
+        if caps.len() > 0x20 {
+            return Err(Error::InvalidKip1(
+                "kernel_capabilities should have at most 0x20 descriptors",
+                Backtrace::new(),
+            ));
+        }
+
         output_writer.write_all(b"KIP1")?;
         let mut name: Vec<u8> = npdm.name.clone().into();
         name.resize(12, 0);
@@ -702,17 +705,6 @@ impl NxoFile {
         }
 
         // Kernel caps:
-        let caps = npdm
-            .kernel_capabilities
-            .iter()
-            .map(|v| v.encode())
-            .flatten()
-            .collect::<Vec<u32>>();
-        assert!(
-            caps.len() < 0x20,
-            "kernel_capabilities should have less than 0x20 entries!"
-        );
-
         unsafe {
             // Safety: This is safe. I'm just casting a slice of u32 to a slice of u8
             // for fuck's sake.
@@ -753,3 +745,1139 @@ where
 
     Ok(())
 }
+
+/// An offset/size pair describing a region of a module.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NxoRegion {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl NxoRegion {
+    fn read<R: Read>(reader: &mut R) -> io::Result<NxoRegion> {
+        Ok(NxoRegion {
+            offset: reader.read_u32::<LittleEndian>()?,
+            size: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    fn write<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.offset)?;
+        writer.write_u32::<LittleEndian>(self.size)
+    }
+}
+
+/// The MOD0 header, embedded in the module image. rtld uses it to locate the
+/// dynamic section, the bss and the eh_frame_hdr. Every offset is relative to
+/// the start of the MOD0 header itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mod0Header {
+    /// Offset of the MOD0 header from the start of the module image.
+    pub offset: u32,
+    pub dynamic_offset: i32,
+    pub bss_start_offset: i32,
+    pub bss_end_offset: i32,
+    pub eh_frame_hdr_start_offset: i32,
+    pub eh_frame_hdr_end_offset: i32,
+    pub module_object_offset: i32,
+}
+
+impl Mod0Header {
+    /// Finds the MOD0 header of a module image laid out as it is in memory.
+    /// The second word of the image holds the MOD0 offset. Returns None if
+    /// the module has no MOD0.
+    pub fn from_image(image: &[u8]) -> Option<Mod0Header> {
+        let offset = u32::from_le_bytes(image.get(4..8)?.try_into().unwrap());
+        let header = image.get(offset as usize..offset as usize + 0x1C)?;
+        if &header[..4] != b"MOD0" {
+            return None;
+        }
+
+        let mut cursor = Cursor::new(&header[4..]);
+        Some(Mod0Header {
+            offset,
+            dynamic_offset: cursor.read_i32::<LittleEndian>().ok()?,
+            bss_start_offset: cursor.read_i32::<LittleEndian>().ok()?,
+            bss_end_offset: cursor.read_i32::<LittleEndian>().ok()?,
+            eh_frame_hdr_start_offset: cursor.read_i32::<LittleEndian>().ok()?,
+            eh_frame_hdr_end_offset: cursor.read_i32::<LittleEndian>().ok()?,
+            module_object_offset: cursor.read_i32::<LittleEndian>().ok()?,
+        })
+    }
+
+    /// Turns an offset relative to the MOD0 header into an offset from the
+    /// start of the module image.
+    pub fn resolve(&self, relative_offset: i32) -> u64 {
+        (i64::from(self.offset) + i64::from(relative_offset)) as u64
+    }
+}
+
+/// Lays out segments at their memory offset, in a zero-filled buffer.
+fn build_module_image(segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let size = segments
+        .iter()
+        .map(|(offset, data)| *offset as usize + data.len())
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![0; size];
+    for (offset, data) in segments {
+        image[*offset as usize..*offset as usize + data.len()].copy_from_slice(data);
+    }
+    image
+}
+
+/// The NRO0 header. It lives at offset 0x10 of the .text segment.
+#[derive(Debug, Clone)]
+pub struct NroHeader {
+    pub version: u32,
+    pub size: u32,
+    pub flags: u32,
+    pub text: NxoRegion,
+    pub rodata: NxoRegion,
+    pub data: NxoRegion,
+    pub bss_size: u32,
+    pub build_id: [u8; 0x20],
+    pub dso_handle_offset: u32,
+    pub api_info: NxoRegion,
+    pub dynstr: NxoRegion,
+    pub dynsym: NxoRegion,
+}
+
+impl NroHeader {
+    fn parse(raw: &[u8; 0x80]) -> Result<NroHeader, Error> {
+        if &raw[0x10..0x14] != b"NRO0" {
+            return Err(Error::InvalidNro("magic is wrong", Backtrace::new()));
+        }
+
+        let mut cursor = Cursor::new(&raw[0x14..]);
+        let version = cursor.read_u32::<LittleEndian>()?;
+        let size = cursor.read_u32::<LittleEndian>()?;
+        let flags = cursor.read_u32::<LittleEndian>()?;
+        let text = NxoRegion::read(&mut cursor)?;
+        let rodata = NxoRegion::read(&mut cursor)?;
+        let data = NxoRegion::read(&mut cursor)?;
+        let bss_size = cursor.read_u32::<LittleEndian>()?;
+        let _reserved = cursor.read_u32::<LittleEndian>()?;
+        let mut build_id = [0; 0x20];
+        cursor.read_exact(&mut build_id)?;
+        let dso_handle_offset = cursor.read_u32::<LittleEndian>()?;
+        let _reserved = cursor.read_u32::<LittleEndian>()?;
+        let api_info = NxoRegion::read(&mut cursor)?;
+        let dynstr = NxoRegion::read(&mut cursor)?;
+        let dynsym = NxoRegion::read(&mut cursor)?;
+
+        Ok(NroHeader {
+            version,
+            size,
+            flags,
+            text,
+            rodata,
+            data,
+            bss_size,
+            build_id,
+            dso_handle_offset,
+            api_info,
+            dynstr,
+            dynsym,
+        })
+    }
+
+    fn write<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        output_writter.write_all(b"NRO0")?;
+        output_writter.write_u32::<LittleEndian>(self.version)?;
+        output_writter.write_u32::<LittleEndian>(self.size)?;
+        output_writter.write_u32::<LittleEndian>(self.flags)?;
+        self.text.write(output_writter)?;
+        self.rodata.write(output_writter)?;
+        self.data.write(output_writter)?;
+        output_writter.write_u32::<LittleEndian>(self.bss_size)?;
+        output_writter.write_u32::<LittleEndian>(0)?;
+        output_writter.write_all(&self.build_id)?;
+        output_writter.write_u32::<LittleEndian>(self.dso_handle_offset)?;
+        output_writter.write_u32::<LittleEndian>(0)?;
+        self.api_info.write(output_writter)?;
+        self.dynstr.write(output_writter)?;
+        self.dynsym.write(output_writter)
+    }
+}
+
+/// Writes an asset of the ASET section, returning how many bytes it wrote.
+type AsetWriter<'a> = Box<dyn FnMut(&mut dyn Write) -> io::Result<u64> + 'a>;
+
+/// Writes an ASET section holding the icon, the NACP and the RomFS, each given
+/// as its size and a function writing it.
+fn write_aset<W: Write>(
+    output_writter: &mut W,
+    version: u32,
+    mut assets: [Option<(u64, AsetWriter)>; 3],
+) -> io::Result<()> {
+    output_writter.write_all(b"ASET")?;
+    output_writter.write_u32::<LittleEndian>(version)?;
+
+    // Offset to the next available region.
+    let mut offset = 8 + 16 + 16 + 16;
+    for asset in assets.iter() {
+        if let Some((len, _)) = asset {
+            output_writter.write_u64::<LittleEndian>(offset)?;
+            output_writter.write_u64::<LittleEndian>(*len)?;
+            offset += len;
+        } else {
+            output_writter.write_u64::<LittleEndian>(0)?;
+            output_writter.write_u64::<LittleEndian>(0)?;
+        }
+    }
+
+    for (name, asset) in ["Icon", "NACP", "RomFS"].iter().zip(assets.iter_mut()) {
+        if let Some((len, write)) = asset {
+            if write(output_writter)? != *len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} changed while writing.", name),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The ASET section homebrew loaders look for right after the NRO image.
+pub struct NroAssets {
+    pub version: u32,
+    pub icon: Option<Vec<u8>>,
+    pub nacp: Option<Vec<u8>>,
+    pub romfs: Option<Box<dyn ReadSeek>>,
+}
+
+impl NroAssets {
+    fn from_reader<R: Read + Seek + TryClone + 'static>(
+        f: &mut R,
+        aset_offset: u64,
+    ) -> Result<Option<NroAssets>, Error> {
+        f.seek(SeekFrom::Start(aset_offset))?;
+        let mut magic = [0; 4];
+        match f.read_exact(&mut magic) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        if &magic != b"ASET" {
+            return Ok(None);
+        }
+
+        let version = f.read_u32::<LittleEndian>()?;
+        let mut regions = [(0, 0); 3];
+        for region in regions.iter_mut() {
+            *region = (f.read_u64::<LittleEndian>()?, f.read_u64::<LittleEndian>()?);
+        }
+        let file_size = f.seek(SeekFrom::End(0))?;
+        for (offset, size) in regions.iter() {
+            let end = aset_offset
+                .checked_add(*offset)
+                .and_then(|start| start.checked_add(*size));
+            if end.map_or(true, |end| end > file_size) {
+                return Err(Error::InvalidNro(
+                    "asset is out of bounds",
+                    Backtrace::new(),
+                ));
+            }
+        }
+        let [(icon_offset, icon_size), (nacp_offset, nacp_size), (romfs_offset, romfs_size)] =
+            regions;
+
+        let mut read_asset = |offset: u64, size: u64| -> Result<Option<Vec<u8>>, Error> {
+            if size == 0 {
+                return Ok(None);
+            }
+            let mut data = vec![0; size as usize];
+            f.seek(SeekFrom::Start(aset_offset + offset))?;
+            f.read_exact(&mut data)?;
+            Ok(Some(data))
+        };
+        let icon = read_asset(icon_offset, icon_size)?;
+        let nacp = read_asset(nacp_offset, nacp_size)?;
+
+        let romfs = if romfs_size != 0 {
+            Some(Box::new(ReadRange::new(
+                f.try_clone()?,
+                aset_offset + romfs_offset,
+                romfs_size,
+            )) as Box<dyn ReadSeek>)
+        } else {
+            None
+        };
+
+        Ok(Some(NroAssets {
+            version,
+            icon,
+            nacp,
+            romfs,
+        }))
+    }
+
+    fn write<W: Write>(&mut self, output_writter: &mut W) -> io::Result<()> {
+        let icon = self.icon.as_ref().map(|icon| {
+            (
+                icon.len() as u64,
+                Box::new(move |w: &mut dyn Write| {
+                    w.write_all(icon)?;
+                    Ok(icon.len() as u64)
+                }) as AsetWriter,
+            )
+        });
+        let nacp = self.nacp.as_ref().map(|nacp| {
+            (
+                nacp.len() as u64,
+                Box::new(move |w: &mut dyn Write| {
+                    w.write_all(nacp)?;
+                    Ok(nacp.len() as u64)
+                }) as AsetWriter,
+            )
+        });
+        let romfs = match &mut self.romfs {
+            Some(romfs) => Some((
+                romfs.seek(SeekFrom::End(0))?,
+                Box::new(move |w: &mut dyn Write| {
+                    romfs.seek(SeekFrom::Start(0))?;
+                    io::copy(romfs, w)
+                }) as AsetWriter,
+            )),
+            None => None,
+        };
+        write_aset(output_writter, self.version, [icon, nacp, romfs])
+    }
+}
+
+/// A parsed NRO. The segments are kept exactly as they are in the file, so
+/// .text still starts with the branch, the MOD0 offset and the NRO0 header.
+pub struct NroFile {
+    pub header: NroHeader,
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub data: Vec<u8>,
+    pub mod0: Option<Mod0Header>,
+    pub assets: Option<NroAssets>,
+}
+
+impl NroFile {
+    pub fn from_reader<R: Read + Seek + TryClone + 'static>(mut f: R) -> Result<NroFile, Error> {
+        let mut raw_header = [0; 0x80];
+        f.read_exact(&mut raw_header)?;
+        let header = NroHeader::parse(&raw_header)?;
+
+        if header.text.offset != 0 || header.text.size < 0x80 {
+            return Err(Error::InvalidNro(
+                ".text doesn't contain the header",
+                Backtrace::new(),
+            ));
+        }
+
+        let mut read_segment = |region: NxoRegion| -> Result<Vec<u8>, Error> {
+            if u64::from(region.offset) + u64::from(region.size) > u64::from(header.size) {
+                return Err(Error::InvalidNro(
+                    "segment is out of bounds",
+                    Backtrace::new(),
+                ));
+            }
+            let mut data = vec![0; region.size as usize];
+            f.seek(SeekFrom::Start(u64::from(region.offset)))?;
+            f.read_exact(&mut data)?;
+            Ok(data)
+        };
+        let text = read_segment(header.text)?;
+        let rodata = read_segment(header.rodata)?;
+        let data = read_segment(header.data)?;
+
+        let mod0 = Mod0Header::from_image(&build_module_image(&[
+            (header.text.offset, &text),
+            (header.rodata.offset, &rodata),
+            (header.data.offset, &data),
+        ]));
+
+        let assets = NroAssets::from_reader(&mut f, u64::from(header.size))?;
+
+        Ok(NroFile {
+            header,
+            text,
+            rodata,
+            data,
+            mod0,
+            assets,
+        })
+    }
+
+    /// Returns the build id, which is the first 0x20 bytes of the GNU build-id
+    /// note or a SHA256 of the segments when the ELF didn't have one.
+    pub fn build_id(&self) -> &[u8; 0x20] {
+        &self.header.build_id
+    }
+
+    /// Rebuilds an ELF from the NRO segments. The NRO header and the ASET
+    /// section are not part of the ELF.
+    pub fn write_elf<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        let data_end = self.header.data.offset + self.header.data.size;
+        let bss_offset = self
+            .mod0
+            .map(|mod0| mod0.resolve(mod0.bss_start_offset) as u32)
+            .unwrap_or(data_end);
+        ElfModule {
+            machine: guess_machine(&self.text),
+            text: (self.header.text.offset, &self.text),
+            rodata: (self.header.rodata.offset, &self.rodata),
+            data: (self.header.data.offset, &self.data),
+            bss: (bss_offset, self.header.bss_size),
+            mod0: self.mod0,
+        }
+        .write(output_writter)
+    }
+
+    /// Writes the NRO back. The segment table and the total size are updated
+    /// from the current segments, so they may be modified before repacking.
+    pub fn write<W: Write>(&mut self, output_writter: &mut W) -> Result<(), Error> {
+        if self.text.len() < 0x80 {
+            return Err(Error::InvalidNro(
+                ".text doesn't contain the header",
+                Backtrace::new(),
+            ));
+        }
+
+        let text_size = self.text.len() as u32;
+        let rodata_size = self.rodata.len() as u32;
+        let data_size = self.data.len() as u32;
+        self.header.text = NxoRegion {
+            offset: 0,
+            size: text_size,
+        };
+        self.header.rodata = NxoRegion {
+            offset: text_size,
+            size: rodata_size,
+        };
+        self.header.data = NxoRegion {
+            offset: text_size + rodata_size,
+            size: data_size,
+        };
+        self.header.size = text_size + rodata_size + data_size;
+
+        output_writter.write_all(&self.text[..0x10])?;
+        self.header.write(output_writter)?;
+        output_writter.write_all(&self.text[0x80..])?;
+        output_writter.write_all(&self.rodata)?;
+        output_writter.write_all(&self.data)?;
+
+        if let Some(assets) = &mut self.assets {
+            assets.write(output_writter)?;
+        }
+        Ok(())
+    }
+}
+
+/// A segment header of an NSO. The size is the decompressed size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NsoSegmentHeader {
+    pub file_offset: u32,
+    pub memory_offset: u32,
+    pub size: u32,
+}
+
+/// A decompressed segment of an NSO.
+#[derive(Debug, Clone)]
+pub struct NsoSegment {
+    pub memory_offset: u32,
+    pub data: Vec<u8>,
+    pub hash: [u8; 0x20],
+}
+
+/// A parsed NSO, with every segment decompressed.
+#[derive(Debug, Clone)]
+pub struct NsoFile {
+    pub version: u32,
+    pub flags: u32,
+    pub text: NsoSegment,
+    pub rodata: NsoSegment,
+    pub data: NsoSegment,
+    pub module_name_offset: u32,
+    pub module_name_size: u32,
+    pub bss_size: u32,
+    pub module_id: [u8; 0x20],
+    pub api_info: NxoRegion,
+    pub dynstr: NxoRegion,
+    pub dynsym: NxoRegion,
+    pub mod0: Option<Mod0Header>,
+}
+
+fn read_nso_segment<R: Read + Seek>(
+    f: &mut R,
+    name: &'static str,
+    idx: usize,
+    flags: u32,
+    header: NsoSegmentHeader,
+    file_size: u32,
+    hash: [u8; 0x20],
+) -> Result<NsoSegment, Error> {
+    let is_compressed = flags & (1 << idx) != 0;
+    let check_hash = flags & (1 << (idx + 3)) != 0;
+
+    let mut raw = vec![
+        0;
+        if is_compressed {
+            file_size
+        } else {
+            header.size
+        } as usize
+    ];
+    f.seek(SeekFrom::Start(u64::from(header.file_offset)))?;
+    f.read_exact(&mut raw)?;
+
+    let data = if is_compressed {
+        lz4::block::decompress(&raw, Some(header.size as i32))?
+    } else {
+        raw
+    };
+    if data.len() != header.size as usize {
+        return Err(Error::InvalidNso(
+            "segment doesn't decompress to its declared size",
+            Backtrace::new(),
+        ));
+    }
+
+    if check_hash && utils::calculate_sha256(&data)?[..] != hash[..] {
+        return Err(Error::NsoHashMismatch(name, Backtrace::new()));
+    }
+
+    Ok(NsoSegment {
+        memory_offset: header.memory_offset,
+        data,
+        hash,
+    })
+}
+
+impl NsoFile {
+    pub fn from_reader<R: Read + Seek>(mut f: R) -> Result<NsoFile, Error> {
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != b"NSO0" {
+            return Err(Error::InvalidNso("magic is wrong", Backtrace::new()));
+        }
+        let version = f.read_u32::<LittleEndian>()?;
+        let _reserved = f.read_u32::<LittleEndian>()?;
+        let flags = f.read_u32::<LittleEndian>()?;
+
+        // Each segment header is followed by a field that differs per segment:
+        // the module name offset, the module name size and the bss size.
+        let mut headers = [NsoSegmentHeader::default(); 3];
+        let mut extra_fields = [0; 3];
+        for (header, extra_field) in headers.iter_mut().zip(extra_fields.iter_mut()) {
+            header.file_offset = f.read_u32::<LittleEndian>()?;
+            header.memory_offset = f.read_u32::<LittleEndian>()?;
+            header.size = f.read_u32::<LittleEndian>()?;
+            *extra_field = f.read_u32::<LittleEndian>()?;
+        }
+        let [module_name_offset, module_name_size, bss_size] = extra_fields;
+
+        let mut module_id = [0; 0x20];
+        f.read_exact(&mut module_id)?;
+
+        let mut file_sizes = [0; 3];
+        for file_size in file_sizes.iter_mut() {
+            *file_size = f.read_u32::<LittleEndian>()?;
+        }
+
+        // Reserved (0x1C)
+        f.read_exact(&mut [0; 0x1C])?;
+
+        let api_info = NxoRegion::read(&mut f)?;
+        let dynstr = NxoRegion::read(&mut f)?;
+        let dynsym = NxoRegion::read(&mut f)?;
+
+        let mut hashes = [[0; 0x20]; 3];
+        for hash in hashes.iter_mut() {
+            f.read_exact(hash)?;
+        }
+
+        let text = read_nso_segment(
+            &mut f,
+            "text",
+            0,
+            flags,
+            headers[0],
+            file_sizes[0],
+            hashes[0],
+        )?;
+        let rodata = read_nso_segment(
+            &mut f,
+            "rodata",
+            1,
+            flags,
+            headers[1],
+            file_sizes[1],
+            hashes[1],
+        )?;
+        let data = read_nso_segment(
+            &mut f,
+            "data",
+            2,
+            flags,
+            headers[2],
+            file_sizes[2],
+            hashes[2],
+        )?;
+
+        let mod0 = Mod0Header::from_image(&build_module_image(&[
+            (text.memory_offset, &text.data),
+            (rodata.memory_offset, &rodata.data),
+            (data.memory_offset, &data.data),
+        ]));
+
+        Ok(NsoFile {
+            version,
+            flags,
+            text,
+            rodata,
+            data,
+            module_name_offset,
+            module_name_size,
+            bss_size,
+            module_id,
+            api_info,
+            dynstr,
+            dynsym,
+            mod0,
+        })
+    }
+
+    /// Rebuilds an ELF from the NSO segments.
+    pub fn write_elf<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        let bss_offset = self.data.memory_offset + self.data.data.len() as u32;
+        ElfModule {
+            machine: guess_machine(&self.text.data),
+            text: (self.text.memory_offset, &self.text.data),
+            rodata: (self.rodata.memory_offset, &self.rodata.data),
+            data: (self.data.memory_offset, &self.data.data),
+            bss: (bss_offset, self.bss_size),
+            mod0: self.mod0,
+        }
+        .write(output_writter)
+    }
+}
+
+/// A segment header of a KIP1. The size is the decompressed size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Kip1SegmentHeader {
+    pub memory_offset: u32,
+    pub size: u32,
+    pub compressed_size: u32,
+    pub attribute: u32,
+}
+
+/// A decompressed segment of a KIP1.
+#[derive(Debug, Clone)]
+pub struct Kip1Segment {
+    pub memory_offset: u32,
+    pub data: Vec<u8>,
+}
+
+/// A parsed KIP1, with every segment decompressed.
+#[derive(Debug, Clone)]
+pub struct Kip1File {
+    pub name: String,
+    pub title_id: u64,
+    pub process_category: u32,
+    pub main_thread_priority: u8,
+    pub default_cpu_id: u8,
+    pub flags: u8,
+    /// The text, rodata, data, bss and the two unused segment headers.
+    pub segment_headers: [Kip1SegmentHeader; 6],
+    /// The raw kernel capability descriptors, including the 0xFFFFFFFF
+    /// padding.
+    pub kernel_capabilities: [u32; 0x20],
+    pub text: Kip1Segment,
+    pub rodata: Kip1Segment,
+    pub data: Kip1Segment,
+    pub mod0: Option<Mod0Header>,
+}
+
+impl Kip1File {
+    pub fn from_reader<R: Read>(mut f: R) -> Result<Kip1File, Error> {
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != b"KIP1" {
+            return Err(Error::InvalidKip1("magic is wrong", Backtrace::new()));
+        }
+
+        let mut name = [0; 12];
+        f.read_exact(&mut name)?;
+        let name_len = name.iter().position(|v| *v == 0).unwrap_or(12);
+        let name = String::from_utf8(name[..name_len].to_vec())?;
+
+        let title_id = f.read_u64::<LittleEndian>()?;
+        let process_category = f.read_u32::<LittleEndian>()?;
+        let main_thread_priority = f.read_u8()?;
+        let default_cpu_id = f.read_u8()?;
+        let _reserved = f.read_u8()?;
+        let flags = f.read_u8()?;
+
+        let mut segment_headers = [Kip1SegmentHeader::default(); 6];
+        for header in segment_headers.iter_mut() {
+            header.memory_offset = f.read_u32::<LittleEndian>()?;
+            header.size = f.read_u32::<LittleEndian>()?;
+            header.compressed_size = f.read_u32::<LittleEndian>()?;
+            header.attribute = f.read_u32::<LittleEndian>()?;
+        }
+
+        let mut kernel_capabilities = [0; 0x20];
+        f.read_u32_into::<LittleEndian>(&mut kernel_capabilities)?;
+
+        // The segments directly follow the header, in order.
+        let mut segments = Vec::with_capacity(3);
+        for (idx, header) in segment_headers[..3].iter().enumerate() {
+            let mut raw = vec![0; header.compressed_size as usize];
+            f.read_exact(&mut raw)?;
+            let data = if flags & (1 << idx) != 0 {
+                utils::decompress_blz(&raw)?
+            } else {
+                raw
+            };
+            if data.len() != header.size as usize {
+                return Err(Error::InvalidKip1(
+                    "segment doesn't decompress to its declared size",
+                    Backtrace::new(),
+                ));
+            }
+            segments.push(Kip1Segment {
+                memory_offset: header.memory_offset,
+                data,
+            });
+        }
+        let data = segments.pop().unwrap();
+        let rodata = segments.pop().unwrap();
+        let text = segments.pop().unwrap();
+
+        let mod0 = Mod0Header::from_image(&build_module_image(&[
+            (text.memory_offset, &text.data),
+            (rodata.memory_offset, &rodata.data),
+            (data.memory_offset, &data.data),
+        ]));
+
+        Ok(Kip1File {
+            name,
+            title_id,
+            process_category,
+            main_thread_priority,
+            default_cpu_id,
+            flags,
+            segment_headers,
+            kernel_capabilities,
+            text,
+            rodata,
+            data,
+            mod0,
+        })
+    }
+
+    /// The main thread stack size, stored in the attribute of the rodata
+    /// segment header.
+    pub fn main_thread_stack_size(&self) -> u32 {
+        self.segment_headers[1].attribute
+    }
+
+    pub fn bss_offset(&self) -> u32 {
+        self.segment_headers[3].memory_offset
+    }
+
+    pub fn bss_size(&self) -> u32 {
+        self.segment_headers[3].size
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.flags & (1 << 3) != 0
+    }
+
+    /// Rebuilds an ELF from the KIP1 segments.
+    pub fn write_elf<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        ElfModule {
+            machine: if self.is_64bit() { EM_AARCH64 } else { EM_ARM },
+            text: (self.text.memory_offset, &self.text.data),
+            rodata: (self.rodata.memory_offset, &self.rodata.data),
+            data: (self.data.memory_offset, &self.data.data),
+            bss: (self.bss_offset(), self.bss_size()),
+            mod0: self.mod0,
+        }
+        .write(output_writter)
+    }
+}
+
+const DT_NULL: u64 = 0;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_STRSZ: u64 = 10;
+
+/// Where the ELF segments start in the rebuilt file. The headers fit in the
+/// first page, and every segment is stored at this offset plus its address.
+const ELF_SEGMENTS_OFFSET: u64 = 0x1000;
+
+/// NRO and NSO don't store the architecture. A 32-bit module starts with an
+/// ARM unconditional branch (0xEAxxxxxx), which is never a valid AArch64
+/// branch.
+fn guess_machine(text: &[u8]) -> Machine {
+    if text.get(3) == Some(&0xEA) {
+        EM_ARM
+    } else {
+        EM_AARCH64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ElfRegion {
+    address: u64,
+    size: u64,
+}
+
+/// The .dynamic, .dynsym and .dynstr locations, found by walking the dynamic
+/// section pointed to by the MOD0.
+struct ElfDynamicInfo {
+    dynamic: ElfRegion,
+    dynstr: Option<ElfRegion>,
+    dynsym: Option<ElfRegion>,
+}
+
+impl ElfDynamicInfo {
+    fn parse(image: &[u8], mod0: &Mod0Header, is_64bit: bool) -> Option<ElfDynamicInfo> {
+        if mod0.dynamic_offset == 0 {
+            return None;
+        }
+        let dynamic_address = mod0.resolve(mod0.dynamic_offset);
+        let word_size = if is_64bit { 8 } else { 4 };
+        let read_word = |address: u64| -> Option<u64> {
+            let bytes = image.get(address as usize..address as usize + word_size)?;
+            Some(if is_64bit {
+                u64::from_le_bytes(bytes.try_into().unwrap())
+            } else {
+                u64::from(u32::from_le_bytes(bytes.try_into().unwrap()))
+            })
+        };
+
+        let (mut hash, mut strtab, mut strsz, mut symtab) = (None, None, None, None);
+        let mut entry_address = dynamic_address;
+        loop {
+            let tag = read_word(entry_address)?;
+            let value = read_word(entry_address + word_size as u64)?;
+            entry_address += 2 * word_size as u64;
+            match tag {
+                DT_NULL => break,
+                DT_HASH => hash = Some(value),
+                DT_STRTAB => strtab = Some(value),
+                DT_STRSZ => strsz = Some(value),
+                DT_SYMTAB => symtab = Some(value),
+                _ => (),
+            }
+        }
+
+        let dynstr = match (strtab, strsz) {
+            (Some(address), Some(size)) => Some(ElfRegion { address, size }),
+            _ => None,
+        };
+
+        // The symbol count is the number of chains in the hash table. Without
+        // one, assume .dynstr directly follows .dynsym, as linkers do.
+        let symbol_size = if is_64bit { 0x18 } else { 0x10 };
+        let dynsym = symtab.and_then(|address| {
+            let size = hash
+                .and_then(|hash| image.get(hash as usize + 4..hash as usize + 8))
+                .map(|nchain| {
+                    u64::from(u32::from_le_bytes(nchain.try_into().unwrap())) * symbol_size
+                })
+                .or_else(|| strtab.filter(|v| *v > address).map(|v| v - address))?;
+            Some(ElfRegion { address, size })
+        });
+
+        Some(ElfDynamicInfo {
+            dynamic: ElfRegion {
+                address: dynamic_address,
+                size: entry_address - dynamic_address,
+            },
+            dynstr,
+            dynsym,
+        })
+    }
+}
+
+struct ElfProgramHeader {
+    kind: ProgType,
+    flags: ProgFlag,
+    region: ElfRegion,
+    file_size: u64,
+    align: u64,
+}
+
+struct ElfSection {
+    name: &'static str,
+    kind: SectionType,
+    flags: SectionFlag,
+    region: ElfRegion,
+    offset: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+/// The segments of an NRO, NSO or KIP1 at their memory offset, which is
+/// everything needed to rebuild an ELF.
+struct ElfModule<'a> {
+    machine: Machine,
+    text: (u32, &'a [u8]),
+    rodata: (u32, &'a [u8]),
+    data: (u32, &'a [u8]),
+    bss: (u32, u32),
+    mod0: Option<Mod0Header>,
+}
+
+fn write_elf_word<W: Write>(output_writter: &mut W, is_64bit: bool, value: u64) -> io::Result<()> {
+    if is_64bit {
+        output_writter.write_u64::<LittleEndian>(value)
+    } else {
+        output_writter.write_u32::<LittleEndian>(value as u32)
+    }
+}
+
+impl<'a> ElfModule<'a> {
+    fn write<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        let is_64bit = self.machine == EM_AARCH64;
+        let image = build_module_image(&[self.text, self.rodata, self.data]);
+        let dynamic = self
+            .mod0
+            .and_then(|mod0| ElfDynamicInfo::parse(&image, &mod0, is_64bit));
+
+        let segment_region = |(offset, data): (u32, &[u8])| ElfRegion {
+            address: u64::from(offset),
+            size: data.len() as u64,
+        };
+        let bss = ElfRegion {
+            address: u64::from(self.bss.0),
+            size: u64::from(self.bss.1),
+        };
+
+        // Program headers
+        let mut program_headers = vec![];
+        for (region, flags) in [
+            (segment_region(self.text), PF_R.0 | PF_X.0),
+            (segment_region(self.rodata), PF_R.0),
+            (segment_region(self.data), PF_R.0 | PF_W.0),
+        ]
+        .iter()
+        {
+            program_headers.push(ElfProgramHeader {
+                kind: PT_LOAD,
+                flags: ProgFlag(*flags),
+                region: *region,
+                file_size: region.size,
+                align: 0x1000,
+            });
+        }
+        if bss.size != 0 {
+            program_headers.push(ElfProgramHeader {
+                kind: PT_LOAD,
+                flags: ProgFlag(PF_R.0 | PF_W.0),
+                region: bss,
+                file_size: 0,
+                align: 0x1000,
+            });
+        }
+        if let Some(dynamic) = &dynamic {
+            program_headers.push(ElfProgramHeader {
+                kind: PT_DYNAMIC,
+                flags: ProgFlag(PF_R.0 | PF_W.0),
+                region: dynamic.dynamic,
+                file_size: dynamic.dynamic.size,
+                align: 8,
+            });
+        }
+
+        // Section headers
+        let alloc_section = |name, kind, flags, region: ElfRegion| ElfSection {
+            name,
+            kind,
+            flags,
+            region,
+            offset: ELF_SEGMENTS_OFFSET + region.address,
+            link: 0,
+            info: 0,
+            align: 0x10,
+            entry_size: 0,
+        };
+        let mut sections = vec![
+            ElfSection {
+                name: "",
+                kind: SHT_NULL,
+                flags: SHF_NONE,
+                region: ElfRegion {
+                    address: 0,
+                    size: 0,
+                },
+                offset: 0,
+                link: 0,
+                info: 0,
+                align: 0,
+                entry_size: 0,
+            },
+            alloc_section(
+                ".text",
+                SHT_PROGBITS,
+                SectionFlag(SHF_ALLOC.0 | SHF_EXECINSTR.0),
+                segment_region(self.text),
+            ),
+            alloc_section(
+                ".rodata",
+                SHT_PROGBITS,
+                SHF_ALLOC,
+                segment_region(self.rodata),
+            ),
+            alloc_section(
+                ".data",
+                SHT_PROGBITS,
+                SectionFlag(SHF_ALLOC.0 | SHF_WRITE.0),
+                segment_region(self.data),
+            ),
+        ];
+        if bss.size != 0 {
+            sections.push(alloc_section(
+                ".bss",
+                SHT_NOBITS,
+                SectionFlag(SHF_ALLOC.0 | SHF_WRITE.0),
+                bss,
+            ));
+        }
+        if let Some(dynamic) = &dynamic {
+            let mut dynstr_index = 0;
+            if let Some(dynstr) = dynamic.dynstr {
+                dynstr_index = sections.len() as u32;
+                let mut section = alloc_section(".dynstr", SHT_STRTAB, SHF_ALLOC, dynstr);
+                section.align = 1;
+                sections.push(section);
+            }
+            if let Some(dynsym) = dynamic.dynsym {
+                let mut section = alloc_section(".dynsym", SHT_DYNSYM, SHF_ALLOC, dynsym);
+                section.link = dynstr_index;
+                section.info = 1;
+                section.align = 8;
+                section.entry_size = if is_64bit { 0x18 } else { 0x10 };
+                sections.push(section);
+            }
+            let mut section = alloc_section(
+                ".dynamic",
+                SHT_DYNAMIC,
+                SectionFlag(SHF_ALLOC.0 | SHF_WRITE.0),
+                dynamic.dynamic,
+            );
+            section.link = dynstr_index;
+            section.align = 8;
+            section.entry_size = if is_64bit { 0x10 } else { 0x8 };
+            sections.push(section);
+        }
+
+        // The section names go right after the segments, followed by the
+        // section header table.
+        let shstrtab_offset = ELF_SEGMENTS_OFFSET + image.len() as u64;
+        let mut shstrtab = vec![0];
+        let mut name_offsets = vec![];
+        for section in sections.iter() {
+            if section.name.is_empty() {
+                name_offsets.push(0);
+            } else {
+                name_offsets.push(shstrtab.len() as u32);
+                shstrtab.extend_from_slice(section.name.as_bytes());
+                shstrtab.push(0);
+            }
+        }
+        name_offsets.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(b".shstrtab\0");
+        sections.push(ElfSection {
+            name: ".shstrtab",
+            kind: SHT_STRTAB,
+            flags: SHF_NONE,
+            region: ElfRegion {
+                address: 0,
+                size: shstrtab.len() as u64,
+            },
+            offset: shstrtab_offset,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        });
+        let section_headers_offset =
+            utils::align((shstrtab_offset as usize) + shstrtab.len(), 0x7) as u64;
+
+        let (header_size, program_header_size, section_header_size) = if is_64bit {
+            (0x40, 0x38, 0x40)
+        } else {
+            (0x34, 0x20, 0x28)
+        };
+
+        // ELF header
+        output_writter.write_all(b"\x7FELF")?;
+        output_writter.write_u8(if is_64bit { ELFCLASS64.0 } else { ELFCLASS32.0 })?;
+        output_writter.write_u8(ELFDATA2LSB.0)?;
+        output_writter.write_u8(EV_CURRENT.0 as u8)?;
+        output_writter.write_all(&[0; 9])?;
+        output_writter.write_u16::<LittleEndian>(ET_DYN.0)?;
+        output_writter.write_u16::<LittleEndian>(self.machine.0)?;
+        output_writter.write_u32::<LittleEndian>(EV_CURRENT.0)?;
+        write_elf_word(output_writter, is_64bit, u64::from(self.text.0))?;
+        write_elf_word(output_writter, is_64bit, header_size)?;
+        write_elf_word(output_writter, is_64bit, section_headers_offset)?;
+        // Flags: EABI version 5 on ARM
+        output_writter.write_u32::<LittleEndian>(if is_64bit { 0 } else { 0x0500_0000 })?;
+        output_writter.write_u16::<LittleEndian>(header_size as u16)?;
+        output_writter.write_u16::<LittleEndian>(program_header_size)?;
+        output_writter.write_u16::<LittleEndian>(program_headers.len() as u16)?;
+        output_writter.write_u16::<LittleEndian>(section_header_size)?;
+        output_writter.write_u16::<LittleEndian>(sections.len() as u16)?;
+        output_writter.write_u16::<LittleEndian>(sections.len() as u16 - 1)?;
+
+        for program_header in program_headers.iter() {
+            let offset = ELF_SEGMENTS_OFFSET + program_header.region.address;
+            output_writter.write_u32::<LittleEndian>(program_header.kind.0)?;
+            if is_64bit {
+                output_writter.write_u32::<LittleEndian>(program_header.flags.0)?;
+            }
+            write_elf_word(output_writter, is_64bit, offset)?;
+            write_elf_word(output_writter, is_64bit, program_header.region.address)?;
+            write_elf_word(output_writter, is_64bit, program_header.region.address)?;
+            write_elf_word(output_writter, is_64bit, program_header.file_size)?;
+            write_elf_word(output_writter, is_64bit, program_header.region.size)?;
+            if !is_64bit {
+                output_writter.write_u32::<LittleEndian>(program_header.flags.0)?;
+            }
+            write_elf_word(output_writter, is_64bit, program_header.align)?;
+        }
+
+        let headers_size =
+            header_size as usize + program_headers.len() * program_header_size as usize;
+        output_writter.write_all(&vec![0; ELF_SEGMENTS_OFFSET as usize - headers_size])?;
+
+        // Segments and section names
+        output_writter.write_all(&image)?;
+        output_writter.write_all(&shstrtab)?;
+        output_writter.write_all(&vec![
+            0;
+            section_headers_offset as usize
+                - (shstrtab_offset as usize + shstrtab.len())
+        ])?;
+
+        for (section, name_offset) in sections.iter().zip(name_offsets.iter()) {
+            output_writter.write_u32::<LittleEndian>(*name_offset)?;
+            output_writter.write_u32::<LittleEndian>(section.kind.0)?;
+            write_elf_word(output_writter, is_64bit, section.flags.0)?;
+            write_elf_word(output_writter, is_64bit, section.region.address)?;
+            write_elf_word(output_writter, is_64bit, section.offset)?;
+            write_elf_word(output_writter, is_64bit, section.region.size)?;
+            output_writter.write_u32::<LittleEndian>(section.link)?;
+            output_writter.write_u32::<LittleEndian>(section.info)?;
+            write_elf_word(output_writter, is_64bit, section.align)?;
+            write_elf_word(output_writter, is_64bit, section.entry_size)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Package1, holding the warmboot firmware, the NX bootloader and the secure
+//! monitor.
+
+use crate::error::Error;
+use crate::pki::Keys;
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Backtrace;
+use std::io::Read;
+
+/// Offsets of the PK11 header, before and after 6.2.0 moved it to make room
+/// for the TSEC firmware.
+const PK11_HEADER_OFFSETS: [usize; 2] = [0x3FE0, 0x6FE0];
+
+/// Size of the OEM header in front of the package1 of Mariko units, whose
+/// size field is at 0x154.
+const MARIKO_OEM_HEADER_SIZE: usize = 0x170;
+
+/// A decrypted package1, split into its three parts.
+#[derive(Debug, Clone)]
+pub struct Package1 {
+    build_date: String,
+    key_revision: Option<usize>,
+    warmboot: Vec<u8>,
+    nx_bootloader: Vec<u8>,
+    secure_monitor: Vec<u8>,
+}
+
+impl Package1 {
+    /// Decrypts the PK11 of a package1, trying every package1 key until one
+    /// yields a valid PK11 header. The package1 of Mariko units is first
+    /// unwrapped with the mariko_bek, and holds a plaintext PK11.
+    pub fn from_reader<R: Read>(mut f: R, keys: &Keys) -> Result<Package1, Error> {
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        if data.len() < 0x20 {
+            return Err(Error::InvalidPackage1(
+                "file is too small",
+                Backtrace::new(),
+            ));
+        }
+        if is_mariko(&data) {
+            data = unwrap_mariko(&data, keys)?;
+        }
+        let build_date = String::from_utf8(data[0x10..0x1E].to_vec())?;
+
+        let mut decrypted = None;
+        'search: for &offset in PK11_HEADER_OFFSETS.iter() {
+            if data.len() < offset + 0x40 {
+                continue;
+            }
+            let size = LittleEndian::read_u32(&data[offset..]) as usize;
+            if size < 0x20 || data.len() < offset + 0x20 + size {
+                continue;
+            }
+            if &data[offset + 0x20..offset + 0x24] == b"PK11" {
+                decrypted = Some((None, data[offset + 0x20..offset + 0x20 + size].to_vec()));
+                break 'search;
+            }
+            let mut ctr = [0; 0x10];
+            ctr.copy_from_slice(&data[offset + 0x10..offset + 0x20]);
+            for key_revision in 0..0x20 {
+                let key = match keys.package1_key(key_revision) {
+                    Ok(key) => key,
+                    Err(_) => continue,
+                };
+                let mut magic = [0; 0x10];
+                magic.copy_from_slice(&data[offset + 0x20..offset + 0x30]);
+                key.ctr_crypt(&mut magic, &ctr)?;
+                if &magic[..4] == b"PK11" {
+                    let mut pk11 = data[offset + 0x20..offset + 0x20 + size].to_vec();
+                    key.ctr_crypt(&mut pk11, &ctr)?;
+                    decrypted = Some((Some(key_revision), pk11));
+                    break 'search;
+                }
+            }
+        }
+        let (key_revision, pk11) = decrypted.ok_or_else(|| {
+            Error::MissingKey(
+                String::from("package1_key matching the package1"),
+                Backtrace::new(),
+            )
+        })?;
+
+        let warmboot_size = LittleEndian::read_u32(&pk11[0x4..]) as usize;
+        let nx_bootloader_size = LittleEndian::read_u32(&pk11[0x10..]) as usize;
+        let secure_monitor_size = LittleEndian::read_u32(&pk11[0x18..]) as usize;
+        if pk11.len() < 0x20 + warmboot_size + nx_bootloader_size + secure_monitor_size {
+            return Err(Error::InvalidPackage1(
+                "PK11 sections go past its end",
+                Backtrace::new(),
+            ));
+        }
+
+        // The order of the sections changed in 2.0.0 and 4.0.0, long before
+        // Mariko units shipped.
+        let order = if key_revision.map_or(true, |key_revision| key_revision >= 3) {
+            [
+                Section::NxBootloader,
+                Section::SecureMonitor,
+                Section::Warmboot,
+            ]
+        } else if build_date.starts_with("2016") {
+            [
+                Section::SecureMonitor,
+                Section::NxBootloader,
+                Section::Warmboot,
+            ]
+        } else {
+            [
+                Section::Warmboot,
+                Section::NxBootloader,
+                Section::SecureMonitor,
+            ]
+        };
+        let mut offset = 0x20;
+        let mut warmboot = Vec::new();
+        let mut nx_bootloader = Vec::new();
+        let mut secure_monitor = Vec::new();
+        for section in order.iter() {
+            let (out, size) = match section {
+                Section::Warmboot => (&mut warmboot, warmboot_size),
+                Section::NxBootloader => (&mut nx_bootloader, nx_bootloader_size),
+                Section::SecureMonitor => (&mut secure_monitor, secure_monitor_size),
+            };
+            out.extend_from_slice(&pk11[offset..offset + size]);
+            offset += size;
+        }
+
+        Ok(Package1 {
+            build_date,
+            key_revision,
+            warmboot,
+            nx_bootloader,
+            secure_monitor,
+        })
+    }
+
+    /// The build timestamp of the package1ldr, as YYYYMMDDHHMMSS.
+    pub fn build_date(&self) -> &str {
+        &self.build_date
+    }
+
+    /// The key revision of the package1 key that decrypted the PK11. This is
+    /// also the generation of the master_kek_source (or, before 6.2.0, of
+    /// the keyblob) its master key derives from. None for the package1 of
+    /// Mariko units, whose PK11 isn't encrypted.
+    pub fn key_revision(&self) -> Option<usize> {
+        self.key_revision
+    }
+
+    pub fn warmboot(&self) -> &[u8] {
+        &self.warmboot
+    }
+
+    pub fn nx_bootloader(&self) -> &[u8] {
+        &self.nx_bootloader
+    }
+
+    pub fn secure_monitor(&self) -> &[u8] {
+        &self.secure_monitor
+    }
+}
+
+/// Erista package1s start with a plaintext header holding their build date,
+/// where Mariko ones have the MAC and signature of their OEM header.
+fn is_mariko(data: &[u8]) -> bool {
+    if data.len() < MARIKO_OEM_HEADER_SIZE || data[0x10..0x1E].iter().all(u8::is_ascii_digit) {
+        return false;
+    }
+    let size = LittleEndian::read_u32(&data[0x154..]) as usize;
+    size != 0 && size % 0x10 == 0 && MARIKO_OEM_HEADER_SIZE + size <= data.len()
+}
+
+/// Decrypts the body following the OEM header with the mariko_bek. It is
+/// encrypted in CBC mode with a zero IV, its first block being random so that
+/// it acts as the IV of the rest.
+fn unwrap_mariko(data: &[u8], keys: &Keys) -> Result<Vec<u8>, Error> {
+    let size = LittleEndian::read_u32(&data[0x154..]) as usize;
+    let mut body = data[MARIKO_OEM_HEADER_SIZE..MARIKO_OEM_HEADER_SIZE + size].to_vec();
+    keys.mariko_bek()?.decrypt_cbc(&mut body, &[0; 0x10])?;
+    // The build date locates the package1 header, whether the random block
+    // is in front of it or part of it.
+    match [0, 0x10].iter().find(|&&start| {
+        body.len() >= start + 0x20
+            && body[start + 0x10..start + 0x1E]
+                .iter()
+                .all(u8::is_ascii_digit)
+    }) {
+        Some(&start) => Ok(body.split_off(start)),
+        None => Err(Error::InvalidPackage1(
+            "mariko_bek doesn't decrypt the package1",
+            Backtrace::new(),
+        )),
+    }
+}
+
+enum Section {
+    Warmboot,
+    NxBootloader,
+    SecureMonitor,
+}
@@ -0,0 +1,431 @@
+//! Package2, holding the kernel and the INI1 of the built-in sysmodules.
+
+use crate::error::Error;
+use crate::format::ini1::Ini1;
+use crate::pki::Keys;
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Backtrace;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+const PACKAGE2_HEADER_SIZE: usize = 0x200;
+
+/// Where the INI1 lives. Starting with 8.0.0, it is embedded in the kernel
+/// section instead of having its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ini1Location {
+    Section,
+    Kernel { offset: usize, size: usize },
+}
+
+/// A decrypted package2.
+#[derive(Debug, Clone)]
+pub struct Package2 {
+    signature: Vec<u8>,
+    /// The decrypted header, after the signature. The section sizes and
+    /// hashes are recomputed when writing.
+    header: Vec<u8>,
+    header_ctr: [u8; 0x10],
+    key_generation: usize,
+    signature_valid: Option<bool>,
+    kernel: Vec<u8>,
+    ini1_location: Ini1Location,
+    ini1: Ini1,
+}
+
+impl Package2 {
+    /// Decrypts a package2 with the package2 key of the generation encoded
+    /// in its header counter. The section hashes are checked, and so is the
+    /// RSA signature if the fixed key modulus is known.
+    pub fn from_reader<R: Read>(mut f: R, keys: &Keys) -> Result<Package2, Error> {
+        let mut raw_header = [0; PACKAGE2_HEADER_SIZE];
+        f.read_exact(&mut raw_header)?;
+        let mut header_ctr = [0; 0x10];
+        header_ctr.copy_from_slice(&raw_header[0x100..0x110]);
+
+        let key_generation = key_generation(&header_ctr);
+        let key = keys.package2_key(key_generation)?;
+        let mut header = raw_header[0x100..].to_vec();
+        key.ctr_crypt(&mut header, &header_ctr)?;
+        if &header[0x50..0x54] != b"PK21" {
+            return Err(Error::InvalidPackage2("magic is wrong", Backtrace::new()));
+        }
+
+        let signature = raw_header[..0x100].to_vec();
+        let signature_valid = keys
+            .package2_fixed_key_modulus()
+            .map(|modulus| modulus.verify_pss_sha256(&signature, &raw_header[0x100..]));
+
+        // Only the kernel and INI1 sections are used, and they directly
+        // follow the header.
+        if header[0x68..0x70].iter().any(|byte| *byte != 0) {
+            return Err(Error::InvalidPackage2(
+                "unexpected third section",
+                Backtrace::new(),
+            ));
+        }
+        let mut sections = Vec::with_capacity(2);
+        for idx in 0..2 {
+            let size = LittleEndian::read_u32(&header[0x60 + idx * 4..]) as usize;
+            let mut section = vec![0; size];
+            f.read_exact(&mut section)?;
+            key.ctr_crypt(&mut section, &section_ctr(&header, idx))?;
+            let hash = &header[0x80 + idx * 0x20..0xA0 + idx * 0x20];
+            if size != 0 && Sha256::digest(&section).as_slice() != hash {
+                return Err(Error::InvalidPackage2(
+                    "section hash mismatch",
+                    Backtrace::new(),
+                ));
+            }
+            sections.push(section);
+        }
+        let ini1 = sections.pop().unwrap();
+        let kernel = sections.pop().unwrap();
+
+        let (ini1_location, ini1) = if ini1.is_empty() {
+            let offset = find_ini1(&kernel).ok_or_else(|| {
+                Error::InvalidPackage2("no INI1 found in the kernel", Backtrace::new())
+            })?;
+            let size = LittleEndian::read_u32(&kernel[offset + 4..]) as usize;
+            if size < 0x10 {
+                return Err(Error::InvalidPackage2(
+                    "the INI1 in the kernel is smaller than its header",
+                    Backtrace::new(),
+                ));
+            }
+            let ini1 = Ini1::from_reader(&kernel[offset..offset + size])?;
+            (Ini1Location::Kernel { offset, size }, ini1)
+        } else {
+            (Ini1Location::Section, Ini1::from_reader(&ini1[..])?)
+        };
+
+        Ok(Package2 {
+            signature,
+            header,
+            header_ctr,
+            key_generation,
+            signature_valid,
+            kernel,
+            ini1_location,
+            ini1,
+        })
+    }
+
+    /// The master key generation of the package2 key used to decrypt it.
+    pub fn key_generation(&self) -> usize {
+        self.key_generation
+    }
+
+    /// Whether the RSA signature of the header is valid, or None if the
+    /// fixed key modulus isn't known.
+    pub fn signature_valid(&self) -> Option<bool> {
+        self.signature_valid
+    }
+
+    pub fn version(&self) -> (u8, u8) {
+        (self.header[0x5C], self.header[0x5D])
+    }
+
+    /// The kernel section. If the INI1 is embedded in it, it is included.
+    pub fn kernel(&self) -> &[u8] {
+        &self.kernel
+    }
+
+    pub fn ini1(&self) -> &Ini1 {
+        &self.ini1
+    }
+
+    /// The INI1, to replace its KIPs before writing the package2 back.
+    pub fn ini1_mut(&mut self) -> &mut Ini1 {
+        &mut self.ini1
+    }
+
+    /// Re-encrypts the package2 with the same key generation. The signature
+    /// is kept as is, so a modified package2 will only boot with signature
+    /// checks patched out.
+    pub fn write<W: Write>(&self, keys: &Keys, output_writter: &mut W) -> Result<(), Error> {
+        let key = keys.package2_key(self.key_generation)?;
+        let mut ini1 = Vec::with_capacity(self.ini1.size());
+        self.ini1.write(&mut ini1)?;
+        let mut kernel = self.kernel.clone();
+        let sections = match self.ini1_location {
+            Ini1Location::Section => [kernel, ini1],
+            Ini1Location::Kernel { offset, size } => {
+                // The kernel expects its bss right after the INI1, so it
+                // can't grow.
+                if ini1.len() > size {
+                    return Err(Error::InvalidPackage2(
+                        "the new INI1 doesn't fit in the kernel",
+                        Backtrace::new(),
+                    ));
+                }
+                kernel[offset..offset + ini1.len()].copy_from_slice(&ini1);
+                for byte in &mut kernel[offset + ini1.len()..offset + size] {
+                    *byte = 0;
+                }
+                [kernel, Vec::new()]
+            }
+        };
+
+        let mut header = self.header.clone();
+        for (idx, section) in sections.iter().enumerate() {
+            LittleEndian::write_u32(&mut header[0x60 + idx * 4..], section.len() as u32);
+            let hash = if section.is_empty() {
+                [0; 0x20]
+            } else {
+                let mut hash = [0; 0x20];
+                hash.copy_from_slice(Sha256::digest(section).as_slice());
+                hash
+            };
+            header[0x80 + idx * 0x20..0xA0 + idx * 0x20].copy_from_slice(&hash);
+        }
+        // The total size is encoded in the counter, as the xor of its first,
+        // third and fourth words.
+        let size = PACKAGE2_HEADER_SIZE + sections.iter().map(Vec::len).sum::<usize>();
+        let mut header_ctr = self.header_ctr;
+        let size_word = LittleEndian::read_u32(&header_ctr[0..])
+            ^ LittleEndian::read_u32(&header_ctr[8..])
+            ^ size as u32;
+        LittleEndian::write_u32(&mut header_ctr[0xC..], size_word);
+
+        key.ctr_crypt(&mut header, &header_ctr)?;
+        // The counter is stored in the clear, in place of its own
+        // encryption.
+        header[..0x10].copy_from_slice(&header_ctr);
+
+        output_writter.write_all(&self.signature)?;
+        output_writter.write_all(&header)?;
+        for (idx, section) in sections.iter().enumerate() {
+            let mut section = section.clone();
+            key.ctr_crypt(&mut section, &section_ctr(&self.header, idx))?;
+            output_writter.write_all(&section)?;
+        }
+        Ok(())
+    }
+}
+
+/// The master key generation of the package2 key, encoded in the second word
+/// of the header counter along with the header version.
+fn key_generation(header_ctr: &[u8; 0x10]) -> usize {
+    let word = LittleEndian::read_u32(&header_ctr[4..]);
+    let version = (word ^ (word >> 16) ^ (word >> 24)) & 0xFF;
+    version.saturating_sub(1) as usize
+}
+
+fn section_ctr(header: &[u8], idx: usize) -> [u8; 0x10] {
+    let mut ctr = [0; 0x10];
+    ctr.copy_from_slice(&header[0x10 + idx * 0x10..0x20 + idx * 0x10]);
+    ctr
+}
+
+/// Looks for an INI1 header in the kernel image, followed by a KIP1.
+fn find_ini1(kernel: &[u8]) -> Option<usize> {
+    (0..kernel.len().saturating_sub(0x14))
+        .step_by(4)
+        .find(|&offset| {
+            &kernel[offset..offset + 4] == b"INI1"
+                && &kernel[offset + 0x10..offset + 0x14] == b"KIP1"
+                && offset + LittleEndian::read_u32(&kernel[offset + 4..]) as usize <= kernel.len()
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The signature of the header of the package2 of
+    /// `package2_signature_is_checked`, by the test key whose modulus is in
+    /// `Keys::test_keys`.
+    const SIGNATURE: [u8; 0x100] = [
+        0x81, 0xBA, 0x33, 0x4B, 0x5A, 0x85, 0xE8, 0x3C, 0x27, 0x54, 0xB0, 0x5F, 0x34, 0xCF, 0xD3,
+        0xE7, 0x1E, 0x42, 0xB8, 0x3D, 0x5A, 0x3A, 0x39, 0xDB, 0xBA, 0xD9, 0xE1, 0x24, 0x01, 0xCF,
+        0xD3, 0xF5, 0x3C, 0x71, 0xFD, 0xD1, 0x8C, 0x0F, 0xF2, 0x87, 0xFF, 0xDC, 0x7A, 0xF6, 0xBF,
+        0xD4, 0x0B, 0x5C, 0x41, 0x43, 0x62, 0xAC, 0x0B, 0xD6, 0x44, 0xAA, 0xAD, 0x0F, 0xCB, 0x4B,
+        0x40, 0x10, 0x99, 0x9F, 0x45, 0x90, 0x9D, 0x70, 0x5F, 0xAC, 0x17, 0x61, 0xDF, 0x99, 0x81,
+        0x0A, 0xC6, 0x5C, 0x52, 0xE6, 0xE0, 0x12, 0xDE, 0xD0, 0xF0, 0x67, 0xC3, 0x8B, 0xCE, 0x89,
+        0x89, 0xB0, 0x79, 0x38, 0xCD, 0xE0, 0x98, 0x3C, 0x1C, 0x0B, 0x30, 0x4D, 0xE8, 0x3E, 0x7C,
+        0x77, 0x55, 0x26, 0x03, 0x11, 0xAE, 0xCF, 0x33, 0xB1, 0x10, 0x9D, 0x8C, 0xD7, 0x13, 0xA2,
+        0x14, 0x54, 0xEF, 0x97, 0x1E, 0x41, 0xAB, 0x0D, 0x92, 0x11, 0xA0, 0x90, 0xC5, 0x46, 0x3E,
+        0x0F, 0xF5, 0xAC, 0xC7, 0xF9, 0x5C, 0x07, 0x82, 0xB8, 0xCC, 0xAA, 0xC1, 0xE9, 0xFB, 0x9C,
+        0x9E, 0x81, 0x28, 0xFE, 0xC3, 0xB6, 0xCA, 0x7C, 0x05, 0xFF, 0x9A, 0x9F, 0x52, 0x05, 0xBA,
+        0x86, 0xFF, 0xF1, 0x58, 0x27, 0xA9, 0xA4, 0xD4, 0x78, 0x10, 0x66, 0xDC, 0x8A, 0x8E, 0x37,
+        0xA4, 0xE0, 0x40, 0xA1, 0xFC, 0x10, 0xCE, 0xCB, 0xBD, 0xA1, 0x8D, 0x9C, 0x98, 0xF0, 0x10,
+        0x05, 0xA5, 0x79, 0x88, 0x08, 0xEE, 0x49, 0xAB, 0x05, 0x4B, 0xB9, 0xD2, 0xFB, 0xC2, 0x8B,
+        0x32, 0xE6, 0x64, 0xB6, 0xAD, 0xD4, 0xD1, 0x43, 0x8A, 0xAB, 0xDC, 0x03, 0xD1, 0xEF, 0xC1,
+        0x3C, 0xAC, 0xA6, 0x33, 0xE2, 0xC0, 0xDB, 0x96, 0x55, 0xEC, 0x99, 0x02, 0xCB, 0xDB, 0x28,
+        0x25, 0xDE, 0x57, 0xB0, 0xCB, 0xEA, 0xE4, 0x8C, 0xBB, 0x78, 0x4D, 0xE4, 0x23, 0xBB, 0x85,
+        0x14,
+    ];
+
+    fn kip(name: &[u8], text_size: u32) -> Vec<u8> {
+        let mut kip = vec![0; 0x100 + text_size as usize];
+        kip[..4].copy_from_slice(b"KIP1");
+        kip[4..4 + name.len()].copy_from_slice(name);
+        LittleEndian::write_u32(&mut kip[0x28..], text_size);
+        kip
+    }
+
+    /// The total size encoded in the header counter of a package2.
+    fn encoded_size(package2: &[u8]) -> usize {
+        (LittleEndian::read_u32(&package2[0x100..])
+            ^ LittleEndian::read_u32(&package2[0x108..])
+            ^ LittleEndian::read_u32(&package2[0x10C..])) as usize
+    }
+
+    fn ini1(kips: &[Vec<u8>]) -> Vec<u8> {
+        let mut ini1 = Ini1::new();
+        for kip in kips {
+            ini1.push_kip(kip.clone()).unwrap();
+        }
+        let mut data = Vec::new();
+        ini1.write(&mut data).unwrap();
+        data
+    }
+
+    /// Builds an unsigned package2 encrypted with the package2 key of
+    /// generation 3. The INI1 section is empty if the INI1 is embedded in
+    /// the kernel.
+    fn package2(keys: &Keys, kernel: &[u8], ini1: &[u8]) -> Vec<u8> {
+        let sections = [kernel.to_vec(), ini1.to_vec()];
+        let size = PACKAGE2_HEADER_SIZE + kernel.len() + ini1.len();
+
+        let mut header = vec![0; 0x100];
+        // Header version 4, for key generation 3.
+        LittleEndian::write_u32(&mut header[0..], 0x1234_5678);
+        LittleEndian::write_u32(&mut header[4..], 4);
+        LittleEndian::write_u32(&mut header[8..], 0x9ABC_DEF0);
+        LittleEndian::write_u32(&mut header[0xC..], 0x1234_5678 ^ 0x9ABC_DEF0 ^ size as u32);
+        for (idx, byte) in header[0x10..0x50].iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        header[0x50..0x54].copy_from_slice(b"PK21");
+        header[0x5C] = 6;
+        header[0x5D] = 7;
+        for (idx, section) in sections.iter().enumerate() {
+            LittleEndian::write_u32(&mut header[0x60 + idx * 4..], section.len() as u32);
+            if !section.is_empty() {
+                header[0x80 + idx * 0x20..0xA0 + idx * 0x20]
+                    .copy_from_slice(Sha256::digest(section).as_slice());
+            }
+        }
+
+        let key = keys.package2_key(3).unwrap();
+        let mut header_ctr = [0; 0x10];
+        header_ctr.copy_from_slice(&header[..0x10]);
+        let mut data = vec![0; 0x100];
+        let mut encrypted_header = header.clone();
+        key.ctr_crypt(&mut encrypted_header, &header_ctr).unwrap();
+        encrypted_header[..0x10].copy_from_slice(&header_ctr);
+        data.extend_from_slice(&encrypted_header);
+        for (idx, section) in sections.iter().enumerate() {
+            let mut section = section.clone();
+            key.ctr_crypt(&mut section, &section_ctr(&header, idx))
+                .unwrap();
+            data.extend_from_slice(&section);
+        }
+        data
+    }
+
+    #[test]
+    fn package2_roundtrip() {
+        let keys = Keys::test_keys();
+        let kips = [kip(b"FS", 0x10), kip(b"Loader", 0x20)];
+        let kernel = vec![0x77; 0x140];
+        let data = package2(&keys, &kernel, &ini1(&kips));
+        assert_eq!(encoded_size(&data), data.len());
+
+        let mut package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.key_generation(), 3);
+        assert_eq!(package2.version(), (6, 7));
+        assert_eq!(package2.kernel(), &kernel[..]);
+        assert_eq!(package2.ini1().kips(), &kips[..]);
+
+        package2.ini1_mut().replace_kip(kip(b"FS", 0x50)).unwrap();
+        let mut data = Vec::new();
+        package2.write(&keys, &mut data).unwrap();
+        assert_eq!(encoded_size(&data), data.len());
+
+        let package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.key_generation(), 3);
+        assert_eq!(package2.kernel(), &kernel[..]);
+        assert_eq!(
+            package2.ini1().kips(),
+            &[kip(b"FS", 0x50), kip(b"Loader", 0x20)][..]
+        );
+    }
+
+    #[test]
+    fn package2_with_ini1_in_kernel() {
+        let keys = Keys::test_keys();
+        // A padded INI1, with room for 0x40 more bytes of KIPs.
+        let mut embedded_ini1 = ini1(&[kip(b"FS", 0x10), kip(b"Loader", 0x20)]);
+        let size = embedded_ini1.len() + 0x40;
+        embedded_ini1.resize(size, 0);
+        LittleEndian::write_u32(&mut embedded_ini1[4..], size as u32);
+        let mut kernel = vec![0x77; 0x100];
+        kernel.extend_from_slice(&embedded_ini1);
+        kernel.extend_from_slice(&[0x88; 0x20]);
+        let data = package2(&keys, &kernel, &[]);
+
+        assert_eq!(find_ini1(&kernel), Some(0x100));
+        let mut package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.kernel(), &kernel[..]);
+        assert_eq!(
+            package2.ini1().kips(),
+            &[kip(b"FS", 0x10), kip(b"Loader", 0x20)][..]
+        );
+
+        // The INI1 is rewritten in place, and padded to its old size.
+        package2.ini1_mut().replace_kip(kip(b"FS", 0x50)).unwrap();
+        let mut data = Vec::new();
+        package2.write(&keys, &mut data).unwrap();
+        assert_eq!(encoded_size(&data), data.len());
+        let mut package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        let mut expected_ini1 = ini1(&[kip(b"FS", 0x50), kip(b"Loader", 0x20)]);
+        expected_ini1.resize(size, 0);
+        assert_eq!(package2.kernel().len(), kernel.len());
+        assert_eq!(&package2.kernel()[0x100..0x100 + size], &expected_ini1[..]);
+        assert_eq!(&package2.kernel()[0x100 + size..], &[0x88; 0x20][..]);
+        assert_eq!(
+            package2.ini1().kips(),
+            &[kip(b"FS", 0x50), kip(b"Loader", 0x20)][..]
+        );
+
+        package2.ini1_mut().replace_kip(kip(b"FS", 0x60)).unwrap();
+        assert!(package2.write(&keys, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn package2_with_too_small_ini1_in_kernel() {
+        let keys = Keys::test_keys();
+        let mut kernel = vec![0x77; 0x100];
+        kernel.extend_from_slice(&ini1(&[kip(b"FS", 0x10)]));
+        LittleEndian::write_u32(&mut kernel[0x104..], 8);
+        let data = package2(&keys, &kernel, &[]);
+        assert!(Package2::from_reader(&data[..], &keys).is_err());
+    }
+
+    #[test]
+    fn package2_signature_is_checked() {
+        let keys = Keys::test_keys();
+        let kernel = vec![0x77; 0x140];
+        let mut data = package2(&keys, &kernel, &ini1(&[kip(b"FS", 0x10)]));
+        assert_eq!(
+            Package2::from_reader(&data[..], &keys)
+                .unwrap()
+                .signature_valid(),
+            Some(false)
+        );
+
+        data[..0x100].copy_from_slice(&SIGNATURE[..]);
+        let mut package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.signature_valid(), Some(true));
+
+        // The signature is kept when writing, so it no longer matches.
+        package2.ini1_mut().replace_kip(kip(b"FS", 0x20)).unwrap();
+        let mut data = Vec::new();
+        package2.write(&keys, &mut data).unwrap();
+        let package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.signature_valid(), Some(false));
+    }
+}
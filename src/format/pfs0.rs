@@ -1,5 +1,4 @@
 use crate::error::Error;
-use crate::format::utils;
 use crate::utils::{align_up, ReadRange, TryClone};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use failure::Backtrace;
@@ -12,7 +11,7 @@ pub trait ReadSeek: Read + Seek {}
 
 impl<T: Read + Seek> ReadSeek for T {}
 
-enum Pfs0Meta {
+pub(crate) enum Pfs0Meta {
     HostPath(PathBuf),
     SubFile {
         file: Box<dyn ReadSeek>,
@@ -22,12 +21,110 @@ enum Pfs0Meta {
 }
 
 impl Pfs0Meta {
-    fn file_name(&self) -> &str {
+    pub(crate) fn file_name(&self) -> &str {
         match self {
             Pfs0Meta::HostPath(path) => path.file_name().unwrap().to_str().unwrap(),
             Pfs0Meta::SubFile { ref name, .. } => name,
         }
     }
+
+    /// Opens the file, rewound to its start.
+    pub(crate) fn open(self) -> io::Result<Pfs0File> {
+        let name = self.file_name().into();
+        let file = match self {
+            Pfs0Meta::HostPath(path) => File::open(path).map(|v| Box::new(v) as Box<dyn ReadSeek>),
+            Pfs0Meta::SubFile { mut file, .. } => file.seek(SeekFrom::Start(0)).map(|_| file),
+        };
+        file.map(|file| Pfs0File { name, file })
+    }
+}
+
+/// Writes a PFS0-style partition: the header with the given magic, a file
+/// table of `entry_size` bytes per file, the string table and the file data.
+///
+/// Each file table entry starts with the data offset, the size and the name
+/// offset of the file. `entry_tail` returns the rest of it, given the contents
+/// and size of the file.
+pub(crate) fn write_partition<T, F>(
+    output_writter: &mut T,
+    magic: [u8; 4],
+    entry_size: u64,
+    files: &mut [&mut Pfs0Meta],
+    mut entry_tail: F,
+) -> io::Result<()>
+where
+    T: Write + Seek,
+    F: FnMut(&mut dyn ReadSeek, u64) -> io::Result<Vec<u8>>,
+{
+    let file_count = files.len() as u32;
+
+    // Header
+    output_writter.write_all(&magic)?;
+    output_writter.write_u32::<LittleEndian>(file_count)?;
+    let file_table_size = entry_size * files.len() as u64;
+    let string_table_pos: u64 = 0x10 + file_table_size;
+    // The Nintendo tools pad the string table so the data starts aligned
+    // to 0x20.
+    let names_size: u64 = files.iter().map(|x| x.file_name().len() as u64 + 1).sum();
+    let string_table_size = align_up(string_table_pos + names_size, 0x20) - string_table_pos;
+    output_writter.write_u32::<LittleEndian>(string_table_size as u32)?;
+    output_writter.write_u32::<LittleEndian>(0)?;
+
+    let data_pos: u64 = string_table_pos + string_table_size;
+
+    // Create empty tabes
+    output_writter.write_all(&vec![0; data_pos as usize - 0x10])?;
+
+    let mut string_offset = 0;
+    let mut data_offset = 0;
+
+    for (file_index, file) in files.iter_mut().enumerate() {
+        println!(
+            "Writing {}... [{}/{}]",
+            file.file_name(),
+            file_index + 1,
+            file_count
+        );
+
+        let mut host_file;
+
+        let (file, file_size, file_name) = match &mut **file {
+            Pfs0Meta::HostPath(path) => {
+                // Open the file and retrieve the size of it
+                host_file = File::open(&path)?;
+                let file_size = host_file.metadata()?.len();
+                let name = path.file_name().unwrap().to_str().unwrap();
+                (&mut host_file as &mut dyn ReadSeek, file_size, name)
+            }
+            Pfs0Meta::SubFile {
+                file, name, size, ..
+            } => (file as &mut dyn ReadSeek, *size, &**name),
+        };
+
+        // Seek and write file name to string table
+        output_writter.seek(SeekFrom::Start(string_table_pos + string_offset))?;
+        output_writter.write_all(file_name.as_bytes())?;
+        output_writter.write_all(b"\0")?;
+
+        // Write file entry to the file entry table
+        let tail = entry_tail(file, file_size)?;
+        output_writter.seek(SeekFrom::Start(0x10 + file_index as u64 * entry_size))?;
+        output_writter.write_u64::<LittleEndian>(data_offset)?;
+        output_writter.write_u64::<LittleEndian>(file_size)?;
+        output_writter.write_u32::<LittleEndian>(string_offset as u32)?;
+        output_writter.write_all(&tail)?;
+
+        // Write the actual file content
+        output_writter.seek(SeekFrom::Start(data_pos + data_offset))?;
+        file.seek(SeekFrom::Start(0))?;
+        let size = io::copy(file, output_writter)?;
+        assert_eq!(size, file_size);
+
+        data_offset += file_size;
+        string_offset += file_name.len() as u64 + 1;
+    }
+
+    Ok(())
 }
 
 pub struct Pfs0 {
@@ -35,6 +132,25 @@ pub struct Pfs0 {
 }
 
 impl Pfs0 {
+    pub fn empty() -> Self {
+        Pfs0 { files: Vec::new() }
+    }
+
+    /// Adds a file to the PFS0, read from the given stream when writing it.
+    pub fn push_file<R: ReadSeek + 'static>(
+        &mut self,
+        name: String,
+        mut file: R,
+    ) -> io::Result<()> {
+        let size = file.seek(SeekFrom::End(0))?;
+        self.files.push(Pfs0Meta::SubFile {
+            file: Box::new(file),
+            name,
+            size,
+        });
+        Ok(())
+    }
+
     pub fn from_directory(input: &str) -> std::io::Result<Self> {
         let path = PathBuf::from(input);
         let mut files = Vec::new();
@@ -95,82 +211,16 @@ impl Pfs0 {
     where
         T: Write + Seek,
     {
-        let files = &mut self.files;
-        files.sort_by_key(|v| v.file_name().to_string());
-        let file_count = files.len() as u32;
-
-        // Header
-        output_writter.write_all(b"PFS0")?;
-        output_writter.write_u32::<LittleEndian>(file_count)?;
-        let string_table_size =
-            utils::align(files.iter().map(|x| x.file_name().len() + 1).sum(), 0x1F);
-        output_writter.write_u32::<LittleEndian>(string_table_size as u32)?;
-        output_writter.write_u32::<LittleEndian>(0)?;
-
-        let file_table_size = 0x18 * files.len() as u64;
-        // The Nintendo tools align the string table to 0x20.
-        let string_table_pos: u64 = align_up(0x10 + file_table_size, 0x20);
-        let data_pos: u64 = string_table_pos + (string_table_size as u64);
-
-        // Create empty tabes
-        let mut empty_tables = Vec::new();
-        empty_tables.resize(data_pos as usize - 0x10, 0);
-        output_writter.write_all(&empty_tables)?;
-
-        let mut string_offset = 0;
-        let mut data_offset = 0;
-
-        for (file_index, file) in files
-            .iter_mut()
-            .enumerate()
-            .map(|(idx, path)| (idx as u64, path))
-        {
-            // Seek and write file name to string table
-            output_writter.seek(SeekFrom::Start(string_table_pos + string_offset))?;
-
-            println!(
-                "Writing {}... [{}/{}]",
-                file.file_name(),
-                file_index + 1,
-                file_count
-            );
-            output_writter.write_all(file.file_name().as_bytes())?;
-            output_writter.write_all(b"\0")?;
-
-            let mut host_file;
-
-            let (file, file_size, file_name) = match file {
-                Pfs0Meta::HostPath(path) => {
-                    // Open the file and retrieve the size of it
-                    host_file = File::open(&path)?;
-                    let file_size = host_file.metadata()?.len();
-                    let name = path.file_name().unwrap().to_str().unwrap();
-                    (&mut host_file as &mut dyn ReadSeek, file_size, name)
-                }
-                Pfs0Meta::SubFile {
-                    file, name, size, ..
-                } => (file as &mut dyn ReadSeek, *size, &**name),
-            };
-
-            // Write file entry to the file entry table
-            output_writter.seek(SeekFrom::Start(0x10 + (file_index * 0x18)))?;
-
-            output_writter.write_u64::<LittleEndian>(data_offset)?;
-            output_writter.write_u64::<LittleEndian>(file_size)?;
-            output_writter.write_u64::<LittleEndian>(string_offset)?;
-
-            // Write the actual file content
-            output_writter.seek(SeekFrom::Start(data_pos + data_offset))?;
-
-            file.seek(SeekFrom::Start(0))?;
-            let size = io::copy(file, output_writter)?;
-            assert_eq!(size, file_size);
-
-            data_offset += file_size;
-            string_offset += file_name.len() as u64 + 1;
-        }
+        self.files.sort_by_key(|v| v.file_name().to_string());
+        let mut files: Vec<&mut Pfs0Meta> = self.files.iter_mut().collect();
+        // The rest of a PFS0 entry is reserved.
+        write_partition(output_writter, *b"PFS0", 0x18, &mut files, |_, _| {
+            Ok(vec![0; 4])
+        })
+    }
 
-        Ok(())
+    pub fn file_names(&self) -> Vec<&str> {
+        self.files.iter().map(|file| file.file_name()).collect()
     }
 
     pub fn files(self) -> impl Iterator<Item = io::Result<Pfs0File>> + 'static {
@@ -209,17 +259,56 @@ impl Iterator for Pfs0FileIterator {
     type Item = io::Result<Pfs0File>;
 
     fn next(&mut self) -> Option<io::Result<Pfs0File>> {
-        if let Some(meta) = self.pfs0.files.pop() {
-            let name = meta.file_name().into();
-            let file = match meta {
-                Pfs0Meta::HostPath(path) => {
-                    File::open(path).map(|v| Box::new(v) as Box<dyn ReadSeek>)
-                }
-                Pfs0Meta::SubFile { mut file, .. } => file.seek(SeekFrom::Start(0)).map(|_| file),
-            };
-            Some(file.map(|file| Pfs0File { name, file }))
-        } else {
-            None
+        self.pfs0.files.pop().map(Pfs0Meta::open)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byteorder::ByteOrder;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    fn roundtrip(files: &[(&str, Vec<u8>)]) {
+        let mut pfs0 = Pfs0::empty();
+        for (name, data) in files {
+            pfs0.push_file((*name).to_string(), Cursor::new(data.clone()))
+                .unwrap();
+        }
+        let mut out = Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut out).unwrap();
+        let data = out.into_inner();
+
+        let string_table_size = LittleEndian::read_u32(&data[8..0xC]) as usize;
+        let data_offset = 0x10 + 0x18 * files.len() + string_table_size;
+        assert_eq!(data_offset % 0x20, 0);
+
+        let pfs0 = Pfs0::from_reader(Cursor::new(Rc::<[u8]>::from(data))).unwrap();
+        let names: Vec<&str> = files.iter().map(|(name, _)| *name).collect();
+        assert_eq!(pfs0.file_names(), names);
+        // files() hands them out last to first.
+        for (entry, file) in files.iter().rev().zip(pfs0.files()) {
+            let (name, expected) = entry;
+            let mut file = file.unwrap();
+            assert_eq!(file.file_name(), *name);
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(&contents, expected);
         }
     }
+
+    #[test]
+    fn pfs0_roundtrip_one_file() {
+        roundtrip(&[("main", vec![0x11; 0x123])]);
+    }
+
+    #[test]
+    fn pfs0_roundtrip_three_files() {
+        roundtrip(&[
+            ("main", vec![0x11; 0x123]),
+            ("main.npdm", vec![0x22; 0x45]),
+            ("rtld", vec![0x33; 0x1000]),
+        ]);
+    }
 }
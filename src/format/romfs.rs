@@ -1,9 +1,10 @@
 use crate::error::Error;
-use byteorder::{WriteBytesExt, LE};
+use crate::utils::{ReadRange, TryClone};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use failure::Backtrace;
 use std::cell::RefCell;
-use std::fs::{self, File};
-use std::io::{self, Cursor, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::rc::{Rc, Weak};
@@ -35,6 +36,9 @@ impl RomFsDirEntCtx {
 #[derive(Debug)]
 struct RomFsFileEntCtx {
     system_path: PathBuf,
+    // Set for files pushed with `RomFs::push_data`, read from system_path
+    // otherwise.
+    data: Option<Vec<u8>>,
     name: String,
     entry_offset: u32,
     offset: u64,
@@ -157,6 +161,8 @@ fn calc_path_hash(parent: u32, path: &str) -> u32 {
 // TODO: why 0x200???
 const ROMFS_FILEPARTITION_OFS: u64 = 0x200;
 
+const ROMFS_ENTRY_EMPTY: u32 = 0xFF_FF_FF_FF;
+
 /// A graph of directories, and various metadata associated to it.
 #[derive(Debug)]
 pub struct RomFs {
@@ -171,19 +177,47 @@ pub struct RomFs {
 impl RomFs {
     // Internal path
     pub fn push_file(&mut self, file_path: &Path, internal_path: &str) -> io::Result<()> {
+        let metadata = file_path.metadata()?;
+        self.push_entry(
+            PathBuf::from(file_path),
+            None,
+            metadata.len(),
+            internal_path,
+        );
+        Ok(())
+    }
+
+    /// Adds a file from memory, at the given internal path.
+    pub fn push_data(&mut self, data: Vec<u8>, internal_path: &str) {
+        let size = data.len() as u64;
+        self.push_entry(
+            PathBuf::from(internal_path),
+            Some(data),
+            size,
+            internal_path,
+        );
+    }
+
+    fn push_entry(
+        &mut self,
+        system_path: PathBuf,
+        mut data: Option<Vec<u8>>,
+        size: u64,
+        internal_path: &str,
+    ) {
         let mut parent = self.dirs[0].clone();
 
         let mut components = internal_path.split('/').peekable();
         while let Some(component) = components.next() {
             if components.peek().is_none() {
-                let metadata = file_path.metadata()?;
                 // Handling last component. Add the file.
                 let file_to_add = Rc::new(RefCell::new(RomFsFileEntCtx {
-                    system_path: PathBuf::from(file_path),
+                    system_path: system_path.clone(),
+                    data: data.take(),
                     name: String::from(component),
                     entry_offset: 0,
                     offset: 0,
-                    size: metadata.len(),
+                    size,
                     parent: Rc::downgrade(&parent),
                 }));
                 self.files.push(file_to_add.clone());
@@ -235,7 +269,6 @@ impl RomFs {
         self.files.sort_by_key(|v| v.borrow().internal_path());
         self.dirs.sort_by_key(|v| v.borrow().internal_path());
         self.calculate_offsets();
-        Ok(())
     }
 
     pub fn empty() -> RomFs {
@@ -299,6 +332,7 @@ impl RomFs {
                 } else if file_type.is_file() {
                     let file = Rc::new(RefCell::new(RomFsFileEntCtx {
                         system_path: entry.path(),
+                        data: None,
                         name: entry
                             .path()
                             .file_name()
@@ -380,8 +414,6 @@ impl RomFs {
     }
 
     pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
-        const ROMFS_ENTRY_EMPTY: u32 = 0xFF_FF_FF_FF;
-
         let mut dir_hash_table =
             vec![ROMFS_ENTRY_EMPTY; romfs_get_hash_table_count(self.dirs.len())];
         let mut file_hash_table =
@@ -490,7 +522,13 @@ impl RomFs {
             );
             assert_eq!(file.borrow().offset, cur_ofs - 0x200, "Wrong offset");
 
-            let len = io::copy(&mut File::open(&file.borrow().system_path)?, to)?;
+            let len = match &file.borrow().data {
+                Some(data) => {
+                    to.write_all(data)?;
+                    data.len() as u64
+                }
+                None => io::copy(&mut File::open(&file.borrow().system_path)?, to)?,
+            };
             assert_eq!(len, file.borrow().size, "File changed while building romfs");
             cur_ofs += file.borrow().size;
         }
@@ -516,3 +554,300 @@ impl RomFs {
         Ok(())
     }
 }
+
+/// A file stored in a RomFS image.
+#[derive(Debug, Clone)]
+pub struct RomFsFileEntry {
+    /// Path of the file inside the RomFS, with `/` as separator and without
+    /// a leading `/`.
+    pub path: String,
+    /// Offset of the file data, relative to the file partition.
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+struct RomFsDirEntry {
+    sibling: u32,
+    child: u32,
+    file: u32,
+    name: String,
+}
+
+/// A RomFS image read from a stream, as produced by `RomFs::write`.
+pub struct RomFsReader<R> {
+    file: R,
+    file_partition_offset: u64,
+    dir_hash_table: Vec<u32>,
+    dir_table: Vec<u8>,
+    file_hash_table: Vec<u32>,
+    file_table: Vec<u8>,
+}
+
+impl<R: Read + Seek + TryClone> RomFsReader<R> {
+    pub fn from_reader(mut f: R) -> Result<RomFsReader<R>, Error> {
+        f.seek(SeekFrom::Start(0))?;
+        let header_size = f.read_u64::<LE>()?;
+        if header_size != 80 {
+            return Err(Error::InvalidRomFs(
+                "header size is wrong",
+                Backtrace::new(),
+            ));
+        }
+        let dir_hash_table_ofs = f.read_u64::<LE>()?;
+        let dir_hash_table_size = f.read_u64::<LE>()?;
+        let dir_table_ofs = f.read_u64::<LE>()?;
+        let dir_table_size = f.read_u64::<LE>()?;
+        let file_hash_table_ofs = f.read_u64::<LE>()?;
+        let file_hash_table_size = f.read_u64::<LE>()?;
+        let file_table_ofs = f.read_u64::<LE>()?;
+        let file_table_size = f.read_u64::<LE>()?;
+        let file_partition_offset = f.read_u64::<LE>()?;
+
+        let mut read_table = |offset: u64, size: u64| -> Result<Vec<u8>, Error> {
+            // Tables are loaded in memory, guard against absurd sizes.
+            if size > 0x1000_0000 {
+                return Err(Error::InvalidRomFs("table is too big", Backtrace::new()));
+            }
+            let mut table = vec![0; size as usize];
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut table)?;
+            Ok(table)
+        };
+        let read_hash_table = |table: Vec<u8>| -> Result<Vec<u32>, Error> {
+            if table.is_empty() || table.len() % 4 != 0 {
+                return Err(Error::InvalidRomFs(
+                    "hash table size is wrong",
+                    Backtrace::new(),
+                ));
+            }
+            let mut hash_table = vec![0; table.len() / 4];
+            Cursor::new(table).read_u32_into::<LE>(&mut hash_table)?;
+            Ok(hash_table)
+        };
+
+        let dir_hash_table = read_hash_table(read_table(dir_hash_table_ofs, dir_hash_table_size)?)?;
+        let dir_table = read_table(dir_table_ofs, dir_table_size)?;
+        let file_hash_table =
+            read_hash_table(read_table(file_hash_table_ofs, file_hash_table_size)?)?;
+        let file_table = read_table(file_table_ofs, file_table_size)?;
+
+        Ok(RomFsReader {
+            file: f,
+            file_partition_offset,
+            dir_hash_table,
+            dir_table,
+            file_hash_table,
+            file_table,
+        })
+    }
+
+    fn read_name(table: &[u8], offset: usize, name_size: u32) -> Result<String, Error> {
+        let name = table
+            .get(offset..offset + name_size as usize)
+            .ok_or_else(|| Error::InvalidRomFs("entry name is out of bounds", Backtrace::new()))?;
+        let name = String::from_utf8(name.to_vec())?;
+        // Names end up as host paths when extracting.
+        if name == "." || name == ".." || name.contains('/') || name.contains('\\') {
+            return Err(Error::InvalidRomFs(
+                "entry name is invalid",
+                Backtrace::new(),
+            ));
+        }
+        Ok(name)
+    }
+
+    /// Returns the parent offset, the next entry in the hash bucket and the
+    /// directory entry at `offset` in the directory table.
+    fn dir_entry(&self, offset: u32) -> Result<(u32, u32, RomFsDirEntry), Error> {
+        let entry = self
+            .dir_table
+            .get(offset as usize..offset as usize + 0x18)
+            .ok_or_else(|| {
+                Error::InvalidRomFs("directory entry is out of bounds", Backtrace::new())
+            })?;
+        let mut cursor = Cursor::new(entry);
+        let parent = cursor.read_u32::<LE>()?;
+        let sibling = cursor.read_u32::<LE>()?;
+        let child = cursor.read_u32::<LE>()?;
+        let file = cursor.read_u32::<LE>()?;
+        let hash_next = cursor.read_u32::<LE>()?;
+        let name_size = cursor.read_u32::<LE>()?;
+        let name = if offset == 0 {
+            // The root directory has no name.
+            String::new()
+        } else {
+            Self::read_name(&self.dir_table, offset as usize + 0x18, name_size)?
+        };
+        Ok((
+            parent,
+            hash_next,
+            RomFsDirEntry {
+                sibling,
+                child,
+                file,
+                name,
+            },
+        ))
+    }
+
+    /// Returns the parent offset, the sibling offset, the next entry in the
+    /// hash bucket and the file entry at `offset` in the file table. The
+    /// entry path only contains the file name.
+    fn file_entry(&self, offset: u32) -> Result<(u32, u32, u32, RomFsFileEntry), Error> {
+        let entry = self
+            .file_table
+            .get(offset as usize..offset as usize + 0x20)
+            .ok_or_else(|| Error::InvalidRomFs("file entry is out of bounds", Backtrace::new()))?;
+        let mut cursor = Cursor::new(entry);
+        let parent = cursor.read_u32::<LE>()?;
+        let sibling = cursor.read_u32::<LE>()?;
+        let data_offset = cursor.read_u64::<LE>()?;
+        let size = cursor.read_u64::<LE>()?;
+        let hash_next = cursor.read_u32::<LE>()?;
+        let name_size = cursor.read_u32::<LE>()?;
+        let name = Self::read_name(&self.file_table, offset as usize + 0x20, name_size)?;
+        Ok((
+            parent,
+            sibling,
+            hash_next,
+            RomFsFileEntry {
+                path: name,
+                offset: data_offset,
+                size,
+            },
+        ))
+    }
+
+    /// Walks the directory tree from the root, returning the path of every
+    /// directory (except the root) and every file.
+    fn walk(&self) -> Result<(Vec<String>, Vec<RomFsFileEntry>), Error> {
+        let mut dirs = vec![];
+        let mut files = vec![];
+        // Every entry is at least 0x18 bytes long, so a well-formed image
+        // can't have more entries than that. This stops us from looping
+        // forever on a corrupted tree.
+        let max_entries = (self.dir_table.len() + self.file_table.len()) / 0x18;
+
+        let mut to_visit = vec![(0, String::new())];
+        while let Some((dir_offset, dir_path)) = to_visit.pop() {
+            let (_, _, dir) = self.dir_entry(dir_offset)?;
+
+            let mut file_offset = dir.file;
+            while file_offset != ROMFS_ENTRY_EMPTY {
+                let (_, sibling, _, mut file) = self.file_entry(file_offset)?;
+                file.path = dir_path.clone() + &file.path;
+                files.push(file);
+                file_offset = sibling;
+                if files.len() > max_entries {
+                    return Err(Error::InvalidRomFs("file tree loops", Backtrace::new()));
+                }
+            }
+
+            let mut child_offset = dir.child;
+            while child_offset != ROMFS_ENTRY_EMPTY {
+                let (_, _, child) = self.dir_entry(child_offset)?;
+                let child_path = dir_path.clone() + &child.name;
+                to_visit.push((child_offset, child_path.clone() + "/"));
+                dirs.push(child_path);
+                child_offset = child.sibling;
+                if dirs.len() > max_entries {
+                    return Err(Error::InvalidRomFs(
+                        "directory tree loops",
+                        Backtrace::new(),
+                    ));
+                }
+            }
+        }
+
+        Ok((dirs, files))
+    }
+
+    /// Returns every file in the RomFS.
+    pub fn files(&self) -> Result<Vec<RomFsFileEntry>, Error> {
+        Ok(self.walk()?.1)
+    }
+
+    /// Looks up a file by path using the RomFS hash tables.
+    pub fn find_file(&self, path: &str) -> Result<Option<RomFsFileEntry>, Error> {
+        let mut components = path.split('/').filter(|v| !v.is_empty()).peekable();
+        let mut parent = 0;
+        while let Some(component) = components.next() {
+            let hash = calc_path_hash(parent, component);
+            if components.peek().is_none() {
+                let bucket = hash as usize % self.file_hash_table.len();
+                let mut offset = self.file_hash_table[bucket];
+                while offset != ROMFS_ENTRY_EMPTY {
+                    let (file_parent, _, hash_next, mut file) = self.file_entry(offset)?;
+                    if file_parent == parent && file.path == component {
+                        file.path = path.trim_start_matches('/').to_string();
+                        return Ok(Some(file));
+                    }
+                    offset = hash_next;
+                }
+                return Ok(None);
+            }
+
+            let bucket = hash as usize % self.dir_hash_table.len();
+            let mut offset = self.dir_hash_table[bucket];
+            loop {
+                if offset == ROMFS_ENTRY_EMPTY {
+                    return Ok(None);
+                }
+                let (dir_parent, hash_next, dir) = self.dir_entry(offset)?;
+                if dir_parent == parent && dir.name == component {
+                    parent = offset;
+                    break;
+                }
+                offset = hash_next;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Opens the data of a file of this RomFS.
+    pub fn open_file(&self, file: &RomFsFileEntry) -> io::Result<ReadRange<R>> {
+        let mut range = ReadRange::new(
+            self.file.try_clone()?,
+            self.file_partition_offset + file.offset,
+            file.size,
+        );
+        range.seek(SeekFrom::Start(0))?;
+        Ok(range)
+    }
+
+    /// Extracts the whole RomFS tree into `output_directory`.
+    pub fn extract(&self, output_directory: &Path) -> Result<(), Error> {
+        let (dirs, files) = self.walk()?;
+        match fs::create_dir(output_directory) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err((err, output_directory).into()),
+        }
+        for dir in dirs {
+            let path = output_directory.join(&dir);
+            match fs::create_dir(&path) {
+                Ok(()) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err((err, path).into()),
+            }
+        }
+
+        let mut option = OpenOptions::new();
+        let output_option = option.write(true).create(true).truncate(true);
+        for file in files {
+            let path = output_directory.join(&file.path);
+            println!("Writing {}", file.path);
+            let mut out_file = output_option.open(&path).map_err(|err| (err, &path))?;
+            let len =
+                io::copy(&mut self.open_file(&file)?, &mut out_file).map_err(|err| (err, &path))?;
+            if len != file.size {
+                return Err(Error::InvalidRomFs(
+                    "file data is out of bounds",
+                    Backtrace::new(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
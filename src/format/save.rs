@@ -0,0 +1,794 @@
+//! Save data images, as stored in the SYSTEM and USER partitions.
+//!
+//! A save is a stack of storages, each read through the one below it:
+//!
+//! - The data remap storage, mapping the file into a virtual address space.
+//! - The hierarchical duplex storage, picking between two copies of each
+//!   block of metadata.
+//! - The metadata remap storage, on top of the duplex storage.
+//! - The journal storage, remapping the blocks of the data remap storage.
+//! - The hierarchical integrity storages, verifying the journal data and the
+//!   allocation table.
+//! - The allocation table file system, holding the actual files.
+
+use crate::error::Error;
+use crate::pki::{hmac_sha256, Keys};
+use crate::utils::seek_position;
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Backtrace;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+const SAVE_HEADER_SIZE: usize = 0x4000;
+const SAVE_FS_LIST_ENTRY_SIZE: u64 = 0x60;
+const SAVE_FS_LIST_USED_HEAD: u32 = 1;
+
+const SALT_SOURCES: [&str; 6] = [
+    "HierarchicalIntegrityVerificationStorage::Master",
+    "HierarchicalIntegrityVerificationStorage::L1",
+    "HierarchicalIntegrityVerificationStorage::L2",
+    "HierarchicalIntegrityVerificationStorage::L3",
+    "HierarchicalIntegrityVerificationStorage::L4",
+    "HierarchicalIntegrityVerificationStorage::L5",
+];
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A random access storage. Save data is made of several of them stacked on
+/// top of each other.
+trait Storage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    fn size(&self) -> u64;
+}
+
+type SharedStorage = Rc<dyn Storage>;
+
+struct FileStorage<R> {
+    file: RefCell<R>,
+    size: u64,
+}
+
+impl<R: Read + Seek> Storage for FileStorage<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+struct SubStorage {
+    base: SharedStorage,
+    offset: u64,
+    size: u64,
+}
+
+impl Storage for SubStorage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of a save data storage",
+            ));
+        }
+        self.base.read_at(self.offset + offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+fn slice(base: &SharedStorage, offset: u64, size: u64) -> SharedStorage {
+    Rc::new(SubStorage {
+        base: base.clone(),
+        offset,
+        size,
+    })
+}
+
+fn read_vec(storage: &dyn Storage, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; size];
+    storage.read_at(offset, &mut data)?;
+    Ok(data)
+}
+
+struct RemapEntry {
+    virtual_offset: u64,
+    physical_offset: u64,
+    size: u64,
+}
+
+/// Maps virtual offsets to physical ones, segment by segment.
+struct RemapStorage {
+    base: SharedStorage,
+    entries: Vec<RemapEntry>,
+}
+
+impl RemapStorage {
+    fn new(
+        base: SharedStorage,
+        header: &[u8],
+        entries: &dyn Storage,
+    ) -> Result<RemapStorage, Error> {
+        if &header[..4] != b"RMAP" {
+            return Err(Error::InvalidSave("remap magic is wrong", Backtrace::new()));
+        }
+        let count = LittleEndian::read_u32(&header[0x8..]) as usize;
+        let data = read_vec(entries, 0, count * 0x20)?;
+        let entries = data
+            .chunks(0x20)
+            .map(|entry| RemapEntry {
+                virtual_offset: LittleEndian::read_u64(&entry[0x0..]),
+                physical_offset: LittleEndian::read_u64(&entry[0x8..]),
+                size: LittleEndian::read_u64(&entry[0x10..]),
+            })
+            .collect();
+        Ok(RemapStorage { base, entries })
+    }
+}
+
+impl Storage for RemapStorage {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let entry = self
+                .entries
+                .iter()
+                .find(|entry| {
+                    entry.virtual_offset <= offset && offset < entry.virtual_offset + entry.size
+                })
+                .ok_or_else(|| invalid_data("save data read outside of the remap entries"))?;
+            let in_entry = offset - entry.virtual_offset;
+            let len = std::cmp::min(buf.len() as u64, entry.size - in_entry) as usize;
+            let (cur, rest) = buf.split_at_mut(len);
+            self.base.read_at(entry.physical_offset + in_entry, cur)?;
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| entry.virtual_offset + entry.size)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Picks every block from either copy A or copy B, depending on a bitmap.
+struct DuplexStorage {
+    data_a: SharedStorage,
+    data_b: SharedStorage,
+    bitmap: SharedStorage,
+    block_size: u64,
+}
+
+impl Storage for DuplexStorage {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let block = offset / self.block_size;
+            let in_block = offset % self.block_size;
+            let len = std::cmp::min(buf.len() as u64, self.block_size - in_block) as usize;
+            // The bitmap is made of little endian words, most significant
+            // bit first.
+            let mut word = [0; 4];
+            self.bitmap.read_at(block / 32 * 4, &mut word)?;
+            let use_b = LittleEndian::read_u32(&word) & (0x8000_0000 >> (block % 32)) != 0;
+            let data = if use_b { &self.data_b } else { &self.data_a };
+            let (cur, rest) = buf.split_at_mut(len);
+            data.read_at(offset, cur)?;
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.data_a.size()
+    }
+}
+
+/// Remaps the blocks of the journaled data.
+struct JournalStorage {
+    data: SharedStorage,
+    map: Vec<u32>,
+    block_size: u64,
+    size: u64,
+}
+
+impl Storage for JournalStorage {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the save journal",
+            ));
+        }
+        while !buf.is_empty() {
+            let block = (offset / self.block_size) as usize;
+            let in_block = offset % self.block_size;
+            let len = std::cmp::min(buf.len() as u64, self.block_size - in_block) as usize;
+            let physical_block = *self
+                .map
+                .get(block)
+                .ok_or_else(|| invalid_data("save journal block out of the map"))?;
+            let (cur, rest) = buf.split_at_mut(len);
+            self.data
+                .read_at(u64::from(physical_block) * self.block_size + in_block, cur)?;
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// One level of a hierarchical integrity storage, verifying every block read
+/// against the salted hashes of the level above it.
+struct IntegrityStorage {
+    hashes: SharedStorage,
+    data: SharedStorage,
+    block_size: u64,
+    salt: [u8; 0x20],
+}
+
+impl Storage for IntegrityStorage {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        let size = self.data.size();
+        let mut block_data = vec![0; self.block_size as usize];
+        while !buf.is_empty() {
+            let block = offset / self.block_size;
+            let in_block = (offset % self.block_size) as usize;
+            let len = std::cmp::min(buf.len(), self.block_size as usize - in_block);
+            let mut hash = [0; 0x20];
+            self.hashes.read_at(block * 0x20, &mut hash)?;
+
+            // Blocks that were never written have an empty hash, and read as
+            // zeroes.
+            for byte in block_data.iter_mut() {
+                *byte = 0;
+            }
+            if hash != [0; 0x20] {
+                let block_start = block * self.block_size;
+                let block_len = std::cmp::min(self.block_size, size - block_start) as usize;
+                self.data
+                    .read_at(block_start, &mut block_data[..block_len])?;
+                let mut hasher = Sha256::default();
+                hasher.input(&self.salt);
+                hasher.input(&block_data);
+                let mut computed = [0; 0x20];
+                computed.copy_from_slice(hasher.result().as_slice());
+                computed[0x1F] |= 0x80;
+                if computed != hash {
+                    return Err(invalid_data("hash mismatch in the save data"));
+                }
+            }
+
+            let (cur, rest) = buf.split_at_mut(len);
+            cur.copy_from_slice(&block_data[in_block..in_block + len]);
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.data.size()
+    }
+}
+
+/// Stacks the levels described by an IVFC header. The first level is the
+/// master hash, and the data level is read through the returned storage.
+fn hierarchical_integrity_storage(
+    header: &[u8],
+    master: SharedStorage,
+    mut level_storage: impl FnMut(usize, u64, u64) -> SharedStorage,
+) -> Result<SharedStorage, Error> {
+    if &header[..4] != b"IVFC" {
+        return Err(Error::InvalidSave("IVFC magic is wrong", Backtrace::new()));
+    }
+    let num_levels = LittleEndian::read_u32(&header[0xC..]) as usize;
+    if num_levels < 2 || num_levels > SALT_SOURCES.len() + 1 {
+        return Err(Error::InvalidSave(
+            "invalid IVFC level count",
+            Backtrace::new(),
+        ));
+    }
+    let salt_source = &header[0xA0..0xC0];
+    let mut storage = master;
+    for level in 1..num_levels {
+        let level_header = &header[0x10 + (level - 1) * 0x18..];
+        let offset = LittleEndian::read_u64(&level_header[0x0..]);
+        let size = LittleEndian::read_u64(&level_header[0x8..]);
+        let block_size_log2 = LittleEndian::read_u32(&level_header[0x10..]);
+        storage = Rc::new(IntegrityStorage {
+            hashes: storage,
+            data: level_storage(level, offset, size),
+            block_size: 1 << block_size_log2,
+            salt: hmac_sha256(salt_source, SALT_SOURCES[level - 1].as_bytes()),
+        });
+    }
+    Ok(storage)
+}
+
+/// The allocation table, chaining the blocks of each file into segments of
+/// contiguous blocks.
+struct AllocationTable {
+    storage: SharedStorage,
+}
+
+impl AllocationTable {
+    /// Reads the raw entry for a block. Entry 0 is the head of the free list,
+    /// so the entries are shifted by one.
+    fn entry(&self, block: u32) -> io::Result<(u32, u32)> {
+        let mut entry = [0; 8];
+        self.storage
+            .read_at((u64::from(block) + 1) * 8, &mut entry)?;
+        Ok((
+            LittleEndian::read_u32(&entry[0..]),
+            LittleEndian::read_u32(&entry[4..]),
+        ))
+    }
+
+    /// Returns the segments of the list starting at the given block, as
+    /// (first block, block count) pairs.
+    fn segments(&self, start_block: u32, max_blocks: u64) -> io::Result<Vec<(u32, u32)>> {
+        let mut segments = Vec::new();
+        let mut block = start_block;
+        let mut total = 0;
+        loop {
+            let (_prev, next) = self.entry(block)?;
+            let length = if next & 0x8000_0000 == 0 {
+                1
+            } else {
+                // Multi-block segments store their last entry in the
+                // following one.
+                let (_, last) = self.entry(block + 1)?;
+                (last & 0x7FFF_FFFF).saturating_sub(block + 1) + 1
+            };
+            total += u64::from(length);
+            if total > max_blocks {
+                return Err(invalid_data("loop in the save allocation table"));
+            }
+            segments.push((block, length));
+            match next & 0x7FFF_FFFF {
+                0 => break,
+                next => block = next - 1,
+            }
+        }
+        Ok(segments)
+    }
+}
+
+/// The data of a single allocation table list.
+struct AllocationTableStorage {
+    data: SharedStorage,
+    segments: Vec<(u32, u32)>,
+    block_size: u64,
+    size: u64,
+}
+
+impl AllocationTableStorage {
+    fn new(
+        data: SharedStorage,
+        table: &AllocationTable,
+        block_size: u64,
+        start_block: u32,
+        size: Option<u64>,
+    ) -> io::Result<AllocationTableStorage> {
+        let max_blocks = data.size() / block_size;
+        let segments = table.segments(start_block, max_blocks)?;
+        let list_size = segments
+            .iter()
+            .map(|(_, length)| u64::from(*length) * block_size)
+            .sum();
+        let size = size.unwrap_or(list_size);
+        if size > list_size {
+            return Err(invalid_data("save file is bigger than its blocks"));
+        }
+        Ok(AllocationTableStorage {
+            data,
+            segments,
+            block_size,
+            size,
+        })
+    }
+}
+
+impl Storage for AllocationTableStorage {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of a save file",
+            ));
+        }
+        let mut segment_start = 0;
+        for (block, length) in &self.segments {
+            if buf.is_empty() {
+                break;
+            }
+            let segment_size = u64::from(*length) * self.block_size;
+            if offset < segment_start + segment_size {
+                let in_segment = offset - segment_start;
+                let len = std::cmp::min(buf.len() as u64, segment_size - in_segment) as usize;
+                let (cur, rest) = buf.split_at_mut(len);
+                self.data
+                    .read_at(u64::from(*block) * self.block_size + in_segment, cur)?;
+                offset += len as u64;
+                buf = rest;
+            }
+            segment_start += segment_size;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// An entry of the directory or file table.
+struct SaveFsEntry {
+    parent: u32,
+    name: String,
+    /// The next sibling, then the first child directory and file for
+    /// directories, or the first block and the size for files.
+    value: [u8; 0x18],
+    next: u32,
+}
+
+fn read_fs_entry(table: &dyn Storage, index: u32) -> Result<SaveFsEntry, Error> {
+    let data = read_vec(
+        table,
+        u64::from(index) * SAVE_FS_LIST_ENTRY_SIZE,
+        SAVE_FS_LIST_ENTRY_SIZE as usize,
+    )?;
+    let name = &data[4..0x44];
+    let name_len = name.iter().position(|c| *c == 0).unwrap_or(0x40);
+    let mut value = [0; 0x18];
+    value.copy_from_slice(&data[0x44..0x5C]);
+    Ok(SaveFsEntry {
+        parent: LittleEndian::read_u32(&data[0..]),
+        name: String::from_utf8(name[..name_len].to_vec())?,
+        value,
+        next: LittleEndian::read_u32(&data[0x5C..]),
+    })
+}
+
+#[derive(Debug, Clone)]
+struct SaveFileMeta {
+    path: String,
+    start_block: u32,
+    size: u64,
+}
+
+/// A save data image, with the files of its file system.
+pub struct Save {
+    data: SharedStorage,
+    allocation_table: AllocationTable,
+    block_size: u64,
+    header_mac_valid: Option<bool>,
+    files: Vec<SaveFileMeta>,
+}
+
+impl Save {
+    /// Parses a save data image. If the save_mac_key is known, the header
+    /// CMAC is checked.
+    pub fn from_reader<R: Read + Seek + 'static>(mut f: R, keys: &Keys) -> Result<Save, Error> {
+        let file_size = f.seek(SeekFrom::End(0))?;
+        let file: SharedStorage = Rc::new(FileStorage {
+            file: RefCell::new(f),
+            size: file_size,
+        });
+        let header = read_vec(&*file, 0, SAVE_HEADER_SIZE)?;
+        let layout = &header[0x100..0x300];
+        if &layout[..4] != b"DISF" {
+            return Err(Error::InvalidSave("DISF magic is wrong", Backtrace::new()));
+        }
+        let header_mac_valid = keys
+            .save_mac_key()
+            .ok()
+            .map(|key| key.cmac(layout) == header[..0x10]);
+        if header_mac_valid == Some(false) {
+            return Err(Error::InvalidSave("header CMAC mismatch", Backtrace::new()));
+        }
+        let version = LittleEndian::read_u32(&layout[0x4..]);
+        let field = |offset: usize| LittleEndian::read_u64(&layout[offset..]);
+
+        let data_remap = Rc::new(RemapStorage::new(
+            slice(&file, field(0x48), field(0x50)),
+            &header[0x650..0x690],
+            &*slice(&file, field(0x28), field(0x30)),
+        )?) as SharedStorage;
+
+        if &header[0x300..0x304] != b"DPFS" {
+            return Err(Error::InvalidSave("DPFS magic is wrong", Backtrace::new()));
+        }
+        let duplex_block_size =
+            |layer: usize| 1 << LittleEndian::read_u32(&header[0x308 + layer * 0x14 + 0x10..]);
+        // The duplex index picks which copy of the master bitmap is current.
+        let master_bitmap = if layout[0x158] == 1 {
+            slice(&file, field(0xB0), field(0xB8))
+        } else {
+            slice(&file, field(0xA8), field(0xB8))
+        };
+        let duplex_l1 = Rc::new(DuplexStorage {
+            data_a: slice(&data_remap, field(0x58), field(0x68)),
+            data_b: slice(&data_remap, field(0x60), field(0x68)),
+            bitmap: master_bitmap,
+            block_size: duplex_block_size(1),
+        }) as SharedStorage;
+        let duplex_data = Rc::new(DuplexStorage {
+            data_a: slice(&data_remap, field(0x70), field(0x80)),
+            data_b: slice(&data_remap, field(0x78), field(0x80)),
+            bitmap: duplex_l1,
+            block_size: duplex_block_size(2),
+        }) as SharedStorage;
+
+        let meta_remap = Rc::new(RemapStorage::new(
+            duplex_data,
+            &header[0x690..0x6D0],
+            &*slice(&file, field(0x38), field(0x40)),
+        )?) as SharedStorage;
+
+        let journal_header = &header[0x408..0x428];
+        if &journal_header[..4] != b"JNGL" {
+            return Err(Error::InvalidSave("JNGL magic is wrong", Backtrace::new()));
+        }
+        let main_data_block_count = LittleEndian::read_u32(&header[0x42C..]) as usize;
+        let journal_map = read_vec(
+            &*slice(&meta_remap, field(0xD8), field(0xE0)),
+            0,
+            main_data_block_count * 8,
+        )?;
+        let journal = Rc::new(JournalStorage {
+            data: slice(&data_remap, field(0x88), field(0x98) + field(0xA0)),
+            map: journal_map
+                .chunks(8)
+                .map(|entry| LittleEndian::read_u32(entry) & 0x7FFF_FFFF)
+                .collect(),
+            block_size: LittleEndian::read_u64(&journal_header[0x18..]),
+            size: LittleEndian::read_u64(&journal_header[0x8..])
+                .saturating_sub(LittleEndian::read_u64(&journal_header[0x10..])),
+        }) as SharedStorage;
+
+        // The hashes live in the metadata, while the data itself is
+        // journaled.
+        let data_ivfc_header = &header[0x344..0x404];
+        let data_num_levels = LittleEndian::read_u32(&data_ivfc_header[0xC..]) as usize;
+        let data = hierarchical_integrity_storage(
+            data_ivfc_header,
+            slice(&file, field(0xC0), field(0xD0)),
+            |level, offset, size| {
+                if level == data_num_levels - 1 {
+                    journal.clone()
+                } else {
+                    slice(&meta_remap, offset, size)
+                }
+            },
+        )?;
+
+        // Starting with version 5, the allocation table is also verified.
+        let fat = if version >= 0x50000 {
+            let fat_ivfc_header = &header[0xA18..0xAD8];
+            let master_hash_size = LittleEndian::read_u32(&fat_ivfc_header[0x8..]);
+            hierarchical_integrity_storage(
+                fat_ivfc_header,
+                slice(&file, field(0x160), u64::from(master_hash_size)),
+                |_, offset, size| slice(&meta_remap, offset, size),
+            )?
+        } else {
+            slice(&meta_remap, field(0x148), field(0x150))
+        };
+
+        if &header[0x608..0x60C] != b"SAVE" {
+            return Err(Error::InvalidSave("SAVE magic is wrong", Backtrace::new()));
+        }
+        let block_size = LittleEndian::read_u64(&header[0x618..]);
+        let fat_header = &header[0x620..0x650];
+        let directory_table_block = LittleEndian::read_u32(&fat_header[0x28..]);
+        let file_table_block = LittleEndian::read_u32(&fat_header[0x2C..]);
+        let allocation_table = AllocationTable { storage: fat };
+
+        let directory_table = AllocationTableStorage::new(
+            data.clone(),
+            &allocation_table,
+            block_size,
+            directory_table_block,
+            None,
+        )?;
+        let file_table = AllocationTableStorage::new(
+            data.clone(),
+            &allocation_table,
+            block_size,
+            file_table_block,
+            None,
+        )?;
+        let files = list_files(&directory_table, &file_table)?;
+
+        Ok(Save {
+            data,
+            allocation_table,
+            block_size,
+            header_mac_valid,
+            files,
+        })
+    }
+
+    /// Whether the header CMAC is valid, or None if the save_mac_key isn't
+    /// known.
+    pub fn header_mac_valid(&self) -> Option<bool> {
+        self.header_mac_valid
+    }
+
+    /// The paths of every file, without the leading slash.
+    pub fn file_names(&self) -> Vec<&str> {
+        self.files.iter().map(|file| &file.path[..]).collect()
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = io::Result<SaveFile>> + '_ {
+        self.files.iter().map(move |file| self.open(file))
+    }
+
+    fn open(&self, file: &SaveFileMeta) -> io::Result<SaveFile> {
+        let storage: Option<SharedStorage> = if file.size == 0 {
+            None
+        } else {
+            Some(Rc::new(AllocationTableStorage::new(
+                self.data.clone(),
+                &self.allocation_table,
+                self.block_size,
+                file.start_block,
+                Some(file.size),
+            )?))
+        };
+        Ok(SaveFile {
+            name: file.path.clone(),
+            storage,
+            size: file.size,
+            pos: 0,
+        })
+    }
+}
+
+/// Walks the directory tree from the root, listing every file.
+fn list_files(
+    directory_table: &dyn Storage,
+    file_table: &dyn Storage,
+) -> Result<Vec<SaveFileMeta>, Error> {
+    let max_entries =
+        std::cmp::max(directory_table.size(), file_table.size()) / SAVE_FS_LIST_ENTRY_SIZE;
+
+    // The root directory is the only one without a parent.
+    let mut root = None;
+    let mut index = read_fs_entry(directory_table, SAVE_FS_LIST_USED_HEAD)?.next;
+    for _ in 0..max_entries {
+        if index == 0 {
+            break;
+        }
+        let entry = read_fs_entry(directory_table, index)?;
+        if entry.parent == 0 && entry.name.is_empty() {
+            root = Some(entry);
+            break;
+        }
+        index = entry.next;
+    }
+    let root = root.ok_or_else(|| Error::InvalidSave("no root directory", Backtrace::new()))?;
+
+    let mut files = Vec::new();
+    let mut directories = vec![(String::new(), root)];
+    let mut visited = 0;
+    while let Some((path, directory)) = directories.pop() {
+        let mut index = LittleEndian::read_u32(&directory.value[0x8..]);
+        while index != 0 {
+            visited += 1;
+            if visited > max_entries {
+                return Err(Error::InvalidSave(
+                    "loop in the file table",
+                    Backtrace::new(),
+                ));
+            }
+            let entry = read_fs_entry(file_table, index)?;
+            files.push(SaveFileMeta {
+                path: format!("{}{}", path, entry.name),
+                start_block: LittleEndian::read_u32(&entry.value[0x4..]),
+                size: LittleEndian::read_u64(&entry.value[0x8..]),
+            });
+            index = LittleEndian::read_u32(&entry.value[0x0..]);
+        }
+
+        let mut index = LittleEndian::read_u32(&directory.value[0x4..]);
+        while index != 0 {
+            visited += 1;
+            if visited > max_entries {
+                return Err(Error::InvalidSave(
+                    "loop in the directory table",
+                    Backtrace::new(),
+                ));
+            }
+            let entry = read_fs_entry(directory_table, index)?;
+            index = LittleEndian::read_u32(&entry.value[0x0..]);
+            directories.push((format!("{}{}/", path, entry.name), entry));
+        }
+    }
+    Ok(files)
+}
+
+/// A file of a save, decrypted and verified as it is read.
+pub struct SaveFile {
+    name: String,
+    storage: Option<SharedStorage>,
+    size: u64,
+    pos: u64,
+}
+
+impl SaveFile {
+    /// The path of the file, without the leading slash.
+    pub fn file_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Read for SaveFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.size.saturating_sub(self.pos)) as usize;
+        if let Some(storage) = &self.storage {
+            storage.read_at(self.pos, &mut buf[..len])?;
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for SaveFile {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.size, from)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn allocation_table_follows_segments() {
+        // Block 0 alone, then blocks 3 to 5, then blocks 1 and 2.
+        let entries: [(u32, u32); 7] = [
+            (0, 0),
+            (0x8000_0000, 4),
+            (0x8000_0004, 0x8000_0000),
+            (0x8000_0002, 3),
+            (1, 0x8000_0002),
+            (0x8000_0004, 6),
+            (0x8000_0004, 2),
+        ];
+        let mut table = Vec::new();
+        for (prev, next) in entries.iter() {
+            table.extend_from_slice(&prev.to_le_bytes());
+            table.extend_from_slice(&next.to_le_bytes());
+        }
+        let table = AllocationTable {
+            storage: Rc::new(FileStorage {
+                size: table.len() as u64,
+                file: RefCell::new(Cursor::new(table)),
+            }),
+        };
+        assert_eq!(table.segments(0, 6).unwrap(), vec![(0, 1), (3, 3), (1, 2)]);
+        assert!(table.segments(0, 5).is_err());
+    }
+}
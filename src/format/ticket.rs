@@ -0,0 +1,225 @@
+//! eTickets, holding the title keys of titles using titlekey crypto.
+
+use crate::error::Error;
+use crate::pki::Keys;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Backtrace;
+use std::io::{self, Read, Write};
+
+/// The issuer of retail tickets.
+pub const RETAIL_ISSUER: &str = "Root-CA00000003-XS00000020";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureType {
+    Rsa4096Sha1,
+    Rsa2048Sha1,
+    EcdsaSha1,
+    Rsa4096Sha256,
+    Rsa2048Sha256,
+    EcdsaSha256,
+}
+
+impl SignatureType {
+    fn from_u32(value: u32) -> Result<SignatureType, Error> {
+        match value {
+            0x10000 => Ok(SignatureType::Rsa4096Sha1),
+            0x10001 => Ok(SignatureType::Rsa2048Sha1),
+            0x10002 => Ok(SignatureType::EcdsaSha1),
+            0x10003 => Ok(SignatureType::Rsa4096Sha256),
+            0x10004 => Ok(SignatureType::Rsa2048Sha256),
+            0x10005 => Ok(SignatureType::EcdsaSha256),
+            _ => Err(Error::InvalidTicket(
+                "unknown signature type",
+                Backtrace::new(),
+            )),
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            SignatureType::Rsa4096Sha1 => 0x10000,
+            SignatureType::Rsa2048Sha1 => 0x10001,
+            SignatureType::EcdsaSha1 => 0x10002,
+            SignatureType::Rsa4096Sha256 => 0x10003,
+            SignatureType::Rsa2048Sha256 => 0x10004,
+            SignatureType::EcdsaSha256 => 0x10005,
+        }
+    }
+
+    /// The size of the signature, and of the padding following it.
+    fn sizes(self) -> (usize, usize) {
+        match self {
+            SignatureType::Rsa4096Sha1 | SignatureType::Rsa4096Sha256 => (0x200, 0x3C),
+            SignatureType::Rsa2048Sha1 | SignatureType::Rsa2048Sha256 => (0x100, 0x3C),
+            SignatureType::EcdsaSha1 | SignatureType::EcdsaSha256 => (0x3C, 0x40),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleKeyType {
+    /// The title key is encrypted with the titlekek only.
+    Common,
+    /// The title key is additionally encrypted with the console's eTicket
+    /// RSA key.
+    Personalized,
+}
+
+/// A ticket, as found in `.tik` files.
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub signature_type: SignatureType,
+    pub signature: Vec<u8>,
+    pub issuer: String,
+    /// For common tickets, only the first 0x10 bytes are used.
+    pub title_key_block: Vec<u8>,
+    pub title_key_type: TitleKeyType,
+    pub ticket_version: u16,
+    pub license_type: u8,
+    pub master_key_revision: u8,
+    pub properties: u16,
+    pub ticket_id: u64,
+    pub device_id: u64,
+    pub rights_id: [u8; 0x10],
+    pub account_id: u32,
+}
+
+impl Ticket {
+    /// Creates a common ticket for the given title key, encrypting it with
+    /// the titlekek of the master key revision. We can't sign it, so the
+    /// signature is left zeroed.
+    pub fn new_common(
+        rights_id: [u8; 0x10],
+        title_key: &[u8; 0x10],
+        master_key_revision: u8,
+        keys: &Keys,
+    ) -> Result<Ticket, Error> {
+        let titlekek = keys.titlekek(usize::from(master_key_revision))?;
+        let mut title_key_block = vec![0; 0x100];
+        title_key_block[..0x10].copy_from_slice(&titlekek.encrypt_block(title_key));
+        Ok(Ticket {
+            signature_type: SignatureType::Rsa2048Sha256,
+            signature: vec![0; 0x100],
+            issuer: String::from(RETAIL_ISSUER),
+            title_key_block,
+            title_key_type: TitleKeyType::Common,
+            ticket_version: 0,
+            license_type: 0,
+            master_key_revision,
+            properties: 0,
+            ticket_id: 0,
+            device_id: 0,
+            rights_id,
+            account_id: 0,
+        })
+    }
+
+    pub fn from_reader<R: Read>(mut f: R) -> Result<Ticket, Error> {
+        let signature_type = SignatureType::from_u32(f.read_u32::<LittleEndian>()?)?;
+        let (signature_size, padding_size) = signature_type.sizes();
+        let mut signature = vec![0; signature_size];
+        f.read_exact(&mut signature)?;
+        io::copy(&mut (&mut f).take(padding_size as u64), &mut io::sink())?;
+
+        let mut issuer = [0; 0x40];
+        f.read_exact(&mut issuer)?;
+        let issuer_len = issuer.iter().position(|c| *c == 0).unwrap_or(0x40);
+        let issuer = String::from_utf8(issuer[..issuer_len].to_vec())?;
+        let mut title_key_block = vec![0; 0x100];
+        f.read_exact(&mut title_key_block)?;
+
+        let _format_version = f.read_u8()?;
+        let title_key_type = match f.read_u8()? {
+            0 => TitleKeyType::Common,
+            1 => TitleKeyType::Personalized,
+            _ => {
+                return Err(Error::InvalidTicket(
+                    "unknown title key type",
+                    Backtrace::new(),
+                ))
+            }
+        };
+        let ticket_version = f.read_u16::<LittleEndian>()?;
+        let license_type = f.read_u8()?;
+        let master_key_revision = f.read_u8()?;
+        let properties = f.read_u16::<LittleEndian>()?;
+        let _reserved = f.read_u64::<LittleEndian>()?;
+        let ticket_id = f.read_u64::<LittleEndian>()?;
+        let device_id = f.read_u64::<LittleEndian>()?;
+        let mut rights_id = [0; 0x10];
+        f.read_exact(&mut rights_id)?;
+        let account_id = f.read_u32::<LittleEndian>()?;
+
+        Ok(Ticket {
+            signature_type,
+            signature,
+            issuer,
+            title_key_block,
+            title_key_type,
+            ticket_version,
+            license_type,
+            master_key_revision,
+            properties,
+            ticket_id,
+            device_id,
+            rights_id,
+            account_id,
+        })
+    }
+
+    /// Decrypts the title key with the titlekek of the master key revision.
+    /// Only common tickets are supported, as personalized ones need the
+    /// eTicket RSA key of the console.
+    pub fn title_key(&self, keys: &Keys) -> Result<[u8; 0x10], Error> {
+        if self.title_key_type != TitleKeyType::Common {
+            return Err(Error::InvalidTicket(
+                "personalized tickets are not supported",
+                Backtrace::new(),
+            ));
+        }
+        let titlekek = keys.titlekek(usize::from(self.master_key_revision))?;
+        let mut encrypted_title_key = [0; 0x10];
+        encrypted_title_key.copy_from_slice(&self.title_key_block[..0x10]);
+        Ok(titlekek.decrypt_block(&encrypted_title_key))
+    }
+
+    pub fn write<W: Write>(&self, output_writter: &mut W) -> io::Result<()> {
+        let (signature_size, padding_size) = self.signature_type.sizes();
+        output_writter.write_u32::<LittleEndian>(self.signature_type.to_u32())?;
+        let mut signature = vec![0; signature_size];
+        let len = self.signature.len().min(signature_size);
+        signature[..len].copy_from_slice(&self.signature[..len]);
+        output_writter.write_all(&signature)?;
+        output_writter.write_all(&vec![0; padding_size])?;
+
+        let mut issuer = [0; 0x40];
+        let len = self.issuer.len().min(0x40);
+        issuer[..len].copy_from_slice(&self.issuer.as_bytes()[..len]);
+        output_writter.write_all(&issuer)?;
+        let mut title_key_block = [0; 0x100];
+        let len = self.title_key_block.len().min(0x100);
+        title_key_block[..len].copy_from_slice(&self.title_key_block[..len]);
+        output_writter.write_all(&title_key_block)?;
+
+        output_writter.write_u8(2)?; // Format version
+        output_writter.write_u8(match self.title_key_type {
+            TitleKeyType::Common => 0,
+            TitleKeyType::Personalized => 1,
+        })?;
+        output_writter.write_u16::<LittleEndian>(self.ticket_version)?;
+        output_writter.write_u8(self.license_type)?;
+        output_writter.write_u8(self.master_key_revision)?;
+        output_writter.write_u16::<LittleEndian>(self.properties)?;
+        output_writter.write_all(&[0; 8])?;
+        output_writter.write_u64::<LittleEndian>(self.ticket_id)?;
+        output_writter.write_u64::<LittleEndian>(self.device_id)?;
+        output_writter.write_all(&self.rights_id)?;
+        output_writter.write_u32::<LittleEndian>(self.account_id)?;
+        // No section records.
+        output_writter.write_u32::<LittleEndian>(0)?;
+        output_writter.write_u32::<LittleEndian>(0x2C0)?;
+        output_writter.write_u16::<LittleEndian>(0)?;
+        output_writter.write_u16::<LittleEndian>(0)?;
+        Ok(())
+    }
+}
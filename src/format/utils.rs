@@ -1,4 +1,6 @@
+use crate::error::Error;
 use elf;
+use failure::Backtrace;
 use lz4;
 use serde::de::{Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -46,6 +48,17 @@ pub fn compress_blz(uncompressed_data: &mut Vec<u8>) -> blz_nx::BlzResult<Vec<u8
     Ok(compressed_data)
 }
 
+/// Decompresses data compressed with `compress_blz`.
+pub fn decompress_blz(compressed_data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut compressed_data = compressed_data.to_vec();
+    let decompressed_size = blz_nx::get_decompression_buffer_size(&compressed_data)
+        .map_err(|_| Error::InvalidBlz("footer is corrupted", Backtrace::new()))?;
+    let mut decompressed_data = vec![0; decompressed_size];
+    blz_nx::decompress_raw(&mut compressed_data[..], &mut decompressed_data[..])
+        .map_err(|_| Error::InvalidBlz("compressed data is corrupted", Backtrace::new()))?;
+    Ok(decompressed_data)
+}
+
 pub fn calculate_sha256(data: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut hasher = Sha256::default();
     hasher.input(data);
@@ -111,3 +124,28 @@ impl Serialize for HexOrNum {
         serializer.collect_str(&format_args!("{:#010x}", self.0))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blz_decompresses_literals_and_back_references() {
+        let mut compressed = vec![
+            0x00,
+            0x60, // Back-reference: 9 bytes, 3 bytes behind
+            0x00,
+            0xF0, // Back-reference: 18 bytes, 3 bytes behind
+            b'x',
+            b'y',
+            b'z',        // Literals
+            0b0001_1000, // Control: 3 literals, 2 back-references
+        ];
+        compressed.extend_from_slice(&20u32.to_le_bytes()); // Compressed size
+        compressed.extend_from_slice(&12u32.to_le_bytes()); // Footer size
+        compressed.extend_from_slice(&10u32.to_le_bytes()); // Additional size
+
+        let decompressed = decompress_blz(&compressed).unwrap();
+        assert_eq!(&decompressed[..], &b"xyz".repeat(10)[..]);
+    }
+}
@@ -0,0 +1,187 @@
+//! XCI, the gamecard image format.
+//!
+//! An XCI starts with a signed header, pointing to a root HFS0 whose files
+//! are the partitions of the gamecard (update, normal, secure and logo), each
+//! of them being an HFS0 as well.
+
+use crate::error::Error;
+use crate::format::hfs0::{self, Hfs0, Hfs0Entry};
+use crate::pki::Keys;
+use crate::utils::{ReadRange, TryClone};
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Backtrace;
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// XCI offsets are expressed in media units.
+const MEDIA_UNIT_SIZE: u64 = 0x200;
+
+/// The encrypted part of the XCI header.
+#[derive(Debug, Clone)]
+pub struct XciGamecardInfo {
+    pub firmware_version: u64,
+    pub access_control_flags: u32,
+    pub read_wait_time: u32,
+    pub read_wait_time2: u32,
+    pub write_wait_time: u32,
+    pub write_wait_time2: u32,
+    pub firmware_mode: u32,
+    /// Version of the system update stored in the update partition.
+    pub cup_version: u32,
+    pub update_partition_hash: [u8; 8],
+    /// Title ID of the system update stored in the update partition.
+    pub cup_id: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct XciHeader {
+    pub secure_area_start: u64,
+    pub backup_area_start: u64,
+    pub title_key_dec_index: u8,
+    /// 0xFA for 1GB, 0xF8 for 2GB, 0xF0 for 4GB, 0xE0 for 8GB, 0xE1 for
+    /// 16GB and 0xE2 for 32GB.
+    pub gamecard_size: u8,
+    pub header_version: u8,
+    pub flags: u8,
+    pub package_id: u64,
+    pub valid_data_end: u64,
+    pub root_partition_offset: u64,
+    pub root_partition_header_size: u64,
+    pub root_partition_header_hash: [u8; 0x20],
+    /// Only available when the `xci_header_key` is known.
+    pub gamecard_info: Option<XciGamecardInfo>,
+}
+
+impl XciHeader {
+    fn parse(raw: &[u8; 0x200], keys: &Keys) -> Result<XciHeader, Error> {
+        if &raw[0x100..0x104] != b"HEAD" {
+            return Err(Error::InvalidXci("magic is wrong", Backtrace::new()));
+        }
+
+        // The IV of the header extension is stored reversed.
+        let mut iv = [0; 0x10];
+        iv.copy_from_slice(&raw[0x120..0x130]);
+        iv.reverse();
+        let gamecard_info = match keys.xci_header_key() {
+            Ok(key) => {
+                let mut info = [0; 0x70];
+                info.copy_from_slice(&raw[0x190..0x200]);
+                key.decrypt_cbc(&mut info, &iv)?;
+                let mut update_partition_hash = [0; 8];
+                update_partition_hash.copy_from_slice(&info[0x28..0x30]);
+                Some(XciGamecardInfo {
+                    firmware_version: LittleEndian::read_u64(&info[0x0..0x8]),
+                    access_control_flags: LittleEndian::read_u32(&info[0x8..0xC]),
+                    read_wait_time: LittleEndian::read_u32(&info[0xC..0x10]),
+                    read_wait_time2: LittleEndian::read_u32(&info[0x10..0x14]),
+                    write_wait_time: LittleEndian::read_u32(&info[0x14..0x18]),
+                    write_wait_time2: LittleEndian::read_u32(&info[0x18..0x1C]),
+                    firmware_mode: LittleEndian::read_u32(&info[0x1C..0x20]),
+                    cup_version: LittleEndian::read_u32(&info[0x20..0x24]),
+                    update_partition_hash,
+                    cup_id: LittleEndian::read_u64(&info[0x30..0x38]),
+                })
+            }
+            Err(_) => None,
+        };
+
+        let mut root_partition_header_hash = [0; 0x20];
+        root_partition_header_hash.copy_from_slice(&raw[0x140..0x160]);
+        Ok(XciHeader {
+            secure_area_start: u64::from(LittleEndian::read_u32(&raw[0x104..0x108]))
+                * MEDIA_UNIT_SIZE,
+            backup_area_start: u64::from(LittleEndian::read_u32(&raw[0x108..0x10C]))
+                * MEDIA_UNIT_SIZE,
+            title_key_dec_index: raw[0x10C],
+            gamecard_size: raw[0x10D],
+            header_version: raw[0x10E],
+            flags: raw[0x10F],
+            package_id: LittleEndian::read_u64(&raw[0x110..0x118]),
+            valid_data_end: LittleEndian::read_u64(&raw[0x118..0x120]) * MEDIA_UNIT_SIZE,
+            root_partition_offset: LittleEndian::read_u64(&raw[0x130..0x138]),
+            root_partition_header_size: LittleEndian::read_u64(&raw[0x138..0x140]),
+            root_partition_header_hash,
+            gamecard_info,
+        })
+    }
+}
+
+pub struct Xci<R> {
+    file: R,
+    header: XciHeader,
+    partitions: Vec<Hfs0Entry>,
+}
+
+impl<R: Read + Seek> Xci<R> {
+    pub fn from_reader(mut f: R, keys: &Keys) -> Result<Xci<R>, Error> {
+        let mut raw_header = [0; 0x200];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut raw_header)?;
+        let header = XciHeader::parse(&raw_header, keys)?;
+
+        let mut root_header = vec![0; header.root_partition_header_size as usize];
+        f.seek(SeekFrom::Start(header.root_partition_offset))?;
+        f.read_exact(&mut root_header)?;
+        let mut hasher = Sha256::default();
+        hasher.input(&root_header);
+        if hasher.result()[..] != header.root_partition_header_hash[..] {
+            return Err(Error::InvalidXci(
+                "root partition header hash mismatch",
+                Backtrace::new(),
+            ));
+        }
+
+        let (partitions, _) = hfs0::read_header(&mut Cursor::new(root_header))?;
+        Ok(Xci {
+            file: f,
+            header,
+            partitions,
+        })
+    }
+
+    pub fn header(&self) -> &XciHeader {
+        &self.header
+    }
+
+    pub fn partition_names(&self) -> Vec<&str> {
+        self.partitions
+            .iter()
+            .map(|partition| partition.name.as_str())
+            .collect()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.file
+    }
+}
+
+impl<R: Read + Seek + TryClone + 'static> Xci<R> {
+    /// Opens a partition of the gamecard by name, after checking the hash of
+    /// its header.
+    pub fn open_partition(&self, name: &str) -> Result<Hfs0, Error> {
+        let partition = self
+            .partitions
+            .iter()
+            .find(|partition| partition.name == name)
+            .ok_or_else(|| Error::InvalidXci("no such partition", Backtrace::new()))?;
+
+        let mut file = ReadRange::new(
+            self.file.try_clone()?,
+            self.header.root_partition_offset + partition.offset,
+            partition.size,
+        );
+        let mut data = vec![0; partition.hashed_region_size as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut data)?;
+        let mut hasher = Sha256::default();
+        hasher.input(&data);
+        if hasher.result()[..] != partition.hash[..] {
+            return Err(Error::Hfs0HashMismatch(
+                partition.name.clone(),
+                Backtrace::new(),
+            ));
+        }
+
+        Hfs0::from_reader(file)
+    }
+}
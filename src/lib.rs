@@ -1,4 +1,4 @@
 pub mod error;
 pub mod format;
 pub mod pki;
-mod utils;
+pub mod utils;
@@ -1,23 +1,29 @@
 use crate::error::Error;
+use crate::utils::to_hex;
 use aes::block_cipher_trait::generic_array::GenericArray;
 use aes::block_cipher_trait::BlockCipher;
 use aes::Aes128;
 use block_modes::block_padding::ZeroPadding;
-use block_modes::{BlockMode, BlockModeIv, Ctr128};
+use block_modes::{BlockMode, BlockModeIv, Cbc, Ctr128};
 use cmac::crypto_mac::Mac;
 use cmac::Cmac;
 use failure::Backtrace;
+use hmac::Hmac;
 use ini::{self, ini::Properties};
+use num_bigint::BigUint;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
 use std::io::{self, ErrorKind, Write};
 use std::path::Path;
 
-struct Aes128Key([u8; 0x10]);
-struct AesXtsKey([u8; 0x20]);
+pub(crate) struct Aes128Key([u8; 0x10]);
+pub(crate) struct AesXtsKey([u8; 0x20]);
 struct EncryptedKeyblob([u8; 0xB0]);
 struct Keyblob([u8; 0x90]);
-struct Modulus([u8; 0x100]);
+pub(crate) struct Modulus([u8; 0x100]);
 
 macro_rules! impl_debug {
     ($for:ident) => {
@@ -46,6 +52,181 @@ impl_debug!(EncryptedKeyblob);
 impl_debug!(Keyblob);
 impl_debug!(Modulus);
 
+/// SHA-256 hashes of the well-known key sources, to catch typos in a keyset
+/// without shipping the keys. They are the same on retail and dev units.
+const KEY_FINGERPRINTS: &[(&str, &str)] = &[
+    (
+        "keyblob_mac_key_source",
+        "b24bd293259dbc7ac5d63f88e60c59792498e6fc5443402c7ffe87ee8b61a3f0",
+    ),
+    (
+        "keyblob_key_source_00",
+        "8a06fe274ac491436791fdb388bcdd3ab9943bd4def8094418cdac150fd73786",
+    ),
+    (
+        "keyblob_key_source_01",
+        "2d5caeb2521fef70b47e17d6d0f11f8ce2c1e442a979ad8035832c4e9fbccc4b",
+    ),
+    (
+        "keyblob_key_source_02",
+        "61c5005e713bae780641683af43e5f5c0e03671117f702f401282847d2fc6064",
+    ),
+    (
+        "keyblob_key_source_03",
+        "8e9795928e1c4428e1b78f0be724d7294d6934689c11b190943923b9d5b85903",
+    ),
+    (
+        "keyblob_key_source_04",
+        "95fa33af95aff9d9b61d164655b32710ed8d615d46c7d6cc3cc70481b686b402",
+    ),
+    (
+        "keyblob_key_source_05",
+        "3f5be7b3c8b1abd8c10b4b703d44766ba08730562c172a4fe0d6b866b3e2db3e",
+    ),
+    (
+        "master_key_source",
+        "7944862a3a5c31c6720595efd302245abd1b54ccdcf33000557681e65c5664a4",
+    ),
+    (
+        "package2_key_source",
+        "21e2df100fc9e094db51b47b9b1d6e94ed379db8b547955bef8fe08d8dd35603",
+    ),
+    (
+        "per_console_key_source",
+        "78762d65d81375fb050a3a2148f962fabe0eb8689f3b044be59014aefb50eb55",
+    ),
+    (
+        "aes_kek_generation_source",
+        "fc02b9d37b42d7a1452e71444f1f700311d1132e301a83b16062e72a78175085",
+    ),
+    (
+        "aes_key_generation_source",
+        "fbd10056999edc7acdb96098e47e2c3606230270d23281e671f0f389fc5bc585",
+    ),
+    (
+        "titlekek_source",
+        "c48b619827986c7f4e3081d59db2b460c84312650e9a8e6b458e53e8cbca4e87",
+    ),
+    (
+        "key_area_key_application_source",
+        "04ad66143c726b2a139fb6b21128b46f56c553b2b3887110304298d8d0092d9e",
+    ),
+    (
+        "key_area_key_ocean_source",
+        "fd434000c8ff2b26f8e9a9d2d2c12f6be5773cbb9dc86300e1bd99f8ea33a417",
+    ),
+    (
+        "key_area_key_system_source",
+        "1f17b1fd51ad1c2379b58f152ca4912ec2106441e51722f38700d5937a1162f7",
+    ),
+    (
+        "sd_card_kek_source",
+        "6b2ed877c2c52334ac51e59abfa7ec457f4a7d01e46291e9f2eaa45f011d24b7",
+    ),
+    (
+        "sd_card_save_key_source",
+        "d482743563d3ea5dcdc3b74e97c9ac8a342164fa041a1dc80f17f6d31e4bc01c",
+    ),
+    (
+        "sd_card_nca_key_source",
+        "2e751cecf7d93a2b957bd5ffcb082fd038cc2853219dd3092c6dab9838f5a7cc",
+    ),
+    (
+        "save_mac_kek_source",
+        "3dcba100ad4df1547fe3c4795c4b228aa98038f07a36f1bc148eeaf3dcd750f4",
+    ),
+    (
+        "save_mac_key_source",
+        "b47b600b1ad314f941147d8b391d4b1987cc8c884ac89ffc91cae221c52451f7",
+    ),
+    (
+        "header_kek_source",
+        "1888caed5551b3ede01499e87ce0d86827f80820efb275921055aa4e2abdffc2",
+    ),
+    (
+        "header_key_source",
+        "8f783e46852df6be0ba4e19273c4adbaee16380043e1b8c418c4089a8bd64aa6",
+    ),
+];
+
+/// SHA-256 hashes of the keys derived on retail units, like the header_key,
+/// the key_area_key_*_NN, titlekek_NN and package2_key_NN. They depend on
+/// the master keks, so they are checked against the retail or dev table
+/// depending on how the keyset was loaded. Add a key here once its hash has
+/// been confirmed on a known-good keyset.
+const RETAIL_KEY_FINGERPRINTS: &[(&str, &str)] = &[];
+
+/// SHA-256 hashes of the keys derived on dev units. See
+/// `RETAIL_KEY_FINGERPRINTS`.
+const DEV_KEY_FINGERPRINTS: &[(&str, &str)] = &[];
+
+/// HMAC with SHA-256.
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 0x20] {
+    // HMAC accepts keys of any size.
+    let mut hmac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    hmac.input(message);
+    let mut mac = [0; 0x20];
+    mac.copy_from_slice(hmac.result().code().as_slice());
+    mac
+}
+
+/// MGF1 with SHA-256, as used by RSA-PSS.
+fn mgf1_sha256(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(len + 0x20);
+    let mut counter = 0u32;
+    while mask.len() < len {
+        let mut hasher = Sha256::default();
+        hasher.input(seed);
+        hasher.input(&counter.to_be_bytes());
+        mask.extend_from_slice(hasher.result().as_slice());
+        counter += 1;
+    }
+    mask.truncate(len);
+    mask
+}
+
+impl Modulus {
+    /// Verifies an RSA-2048-PSS signature over the SHA-256 of the message,
+    /// with the public exponent 65537.
+    pub(crate) fn verify_pss_sha256(&self, signature: &[u8], message: &[u8]) -> bool {
+        let modulus = BigUint::from_bytes_be(&self.0);
+        let signature = BigUint::from_bytes_be(signature);
+        if signature >= modulus {
+            return false;
+        }
+        let decrypted = signature
+            .modpow(&BigUint::from(65537u32), &modulus)
+            .to_bytes_be();
+        if decrypted.len() > 0x100 {
+            return false;
+        }
+        let mut em = [0; 0x100];
+        em[0x100 - decrypted.len()..].copy_from_slice(&decrypted);
+
+        // The modulus is 2048 bits, so the top bit of the encoded message
+        // must be clear.
+        if em[0xFF] != 0xBC || em[0] & 0x80 != 0 {
+            return false;
+        }
+        let (masked_db, hash) = em[..0xFF].split_at(0xFF - 0x20);
+        let mut db = mgf1_sha256(hash, masked_db.len());
+        for (db, masked) in db.iter_mut().zip(masked_db) {
+            *db ^= masked;
+        }
+        db[0] &= 0x7F;
+        let salt_start = match db.iter().position(|byte| *byte != 0) {
+            Some(idx) if db[idx] == 1 => idx + 1,
+            _ => return false,
+        };
+
+        let mut hasher = Sha256::default();
+        hasher.input(&[0; 8]);
+        hasher.input(Sha256::digest(message).as_slice());
+        hasher.input(&db[salt_start..]);
+        hasher.result().as_slice() == hash
+    }
+}
+
 impl Keyblob {
     fn encrypt(
         &self,
@@ -95,6 +276,60 @@ impl EncryptedKeyblob {
 }
 
 impl Aes128Key {
+    /// Decrypts a single block with AES-128-ECB, as used by the key area
+    /// and the title keys.
+    pub(crate) fn decrypt_block(&self, block: &[u8; 0x10]) -> [u8; 0x10] {
+        let mut block = *block;
+        let crypter = Aes128::new(GenericArray::from_slice(&self.0));
+        crypter.decrypt_block(GenericArray::from_mut_slice(&mut block));
+        block
+    }
+
+    /// Decrypts data with AES-128-CBC. The data must be a multiple of the
+    /// block size.
+    pub(crate) fn decrypt_cbc(&self, data: &mut [u8], iv: &[u8; 0x10]) -> Result<(), Error> {
+        let mut crypter = Cbc::<Aes128, ZeroPadding>::new_fixkey(
+            GenericArray::from_slice(&self.0),
+            GenericArray::from_slice(iv),
+        );
+        crypter.decrypt_nopad(data)?;
+        Ok(())
+    }
+
+    /// Encrypts or decrypts data with AES-128-CTR, starting from the given
+    /// counter.
+    pub(crate) fn ctr_crypt(&self, data: &mut [u8], ctr: &[u8; 0x10]) -> Result<(), Error> {
+        let mut crypter = Ctr128::<Aes128, ZeroPadding>::new_fixkey(
+            GenericArray::from_slice(&self.0),
+            GenericArray::from_slice(ctr),
+        );
+        if data.len() % 0x10 == 0 {
+            crypter.encrypt_nopad(data)?;
+        } else {
+            let mut padded = vec![0; (data.len() + 0xF) & !0xF];
+            padded[..data.len()].copy_from_slice(data);
+            crypter.encrypt_nopad(&mut padded)?;
+            let len = data.len();
+            data.copy_from_slice(&padded[..len]);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn cmac(&self, data: &[u8]) -> [u8; 0x10] {
+        let mut cmac = Cmac::<Aes128>::new_varkey(&self.0[..]).unwrap();
+        cmac.input(data);
+        let mut mac = [0; 0x10];
+        mac.copy_from_slice(cmac.result().code().as_slice());
+        mac
+    }
+
+    pub(crate) fn encrypt_block(&self, block: &[u8; 0x10]) -> [u8; 0x10] {
+        let mut block = *block;
+        let crypter = Aes128::new(GenericArray::from_slice(&self.0));
+        crypter.encrypt_block(GenericArray::from_mut_slice(&mut block));
+        block
+    }
+
     fn derive_key(&self, source: &[u8; 0x10]) -> Result<Aes128Key, Error> {
         let mut newkey = *source;
 
@@ -124,6 +359,76 @@ impl Aes128Key {
     }
 }
 
+impl AesXtsKey {
+    /// Runs AES-128-XTS over `data`, starting at `sector`. Unlike the
+    /// standard, Nintendo encodes the sector number of the tweak as big
+    /// endian.
+    fn xts(&self, data: &mut [u8], mut sector: usize, sector_size: usize, encrypt: bool) {
+        let data_crypter = Aes128::new(GenericArray::from_slice(&self.0[..0x10]));
+        let tweak_crypter = Aes128::new(GenericArray::from_slice(&self.0[0x10..]));
+        for sector_data in data.chunks_mut(sector_size) {
+            let mut tweak = [0; 0x10];
+            tweak[8..].copy_from_slice(&(sector as u64).to_be_bytes());
+            tweak_crypter.encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+
+            for block in sector_data.chunks_exact_mut(0x10) {
+                for (byte, tweak_byte) in block.iter_mut().zip(tweak.iter()) {
+                    *byte ^= tweak_byte;
+                }
+                if encrypt {
+                    data_crypter.encrypt_block(GenericArray::from_mut_slice(block));
+                } else {
+                    data_crypter.decrypt_block(GenericArray::from_mut_slice(block));
+                }
+                for (byte, tweak_byte) in block.iter_mut().zip(tweak.iter()) {
+                    *byte ^= tweak_byte;
+                }
+
+                // Multiply the tweak by x in GF(2^128).
+                let carry = tweak[0xF] >> 7;
+                for i in (1..0x10).rev() {
+                    tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+                }
+                tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+            }
+            sector += 1;
+        }
+    }
+
+    pub(crate) fn decrypt(&self, data: &mut [u8], sector: usize, sector_size: usize) {
+        self.xts(data, sector, sector_size, false)
+    }
+
+    /// Unwraps the key of a NAX0 file, given its path on the SD card and
+    /// the first 0x80 bytes of its header. The path is hashed with the first
+    /// half of the SD card key into the keks of both halves of the key, and
+    /// the second half checks the MAC of the header. Returns None if the MAC
+    /// doesn't match.
+    pub(crate) fn unwrap_nax0_key(&self, path: &str, header: &[u8]) -> Option<AesXtsKey> {
+        let keks = hmac_sha256(&self.0[..0x10], path.as_bytes());
+        let mut key = [0; 0x20];
+        let mut mac_key = header[0x20..0x80].to_vec();
+        for idx in 0..2 {
+            let mut kek = [0; 0x10];
+            kek.copy_from_slice(&keks[idx * 0x10..(idx + 1) * 0x10]);
+            let mut encrypted_key = [0; 0x10];
+            encrypted_key.copy_from_slice(&header[0x28 + idx * 0x10..0x38 + idx * 0x10]);
+            let half = Aes128Key(kek).decrypt_block(&encrypted_key);
+            key[idx * 0x10..(idx + 1) * 0x10].copy_from_slice(&half);
+            mac_key[8 + idx * 0x10..0x18 + idx * 0x10].copy_from_slice(&half);
+        }
+        if hmac_sha256(&mac_key, &self.0[0x10..]) == header[..0x20] {
+            Some(AesXtsKey(key))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn encrypt(&self, data: &mut [u8], sector: usize, sector_size: usize) {
+        self.xts(data, sector, sector_size, true)
+    }
+}
+
 fn key_to_aes(keys: &Properties, name: &str, key: &mut [u8]) -> Result<Option<()>, Error> {
     let value = keys.get(name);
     if let Some(value) = value {
@@ -152,6 +457,18 @@ fn key_to_aes(keys: &Properties, name: &str, key: &mut [u8]) -> Result<Option<()
     }
 }
 
+/// Parses a hexstring of exactly the size of `out`.
+fn parse_hex(value: &str, out: &mut [u8]) -> Option<()> {
+    if value.len() != out.len() * 2 {
+        return None;
+    }
+    for (idx, c) in value.bytes().enumerate() {
+        let c = (c as char).to_digit(16)? as u8;
+        out[idx / 2] |= c << if idx % 2 == 0 { 4 } else { 0 };
+    }
+    Some(())
+}
+
 fn key_to_aes_array(
     keys: &Properties,
     name: &str,
@@ -173,6 +490,59 @@ impl<T> OptionExt for Option<T> {
     }
 }
 
+/// Where a key of the keyset comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStatus {
+    /// Read from the keyset file.
+    Loaded,
+    /// Derived from other keys.
+    Derived,
+    Missing,
+}
+
+/// The status of a key of the keyset, as returned by `Keys::report`.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyReport {
+    pub name: String,
+    /// The generation of keys that have one per master key revision.
+    pub index: Option<usize>,
+    pub console_unique: bool,
+    pub status: KeyStatus,
+    /// For a missing key, the missing parents of each way to derive it.
+    /// Empty if the key can only be loaded.
+    pub missing_parents: Vec<Vec<String>>,
+}
+
+/// The keys of the boot chain of Erista units, which Mariko units replace with
+/// the mariko_kek and mariko_bek.
+const ERISTA_ONLY_KEYS: &[&str] = &[
+    "secure_boot_key",
+    "tsec_key",
+    "tsec_root_kek",
+    "package1_mac_kek",
+    "package1_kek",
+    "tsec_auth_signatures",
+    "tsec_root_key",
+    "keyblob_mac_key_source",
+    "keyblob_key_sources",
+    "keyblob_keys",
+    "keyblob_mac_keys",
+    "encrypted_keyblobs",
+    "keyblobs",
+    "master_kek_sources",
+    "package1_keys",
+    "package1_mac_keys",
+];
+
+/// A keyset as JSON, see `Keys::write_json`.
+#[derive(Default, Serialize, Deserialize)]
+struct KeysJson {
+    keys: BTreeMap<String, String>,
+    #[serde(default)]
+    title_keys: BTreeMap<String, String>,
+}
+
 #[derive(Default, Debug)]
 pub struct Keys {
     secure_boot_key: Option<Aes128Key>,
@@ -212,6 +582,7 @@ pub struct Keys {
     sd_card_kek_source: Option<Aes128Key>,
     sd_card_save_key_source: Option<AesXtsKey>,
     sd_card_nca_key_source: Option<AesXtsKey>,
+    sd_seed: Option<Aes128Key>,
     save_mac_kek_source: Option<Aes128Key>,
     save_mac_key_source: Option<Aes128Key>,
     header_key_source: Option<AesXtsKey>,
@@ -227,25 +598,55 @@ pub struct Keys {
     nca_hdr_fixed_key_modulus: [Option<Modulus>; 2],
     acid_fixed_key_modulus: [Option<Modulus>; 2],
     package2_fixed_key_modulus: Option<Modulus>,
+    title_keys: HashMap<[u8; 0x10], Aes128Key>,
+    loaded_keys: HashSet<String>,
+    derived_key_fingerprints: &'static [(&'static str, &'static str)],
+}
+
+/// The name of a parent of a key in the `keys!` macro, and whether it is
+/// present. The name is None for a conditional parent whose condition
+/// fails, like the TSEC keys before 6.2.0.
+macro_rules! key_parent {
+    ($self:ident, $name:ident) => {
+        (Some(String::from(stringify!($name))), $self.$name.is_some())
+    };
+    ($self:ident, $name:ident[$idx:expr]) => {
+        (
+            Some(multi_key_name(stringify!($name), $idx)),
+            $self.$name[$idx].is_some(),
+        )
+    };
+    ($self:ident, $name:ident[$idx:expr] if $cond:expr) => {
+        if $cond {
+            key_parent!($self, $name[$idx])
+        } else {
+            (None, false)
+        }
+    };
+}
+
+/// The parents of a key in the `keys!` macro, as returned by `key_parent!`.
+/// Each parent is written `name`, `name[index]`, or `name[index] if cond`
+/// for the parents that only exist in some generations.
+macro_rules! key_parents {
+    ($self:ident; $($name:ident $([$idx:expr])? $(if $cond:expr)?),*) => {{
+        #[allow(unused_mut)]
+        let mut parents: Vec<(Option<String>, bool)> = Vec::new();
+        $(parents.push(key_parent!($self, $name $([$idx])? $(if $cond)?));)*
+        parents
+    }};
 }
 
 macro_rules! make_key_macros_write {
-    ($d:tt, $self:ident, $w:ident, $show_console_unique:expr, $minimal:expr) => {
+    ($d:tt, $self:ident, $w:ident, $shown:ident, $minimal:expr) => {
         macro_rules! single_key {
-            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:expr),*]) => {
-                if $show_console_unique || !$console_unique {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
+                if $shown(stringify!($keyname), $console_unique) {
                     #[allow(unused_mut)]
                     for key in &$self.$keyname {
                         if $minimal {
-                            let mut count = 0;
-                            let mut total = 0;
-                            $d (
-                                total += 1;
-                                if $parent.is_some() {
-                                    count += 1;
-                                }
-                            )*
-                            if count == total && total != 0 {
+                            let parents = key_parents!($self; $d ($parent)*);
+                            if !parents.is_empty() && parents.iter().all(|(_, present)| *present) {
                                 continue;
                             }
                         }
@@ -259,24 +660,14 @@ macro_rules! make_key_macros_write {
         }
 
         macro_rules! single_key_xts {
-            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:expr),*]) => {
-                if $show_console_unique || !$console_unique {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
+                if $shown(stringify!($keyname), $console_unique) {
                     #[allow(unused_mut)]
                     for key in &$self.$keyname {
                         if $minimal {
-                            let mut count = 0;
-                            let mut total = 0;
-                            $d (
-                                total += 1;
-                                if $parent.is_some() {
-                                    count += 1;
-                                }
-                            )*
-                            if count == total && total != 0 {
+                            let parents = key_parents!($self; $d ($parent)*);
+                            if !parents.is_empty() && parents.iter().all(|(_, present)| *present) {
                                 continue;
-                                //println!("Skipping {}", stringify!($keyname));
-                            } else if total != 0 {
-                                //println!("Can't skip {}, need {}, have {}", stringify!($keyname), total, count);
                             }
                         }
                         for line in $doc.split('\n') {
@@ -289,22 +680,15 @@ macro_rules! make_key_macros_write {
         }
 
         macro_rules! multi_key {
-            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:expr),*]),*) => {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
                 let mut first = true;
-                if $show_console_unique || !$console_unique {
+                if $shown(stringify!($keyname), $console_unique) {
                     #[allow(unused_mut)]
                     for ($idx, v) in $self.$keyname.iter().enumerate() {
                         if $minimal {
                             $d (
-                                let mut count = 0;
-                                let mut total = 0;
-                                $d (
-                                    total += 1;
-                                    if $parent.is_some() {
-                                        count += 1;
-                                    }
-                                )*
-                                if count == total && total != 0 {
+                                let parents = key_parents!($self; $d ($parent)*);
+                                if !parents.is_empty() && parents.iter().all(|(_, present)| *present) {
                                     continue;
                                 }
                             )*
@@ -332,8 +716,8 @@ macro_rules! make_key_macros_write {
         }
 
         macro_rules! multi_keyblob {
-            ($keyname:tt, $doc:expr, $console_unique:expr) => {
-                if $show_console_unique || !$console_unique {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                if $shown(stringify!($keyname), $console_unique) {
                     let mut first = true;
                     for (idx, v) in $self.$keyname.iter().enumerate() {
                         if let Some(key) = v {
@@ -359,8 +743,8 @@ macro_rules! make_key_macros_write {
         }
 
         macro_rules! multi_encrypted_keyblob {
-            ($keyname:tt, $doc:expr, $console_unique:expr) => {
-                if $show_console_unique || !$console_unique {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                if $shown(stringify!($keyname), $console_unique) {
                     let mut first = true;
                     for (idx, v) in $self.$keyname.iter().enumerate() {
                         if let Some(key) = v {
@@ -390,7 +774,7 @@ macro_rules! make_key_macros_write {
 macro_rules! make_key_macros {
     ($d:tt, $self:ident, $section:ident) => {
         macro_rules! single_key {
-            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:expr),*]) => {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
                 let mut key = [0; 0x10];
                 $self.$keyname.or_in(
                     key_to_aes($section, stringify!($keyname), &mut key)?.map(|()| Aes128Key(key)),
@@ -399,7 +783,7 @@ macro_rules! make_key_macros {
         }
 
         macro_rules! single_key_xts {
-            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:expr),*]) => {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
                 let mut key = [0; 0x20];
                 $self.$keyname.or_in(
                     key_to_aes($section, stringify!($keyname), &mut key)?.map(|()| AesXtsKey(key)),
@@ -408,7 +792,7 @@ macro_rules! make_key_macros {
         }
 
         macro_rules! multi_key {
-            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:expr),*]),*) => {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
                 for (idx, v) in $self.$keyname.iter_mut().enumerate() {
                     let mut key = [0; 0x10];
                     // remove trailing s
@@ -424,7 +808,7 @@ macro_rules! make_key_macros {
         }
 
         macro_rules! multi_keyblob {
-            ($keyname:tt, $doc:expr, $console_unique:expr) => {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
                 for (idx, v) in $self.$keyname.iter_mut().enumerate() {
                     let mut key = [0; 0x90];
                     // remove trailing s
@@ -440,7 +824,7 @@ macro_rules! make_key_macros {
         }
 
         macro_rules! multi_encrypted_keyblob {
-            ($keyname:tt, $doc:expr, $console_unique:expr) => {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
                 for (idx, v) in $self.$keyname.iter_mut().enumerate() {
                     let mut key = [0; 0xB0];
                     // remove trailing s
@@ -458,8 +842,109 @@ macro_rules! make_key_macros {
     };
 }
 
+macro_rules! make_key_macros_report {
+    ($d:tt, $self:ident, $reports:ident) => {
+        macro_rules! single_key {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
+                let parents = key_parents!($self; $d ($parent)*);
+                $reports.push($self.key_report(
+                    String::from(stringify!($keyname)),
+                    None,
+                    $console_unique,
+                    $self.$keyname.is_some(),
+                    vec![parents],
+                ));
+            };
+        }
+
+        macro_rules! single_key_xts {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
+                single_key!($keyname, $doc, $console_unique, [$d ($parent)*]);
+            };
+        }
+
+        macro_rules! multi_key {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                for ($idx, v) in $self.$keyname.iter().enumerate() {
+                    let alternatives = vec![$d (key_parents!($self; $d ($parent)*)),*];
+                    $reports.push($self.key_report(
+                        multi_key_name(stringify!($keyname), $idx),
+                        Some($idx),
+                        $console_unique,
+                        v.is_some(),
+                        alternatives,
+                    ));
+                }
+            };
+        }
+
+        macro_rules! multi_keyblob {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                multi_key!($keyname, $doc, $console_unique, $idx => $d ([$d ($parent)*]),*);
+            };
+        }
+
+        macro_rules! multi_encrypted_keyblob {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                multi_key!($keyname, $doc, $console_unique, $idx => $d ([$d ($parent)*]),*);
+            };
+        }
+    };
+}
+
+macro_rules! make_key_macros_visit {
+    ($d:tt, $self:ident, $visit:ident) => {
+        macro_rules! single_key {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
+                if let Some(key) = &$self.$keyname {
+                    $visit(String::from(stringify!($keyname)), $console_unique, &key.0[..]);
+                }
+            };
+        }
+
+        macro_rules! single_key_xts {
+            ($keyname:tt, $doc:expr, $console_unique:expr, [$d ($parent:tt)*]) => {
+                single_key!($keyname, $doc, $console_unique, [$d ($parent)*]);
+            };
+        }
+
+        macro_rules! multi_key {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                for ($idx, v) in $self.$keyname.iter().enumerate() {
+                    if let Some(key) = v {
+                        $visit(multi_key_name(stringify!($keyname), $idx), $console_unique, &key.0[..]);
+                    }
+                }
+            };
+        }
+
+        macro_rules! multi_keyblob {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                multi_key!($keyname, $doc, $console_unique, $idx => $d ([$d ($parent)*]),*);
+            };
+        }
+
+        macro_rules! multi_encrypted_keyblob {
+            ($keyname:tt, $doc:expr, $console_unique:expr, $idx:ident => $d ([$d ($parent:tt)*]),*) => {
+                multi_key!($keyname, $doc, $console_unique, $idx => $d ([$d ($parent)*]),*);
+            };
+        }
+    };
+}
+
+/// The name of a key with one instance per generation, as written in the
+/// keyset: without the trailing s, and with the generation in hex.
+fn multi_key_name(keyname: &str, idx: usize) -> String {
+    let keyname = if keyname.ends_with('s') {
+        &keyname[..keyname.len() - 1]
+    } else {
+        keyname
+    };
+    format!("{}_{:02x}", keyname, idx)
+}
+
 macro_rules! keys {
-    ($self:ident) => {
+    () => {
         single_key!(secure_boot_key, "Dumpable using Fusee-Gelee and biskeydump.
 Secure boot key of the console associated with given BOOT0.
 Useful to derive master_key and package1_key from keyblobs.
@@ -470,7 +955,7 @@ Useful to derive master_key and package1_key from keyblobs.
 NOTE: CONSOLE UNIQUE!", true, []);
         single_key!(device_key, "Device key used to derive some FS keys.
 Derived from per_console_key_source and keyblob_key_00
-NOTE: CONSOLE UNIQUE.", true, [$self.keyblob_keys[0], $self.per_console_key_source]);
+NOTE: CONSOLE UNIQUE.", true, [keyblob_keys[0], per_console_key_source]);
         single_key!(tsec_root_kek, "Used to generate TSEC root keys.
 Can be found using [magic hax] on the TSEC.", false, []);
         single_key!(package1_mac_kek, "Used to generate package1 validation keys.", false, []);
@@ -480,97 +965,106 @@ Can be found using [magic hax] on the TSEC.", false, []);
 
         multi_key!(tsec_root_key, "Key for master kek decryption, from TSEC firmware on 6.2.0+.
 Can be dumped using [magic hax] on the TSEC.
-Can be derived from tsec_root_kek and tsec_auth_signatures.", false, i => [$self.tsec_auth_signatures[i], $self.tsec_root_kek]);
+Can be derived from tsec_root_kek and tsec_auth_signatures.", false, i => [tsec_auth_signatures[i], tsec_root_kek]);
 
         single_key!(keyblob_mac_key_source, "Seed for keyblob MAC key derivation.", false, []);
         multi_key!(keyblob_key_sources, "Seeds for keyblob keys.", false, i => []);
 
-        multi_key!(keyblob_keys, "Actual keys used to decrypt keyblobs. NOTE: CONSOLE UNIQUE.", true, i => [$self.keyblob_key_sources[i], $self.tsec_key, $self.secure_boot_key]);
+        multi_key!(keyblob_keys, "Actual keys used to decrypt keyblobs. NOTE: CONSOLE UNIQUE.", true, i => [keyblob_key_sources[i], tsec_key, secure_boot_key]);
 
-        multi_key!(keyblob_mac_keys, "Keys used to validate keyblobs. NOTE: CONSOLE UNIQUE.", true, i => [$self.keyblob_keys[i], $self.keyblob_mac_key_source]);
+        multi_key!(keyblob_mac_keys, "Keys used to validate keyblobs. NOTE: CONSOLE UNIQUE.", true, i => [keyblob_keys[i], keyblob_mac_key_source]);
 
-        multi_encrypted_keyblob!(encrypted_keyblobs, "Actual encrypted keyblobs (EKS). NOTE: CONSOLE UNIQUE.", true);
+        multi_encrypted_keyblob!(encrypted_keyblobs, "Actual encrypted keyblobs (EKS). NOTE: CONSOLE UNIQUE.", true, i => [keyblobs[i], keyblob_keys[i], keyblob_mac_keys[i]]);
 
-        multi_keyblob!(keyblobs, "Actual decrypted keyblobs (EKS).", false);
+        multi_keyblob!(keyblobs, "Actual decrypted keyblobs (EKS).", false, i => [encrypted_keyblobs[i], keyblob_keys[i], keyblob_mac_keys[i]]);
 
         multi_key!(master_kek_sources, "Seeds for firmware master keks.", false, i => []);
 
         single_key!(mariko_kek, "Key Encryption Key for mariko.", false, []);
         single_key!(mariko_bek, "Boot Encryption Key for mariko.", false, []);
         multi_key!(mariko_aes_class_keys, "AES Class Keys set by mariko bootrom.", false, i => []);
+        // Also derived back from mariko_kek and master_keks, but listed as a seed
+        // so that --minimal keeps it over the master_keks derived from it.
         multi_key!(mariko_master_kek_sources, "Seeds for firmware master keks (Mariko).", false, i => []);
         multi_key!(master_keks, "Firmware master keks, stored in keyblob prior to 6.2.0.", false, i =>
-            [$self.keyblobs[i]],
-            [if i >= 6 { &$self.tsec_root_key[i - 6] } else { &None }, $self.master_kek_sources[i]],
-            [$self.mariko_kek, $self.mariko_master_kek_sources[i]]
+            [keyblobs[i]],
+            [tsec_root_key[i - 6] if i >= 6, master_kek_sources[i]],
+            [mariko_kek, mariko_master_kek_sources[i]]
         );
         single_key!(master_key_source, "Seed for master key derivation.", false, []);
-        multi_key!(master_keys, "Firmware master keys.", false, i => [$self.master_key_source, $self.master_keks[i]]);
+        multi_key!(master_keys, "Firmware master keys.", false, i => [master_key_source, master_keks[i]]);
         multi_key!(package1_keys, "Package1 keys.", false, i =>
-            [$self.keyblobs[i]],
-            [$self.package1_kek, if i >= 6 { &$self.tsec_auth_signatures[i - 6] } else { &None }]
+            [keyblobs[i]],
+            [package1_kek, tsec_auth_signatures[i - 6] if i >= 6]
         );
-        multi_key!(package1_mac_keys, "Package1 MAC Keys.", false, i => [$self.package1_mac_kek, if i >= 6 { &$self.tsec_auth_signatures[i - 6] } else { &None }]);
+        multi_key!(package1_mac_keys, "Package1 MAC Keys.", false, i => [package1_mac_kek, tsec_auth_signatures[i - 6] if i >= 6]);
         single_key!(package2_key_source, "Seed for Package2 key.", false, []);
-        multi_key!(package2_keys, "Package2 keys.", false, i => [$self.master_keys[i], $self.package2_key_source]);
+        multi_key!(package2_keys, "Package2 keys.", false, i => [master_keys[i], package2_key_source]);
         single_key!(per_console_key_source, "Seed for Device key.", false, []);
         single_key!(aes_kek_generation_source, "Seed for GenerateAesKek, usecase + generation 0.", false, []);
         single_key!(aes_key_generation_source, "Seed for GenerateAesKek.", false, []);
         single_key!(titlekek_source, "Seed for titlekeks.", false, []);
-        multi_key!(titlekeks, "Title key encryption keys.", false, i => [$self.master_keys[i], $self.titlekek_source]);
+        multi_key!(titlekeks, "Title key encryption keys.", false, i => [master_keys[i], titlekek_source]);
         single_key!(key_area_key_application_source, "Seed for kaek 0.", false, []);
         single_key!(key_area_key_ocean_source, "Seed for kaek 1.", false, []);
         single_key!(key_area_key_system_source, "Seed for kaek 2.", false, []);
         single_key!(sd_card_kek_source, "Seed for SD card kek.", false, []);
         single_key_xts!(sd_card_save_key_source, "Seed for SD card save encryption key.", false, []);
         single_key_xts!(sd_card_nca_key_source, "Seed for SD card NCA encryption key.", false, []);
+        single_key!(sd_seed, "Seed for the SD card keys, at offset 0x10 of the private file of
+system save 8000000000000043. NOTE: CONSOLE UNIQUE!", true, []);
         single_key!(save_mac_kek_source, "Seed for save kek.", false, []);
         single_key!(save_mac_key_source, "Seed for save key.", false, []);
         // Derived from save_mac_key_source, save_mac_kek_source and device_key
         single_key!(save_mac_key, "Key used to sign savedata. NOTE: CONSOLE UNIQUE!", true, [
-            $self.device_key,
-            $self.save_mac_kek_source,
-            $self.aes_kek_generation_source,
-            $self.save_mac_key_source
+            device_key,
+            save_mac_kek_source,
+            aes_kek_generation_source,
+            save_mac_key_source
         ]);
 
         single_key!(header_kek_source, "Seed for header kek.", false, []);
         single_key_xts!(header_key_source, "Seed for NCA header key.", false, []);
         single_key_xts!(header_key, "NCA header key.", false, [
-            $self.master_keys[0],
-            $self.key_area_key_application_source,
-            $self.aes_kek_generation_source,
-            $self.aes_key_generation_source
+            master_keys[0],
+            header_kek_source,
+            header_key_source,
+            aes_kek_generation_source,
+            aes_key_generation_source
         ]);
         multi_key!(key_area_key_application, "Key area encryption key 0.", true, i => [
-            $self.master_keys[i],
-            $self.key_area_key_application_source,
-            $self.aes_kek_generation_source,
-            $self.aes_key_generation_source
+            master_keys[i],
+            key_area_key_application_source,
+            aes_kek_generation_source,
+            aes_key_generation_source
         ]);
         multi_key!(key_area_key_ocean, "Key area encryption key 1.", true, i => [
-            $self.master_keys[i],
-            $self.key_area_key_ocean_source,
-            $self.aes_kek_generation_source,
-            $self.aes_key_generation_source
+            master_keys[i],
+            key_area_key_ocean_source,
+            aes_kek_generation_source,
+            aes_key_generation_source
         ]);
         multi_key!(key_area_key_system, "Key area encryption key 2.", true, i => [
-            $self.master_keys[i],
-            $self.key_area_key_system_source,
-            $self.aes_kek_generation_source,
-            $self.aes_key_generation_source
+            master_keys[i],
+            key_area_key_system_source,
+            aes_kek_generation_source,
+            aes_key_generation_source
         ]);
         single_key_xts!(sd_card_save_key, "Encryption key for SD card save.", true, [
-            $self.master_keys[0],
-            $self.sd_card_save_key_source,
-            $self.aes_kek_generation_source,
-            $self.aes_key_generation_source
+            master_keys[0],
+            sd_card_kek_source,
+            sd_card_save_key_source,
+            sd_seed,
+            aes_kek_generation_source,
+            aes_key_generation_source
         ]);
         single_key_xts!(sd_card_nca_key, "Encryption key for SD card NCA.", true, [
-            $self.master_keys[0],
-            $self.sd_card_nca_key_source,
-            $self.aes_kek_generation_source,
-            $self.aes_key_generation_source
+            master_keys[0],
+            sd_card_kek_source,
+            sd_card_nca_key_source,
+            sd_seed,
+            aes_kek_generation_source,
+            aes_key_generation_source
         ]);
 
         single_key!(xci_header_key, "Key for XCI partially encrypted header.", false, []);
@@ -594,6 +1088,7 @@ impl Keys {
         key_path: Option<&Path>,
         default_key_name: &Path,
         modulus: ([Modulus; 2], [Modulus; 2], Modulus),
+        derived_key_fingerprints: &'static [(&'static str, &'static str)],
     ) -> Result<Keys, Error> {
         let (modulus0, modulus1, modulus2) = modulus;
         let [modulus00, modulus01] = modulus0;
@@ -602,6 +1097,7 @@ impl Keys {
             nca_hdr_fixed_key_modulus: [Some(modulus00), Some(modulus01)],
             acid_fixed_key_modulus: [Some(modulus10), Some(modulus11)],
             package2_fixed_key_modulus: Some(modulus2),
+            derived_key_fingerprints,
             ..Default::default()
         };
 
@@ -623,12 +1119,18 @@ impl Keys {
         };
 
         let mut succeed = false;
+        let mut loaded_path = None;
         for path in paths {
             if let Some(path) = path {
                 match File::open(&path) {
                     Ok(file) => {
-                        keys.read_from_ini(file)?;
+                        if path.extension().map_or(false, |ext| ext == "json") {
+                            keys.read_from_json(file)?;
+                        } else {
+                            keys.read_from_ini(file)?;
+                        }
                         succeed = true;
+                        loaded_path = Some(path);
                         break;
                     }
                     Err(ref err) if err.kind() == ErrorKind::NotFound => (),
@@ -641,7 +1143,38 @@ impl Keys {
             return Err(io::Error::new(ErrorKind::NotFound, "Keyfile not found.").into());
         }
 
+        // The console unique and title keys are looked up next to the keyfile.
+        if let Some(dir) = loaded_path.as_ref().and_then(|path| path.parent()) {
+            let path = dir.join("console.keys");
+            match File::open(&path) {
+                Ok(file) => keys.read_from_ini(file)?,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err((err, path).into()),
+            }
+
+            let path = dir.join("title.keys");
+            match File::open(&path) {
+                Ok(file) => keys.read_title_keys_from_ini(file)?,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err((err, path).into()),
+            }
+        }
+
+        keys.loaded_keys = keys
+            .report()
+            .into_iter()
+            .filter(|key| key.status != KeyStatus::Missing)
+            .map(|key| key.name)
+            .collect();
         keys.derive_keys()?;
+
+        let wrong_keys = keys.wrong_keys();
+        if !wrong_keys.is_empty() {
+            println!(
+                "Warning: wrong value for the keys {}, check the keyset for typos.",
+                wrong_keys.join(", ")
+            );
+        }
         Ok(keys)
     }
 
@@ -776,6 +1309,7 @@ impl Keys {
                     0x39, 0xB1, 0x48, 0x05, 0x1E, 0x21, 0xC7, 0x24, 0x4F,
                 ]),
             ),
+            RETAIL_KEY_FINGERPRINTS,
         )
     }
 
@@ -910,19 +1444,169 @@ impl Keys {
                     0xC5, 0xE2, 0xAC, 0x1E, 0xC4, 0x33, 0x96, 0x09, 0x7B,
                 ]),
             ),
+            DEV_KEY_FINGERPRINTS,
         )
     }
 
+    pub(crate) fn header_key(&self) -> Result<&AesXtsKey, Error> {
+        self.header_key
+            .as_ref()
+            .ok_or_else(|| Error::MissingKey(String::from("header_key"), Backtrace::new()))
+    }
+
+    pub(crate) fn xci_header_key(&self) -> Result<&Aes128Key, Error> {
+        self.xci_header_key
+            .as_ref()
+            .ok_or_else(|| Error::MissingKey(String::from("xci_header_key"), Backtrace::new()))
+    }
+
+    pub(crate) fn titlekek(&self, generation: usize) -> Result<&Aes128Key, Error> {
+        self.titlekeks
+            .get(generation)
+            .and_then(|key| key.as_ref())
+            .ok_or_else(|| {
+                Error::MissingKey(format!("titlekek_{:02x}", generation), Backtrace::new())
+            })
+    }
+
+    /// The SD card keys that are known, for the save folder and the
+    /// Contents folder of the SD card.
+    pub(crate) fn sd_card_keys(&self) -> Result<Vec<&AesXtsKey>, Error> {
+        let keys: Vec<&AesXtsKey> = self
+            .sd_card_save_key
+            .iter()
+            .chain(self.sd_card_nca_key.iter())
+            .collect();
+        if keys.is_empty() {
+            return Err(Error::MissingKey(
+                String::from("sd_card_nca_key or sd_card_save_key"),
+                Backtrace::new(),
+            ));
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn save_mac_key(&self) -> Result<&Aes128Key, Error> {
+        self.save_mac_key
+            .as_ref()
+            .ok_or_else(|| Error::MissingKey(String::from("save_mac_key"), Backtrace::new()))
+    }
+
+    pub(crate) fn package1_key(&self, generation: usize) -> Result<&Aes128Key, Error> {
+        self.package1_keys
+            .get(generation)
+            .and_then(|key| key.as_ref())
+            .ok_or_else(|| {
+                Error::MissingKey(format!("package1_key_{:02x}", generation), Backtrace::new())
+            })
+    }
+
+    pub(crate) fn mariko_bek(&self) -> Result<&Aes128Key, Error> {
+        self.mariko_bek
+            .as_ref()
+            .ok_or_else(|| Error::MissingKey(String::from("mariko_bek"), Backtrace::new()))
+    }
+
+    pub(crate) fn package2_key(&self, generation: usize) -> Result<&Aes128Key, Error> {
+        self.package2_keys
+            .get(generation)
+            .and_then(|key| key.as_ref())
+            .ok_or_else(|| {
+                Error::MissingKey(format!("package2_key_{:02x}", generation), Backtrace::new())
+            })
+    }
+
+    pub(crate) fn package2_fixed_key_modulus(&self) -> Option<&Modulus> {
+        self.package2_fixed_key_modulus.as_ref()
+    }
+
+    /// Returns the key area key for the given key area key index (0 for
+    /// application, 1 for ocean and 2 for system) and master key revision.
+    pub(crate) fn key_area_key(&self, index: u8, generation: usize) -> Result<&Aes128Key, Error> {
+        let (keys, name) = match index {
+            0 => (&self.key_area_key_application, "key_area_key_application"),
+            1 => (&self.key_area_key_ocean, "key_area_key_ocean"),
+            2 => (&self.key_area_key_system, "key_area_key_system"),
+            _ => {
+                return Err(Error::Crypto(
+                    format!("Unknown key area key index {}", index),
+                    Backtrace::new(),
+                ))
+            }
+        };
+        keys.get(generation)
+            .and_then(|key| key.as_ref())
+            .ok_or_else(|| {
+                Error::MissingKey(format!("{}_{:02x}", name, generation), Backtrace::new())
+            })
+    }
+
     #[allow(clippy::cognitive_complexity)]
     fn read_from_ini(&mut self, mut file: File) -> Result<(), Error> {
         let config = ini::Ini::read_from(&mut file)?;
-        let section = config.general_section();
+        self.read_from_section(config.general_section())
+    }
 
+    #[allow(clippy::cognitive_complexity)]
+    fn read_from_section(&mut self, section: &Properties) -> Result<(), Error> {
         make_key_macros!($, self, section);
-        keys!(self);
+        keys!();
+        Ok(())
+    }
+
+    /// Reads a keyset in the JSON format written by `write_json`.
+    fn read_from_json(&mut self, file: File) -> Result<(), Error> {
+        let json: KeysJson = serde_json::from_reader(file)?;
+        self.read_from_section(&json.keys.into_iter().collect())?;
+        self.read_title_keys_from_section(&json.title_keys.into_iter().collect())
+    }
+
+    /// Reads title keys from a `title.keys` file: an INI file associating
+    /// rights IDs to decrypted title keys.
+    pub fn read_title_keys(&mut self, path: &Path) -> Result<(), Error> {
+        let file = File::open(path).map_err(|err| (err, path))?;
+        self.read_title_keys_from_ini(file)
+    }
+
+    fn read_title_keys_from_ini(&mut self, mut file: File) -> Result<(), Error> {
+        let config = ini::Ini::read_from(&mut file)?;
+        self.read_title_keys_from_section(config.general_section())
+    }
+
+    fn read_title_keys_from_section(&mut self, section: &Properties) -> Result<(), Error> {
+        for (name, _) in section.iter() {
+            let mut rights_id = [0; 0x10];
+            let mut title_key = [0; 0x10];
+            if parse_hex(name, &mut rights_id).is_none() {
+                return Err(Error::Crypto(
+                    format!("Rights ID {} should be a 32 character hexstring", name),
+                    Backtrace::new(),
+                ));
+            }
+            key_to_aes(section, name, &mut title_key)?;
+            self.add_title_key(rights_id, title_key);
+        }
         Ok(())
     }
 
+    /// Registers the decrypted title key of the given rights ID, to decrypt
+    /// NCAs using titlekey crypto.
+    pub fn add_title_key(&mut self, rights_id: [u8; 0x10], title_key: [u8; 0x10]) {
+        self.title_keys.insert(rights_id, Aes128Key(title_key));
+    }
+
+    pub(crate) fn title_key(&self, rights_id: &[u8; 0x10]) -> Result<[u8; 0x10], Error> {
+        self.title_keys
+            .get(rights_id)
+            .map(|key| key.0)
+            .ok_or_else(|| {
+                Error::MissingKey(
+                    format!("title key for rights ID {}", to_hex(rights_id)),
+                    Backtrace::new(),
+                )
+            })
+    }
+
     #[allow(clippy::cognitive_complexity)]
     pub fn write<W: Write>(
         &self,
@@ -930,11 +1614,162 @@ impl Keys {
         console_unique: bool,
         minimal: bool,
     ) -> io::Result<()> {
-        make_key_macros_write!($, self, w, console_unique, minimal);
-        keys!(self);
+        self.write_filtered(w, minimal, |_, key_console_unique| {
+            console_unique || !key_console_unique
+        })
+    }
+
+    /// Writes the keyset of a Mariko unit, leaving out the keys only used by
+    /// the boot chain of Erista units.
+    pub fn write_mariko<W: Write>(
+        &self,
+        w: &mut W,
+        console_unique: bool,
+        minimal: bool,
+    ) -> io::Result<()> {
+        self.write_filtered(w, minimal, |keyname, key_console_unique| {
+            (console_unique || !key_console_unique) && !ERISTA_ONLY_KEYS.contains(&keyname)
+        })
+    }
+
+    /// Writes only the console unique keys, in the format of a `console.keys`
+    /// file.
+    pub fn write_console_unique<W: Write>(&self, w: &mut W, minimal: bool) -> io::Result<()> {
+        self.write_filtered(w, minimal, |_, key_console_unique| key_console_unique)
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn write_filtered<W: Write, F: Fn(&str, bool) -> bool>(
+        &self,
+        w: &mut W,
+        minimal: bool,
+        shown: F,
+    ) -> io::Result<()> {
+        make_key_macros_write!($, self, w, shown, minimal);
+        keys!();
+        Ok(())
+    }
+
+    /// Writes the title keys, in the format of a `title.keys` file.
+    pub fn write_title_keys<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (rights_id, title_key) in self.title_keys_by_rights_id() {
+            writeln!(w, "{} = {}", rights_id, title_key)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the keyset and its title keys as JSON, with every key as a
+    /// hexstring under its name in the `keys` object, and every title key
+    /// under its rights ID in the `title_keys` object.
+    pub fn write_json<W: Write>(&self, w: &mut W, console_unique: bool) -> Result<(), Error> {
+        let mut keys = BTreeMap::new();
+        self.visit_keys(|name, key_console_unique, key| {
+            if console_unique || !key_console_unique {
+                keys.insert(name, to_hex(key));
+            }
+        });
+        let json = KeysJson {
+            keys,
+            title_keys: self.title_keys_by_rights_id(),
+        };
+        serde_json::to_writer_pretty(&mut *w, &json)?;
+        writeln!(w)?;
         Ok(())
     }
 
+    fn title_keys_by_rights_id(&self) -> BTreeMap<String, String> {
+        self.title_keys
+            .iter()
+            .map(|(rights_id, title_key)| (to_hex(rights_id), to_hex(&title_key.0)))
+            .collect()
+    }
+
+    /// Reports whether each key of the keyset was loaded, derived, or is
+    /// missing, and in that case which parents blocked its derivation.
+    #[allow(clippy::cognitive_complexity)]
+    pub fn report(&self) -> Vec<KeyReport> {
+        let mut reports = Vec::new();
+        make_key_macros_report!($, self, reports);
+        keys!();
+        reports
+    }
+
+    /// Calls `visit` with the name, console uniqueness and value of each key
+    /// of the keyset.
+    #[allow(clippy::cognitive_complexity)]
+    fn visit_keys<F: FnMut(String, bool, &[u8])>(&self, mut visit: F) {
+        make_key_macros_visit!($, self, visit);
+        keys!();
+    }
+
+    /// The names of the keys whose value doesn't match the fingerprint of the
+    /// well-known key of the same name.
+    pub fn wrong_keys(&self) -> Vec<String> {
+        let mut wrong_keys = Vec::new();
+        self.visit_keys(|name, _, key| {
+            let fingerprint = KEY_FINGERPRINTS
+                .iter()
+                .chain(self.derived_key_fingerprints)
+                .find(|(key_name, _)| *key_name == name)
+                .map(|(_, fingerprint)| fingerprint);
+            if let Some(fingerprint) = fingerprint {
+                if !to_hex(&Sha256::digest(key)).eq_ignore_ascii_case(fingerprint) {
+                    wrong_keys.push(name);
+                }
+            }
+        });
+        wrong_keys
+    }
+
+    /// Fails if a well-known key of the keyset has a wrong value.
+    pub fn check_fingerprints(&self) -> Result<(), Error> {
+        let wrong_keys = self.wrong_keys();
+        if wrong_keys.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::WrongKey(wrong_keys.join(", "), Backtrace::new()))
+        }
+    }
+
+    fn key_report(
+        &self,
+        name: String,
+        index: Option<usize>,
+        console_unique: bool,
+        present: bool,
+        alternatives: Vec<Vec<(Option<String>, bool)>>,
+    ) -> KeyReport {
+        let status = if !present {
+            KeyStatus::Missing
+        } else if self.loaded_keys.contains(&name) {
+            KeyStatus::Loaded
+        } else {
+            KeyStatus::Derived
+        };
+        let missing_parents = if present {
+            Vec::new()
+        } else {
+            alternatives
+                .iter()
+                .filter(|parents| !parents.is_empty())
+                .filter_map(|parents| {
+                    parents
+                        .iter()
+                        .filter(|(_, present)| !present)
+                        .map(|(parent, _)| parent.clone())
+                        .collect::<Option<Vec<String>>>()
+                })
+                .collect()
+        };
+        KeyReport {
+            name,
+            index,
+            console_unique,
+            status,
+            missing_parents,
+        }
+    }
+
     #[allow(clippy::cognitive_complexity)]
     #[allow(clippy::single_match)]
     pub fn derive_keys(&mut self) -> Result<(), Error> {
@@ -1166,12 +2001,14 @@ impl Keys {
                 &self.sd_card_kek_source,
                 &self.aes_kek_generation_source,
                 &self.aes_key_generation_source,
+                &self.sd_seed,
             ) {
                 (
                     Some(master_key),
                     Some(sd_card_kek_source),
                     Some(aes_kek_generation_source),
                     Some(aes_key_generation_source),
+                    Some(sd_seed),
                 ) => {
                     let sd_kek = generate_kek(
                         sd_card_kek_source,
@@ -1179,13 +2016,21 @@ impl Keys {
                         aes_kek_generation_source,
                         aes_key_generation_source,
                     )?;
+                    // The sources are mixed with the seed of the console.
+                    let seeded = |source: &AesXtsKey| {
+                        let mut source = source.0;
+                        for (byte, seed) in source.iter_mut().zip(sd_seed.0.iter().cycle()) {
+                            *byte ^= seed;
+                        }
+                        source
+                    };
                     if let Some(sd_card_save_key_source) = &self.sd_card_save_key_source {
                         self.sd_card_save_key =
-                            Some(sd_kek.derive_xts_key(&sd_card_save_key_source.0)?);
+                            Some(sd_kek.derive_xts_key(&seeded(sd_card_save_key_source))?);
                     }
                     if let Some(sd_card_nca_key_source) = &self.sd_card_nca_key_source {
                         self.sd_card_nca_key =
-                            Some(sd_kek.derive_xts_key(&sd_card_nca_key_source.0)?);
+                            Some(sd_kek.derive_xts_key(&seeded(sd_card_nca_key_source))?);
                     }
                 }
                 _ => (),
@@ -1217,3 +2062,221 @@ impl Keys {
         Ok(())
     }
 }
+
+#[cfg(test)]
+impl Keys {
+    /// A keyset with fixed header, key area, package2 and SD card keys, to
+    /// test the formats encrypted with them, and the modulus of a test key
+    /// signing package2 headers.
+    pub(crate) fn test_keys() -> Keys {
+        let mut keys = Keys::default();
+        let mut header_key = [0; 0x20];
+        for (idx, byte) in header_key.iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        keys.header_key = Some(AesXtsKey(header_key));
+        for (idx, key) in keys.key_area_key_application.iter_mut().enumerate() {
+            *key = Some(Aes128Key([0x40 + idx as u8; 0x10]));
+        }
+        for (idx, key) in keys.package2_keys.iter_mut().enumerate() {
+            *key = Some(Aes128Key([0x80 + idx as u8; 0x10]));
+        }
+        let mut sd_card_nca_key = [0; 0x20];
+        for (idx, byte) in sd_card_nca_key.iter_mut().enumerate() {
+            *byte = 0xC0 + idx as u8;
+        }
+        keys.sd_card_nca_key = Some(AesXtsKey(sd_card_nca_key));
+        keys.package2_fixed_key_modulus = Some(Modulus([
+            0x98, 0xC7, 0xC8, 0xFE, 0xDC, 0x8D, 0x17, 0x96, 0x96, 0xC5, 0xDB, 0x2F, 0x16, 0x89,
+            0x14, 0x34, 0x27, 0xD3, 0xF4, 0x74, 0x7A, 0x20, 0xA1, 0x81, 0x64, 0x64, 0x30, 0x3B,
+            0xDC, 0x22, 0xE2, 0x5B, 0xEA, 0xE6, 0xB2, 0xAB, 0x70, 0xC9, 0x11, 0x7A, 0xB5, 0x13,
+            0x09, 0xE0, 0x9C, 0x2E, 0xBA, 0xCA, 0xFF, 0xE0, 0x16, 0x5A, 0xF5, 0x0D, 0x56, 0x55,
+            0x38, 0x40, 0x72, 0xF1, 0xFA, 0x4F, 0xB7, 0x3B, 0xF9, 0x59, 0xD5, 0xAC, 0x33, 0x00,
+            0x93, 0x22, 0x67, 0x92, 0x4B, 0xC8, 0xCA, 0x78, 0x43, 0x05, 0xB9, 0x07, 0x7B, 0x3A,
+            0x88, 0x61, 0x22, 0x14, 0x68, 0x91, 0x24, 0xFD, 0x80, 0xF6, 0x0C, 0x8F, 0xA8, 0xBC,
+            0xA1, 0x2E, 0xE7, 0x34, 0xC7, 0x0D, 0x3D, 0x32, 0x4C, 0xF8, 0x92, 0x32, 0x6A, 0xE0,
+            0x53, 0x3B, 0x3E, 0xE8, 0x6F, 0x7E, 0x2C, 0x9F, 0xF5, 0x35, 0xA8, 0xA5, 0x3A, 0xD1,
+            0x83, 0x7E, 0xAA, 0xAE, 0x65, 0x67, 0xC7, 0x48, 0x36, 0xD7, 0x75, 0x57, 0x1E, 0xD2,
+            0x2D, 0x33, 0xFD, 0xE1, 0x32, 0xE6, 0x19, 0x43, 0x4E, 0x85, 0x37, 0x38, 0xAC, 0x9D,
+            0xF8, 0xB5, 0x69, 0x21, 0x4E, 0x27, 0x43, 0xE4, 0x70, 0xCA, 0x39, 0xF9, 0xF0, 0x96,
+            0x8E, 0x85, 0xF0, 0x10, 0xB9, 0x0B, 0xFC, 0x14, 0xD5, 0x11, 0xA6, 0x03, 0x89, 0xBC,
+            0x7E, 0xCE, 0x91, 0xDB, 0xE3, 0xD8, 0x00, 0xA3, 0x52, 0xA0, 0x21, 0xA0, 0xB6, 0xC1,
+            0xE1, 0x92, 0xB5, 0x19, 0xB8, 0xE2, 0x11, 0xCD, 0x69, 0x62, 0x08, 0x74, 0x87, 0x03,
+            0x6A, 0xB5, 0x76, 0x68, 0xB9, 0x04, 0x29, 0x6F, 0xBF, 0x09, 0x43, 0xE5, 0xEE, 0x32,
+            0xEB, 0x37, 0xD4, 0xC2, 0x60, 0xB6, 0xAE, 0x98, 0xC2, 0x57, 0x6F, 0xC3, 0x4A, 0x46,
+            0xF3, 0x8E, 0xB2, 0x80, 0x10, 0x37, 0xB6, 0x80, 0xE5, 0x09, 0xE7, 0x63, 0xDE, 0xA2,
+            0xFB, 0x97, 0x8B, 0xE9,
+        ]));
+        keys
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn xts_matches_ieee_1619_vector() {
+        let key = AesXtsKey([0; 0x20]);
+        let mut data = [0; 0x20];
+        key.xts(&mut data, 0, 0x200, true);
+        assert_eq!(
+            data,
+            [
+                0x91, 0x7C, 0xF6, 0x9E, 0xBD, 0x68, 0xB2, 0xEC, 0x9B, 0x9F, 0xE9, 0xA3, 0xEA, 0xDD,
+                0xA6, 0x92, 0xCD, 0x43, 0xD2, 0xF5, 0x95, 0x98, 0xED, 0x85, 0x8C, 0x02, 0xC2, 0x65,
+                0x2F, 0xBF, 0x92, 0x2E,
+            ]
+        );
+        key.decrypt(&mut data, 0, 0x200);
+        assert_eq!(data, [0; 0x20]);
+    }
+
+    #[test]
+    fn pss_signature_is_verified() {
+        let modulus = Modulus([
+            0xC6, 0x27, 0xA0, 0x06, 0x32, 0xF2, 0xE0, 0x57, 0xB5, 0xDE, 0x5A, 0xF4, 0xE2, 0x39,
+            0xF1, 0x04, 0x90, 0x28, 0xC5, 0x2A, 0x63, 0x36, 0x06, 0x6C, 0x09, 0xE6, 0x8E, 0xCE,
+            0x8A, 0x85, 0xDF, 0x64, 0xBE, 0x45, 0x09, 0x75, 0x52, 0xFE, 0x6A, 0xEA, 0x3D, 0x9F,
+            0xCB, 0xD6, 0x23, 0xEC, 0x6F, 0x1A, 0x17, 0x79, 0x58, 0xFE, 0x1B, 0x89, 0xB1, 0xDE,
+            0x1F, 0x95, 0x25, 0xF5, 0xCF, 0x45, 0xD1, 0xB7, 0x7A, 0xA6, 0x1B, 0x65, 0x2D, 0xEA,
+            0x2C, 0x9C, 0x83, 0x26, 0x66, 0xD0, 0x40, 0xF8, 0xF0, 0x48, 0xCE, 0x4E, 0x59, 0x7A,
+            0xF8, 0x3E, 0x8F, 0xC4, 0x3B, 0x54, 0x7B, 0xA0, 0x5E, 0x28, 0x63, 0x55, 0x7F, 0x70,
+            0x89, 0x62, 0x45, 0x83, 0x7F, 0xE4, 0xA3, 0xA6, 0x86, 0x35, 0xBB, 0x74, 0x1A, 0xF3,
+            0x25, 0x88, 0xF0, 0x30, 0x25, 0xE8, 0x6D, 0xC7, 0x49, 0x0E, 0xA5, 0x60, 0x43, 0x78,
+            0x3D, 0xF9, 0x12, 0x40, 0x1D, 0xC5, 0xA9, 0x18, 0x02, 0xF9, 0x49, 0xD2, 0x38, 0x4C,
+            0xB0, 0x4D, 0x1B, 0x1A, 0x02, 0x14, 0x11, 0xBD, 0x8E, 0x52, 0xB7, 0xE6, 0x03, 0x7A,
+            0x25, 0xB9, 0xDE, 0xF6, 0xC1, 0xC6, 0xF0, 0x93, 0xDC, 0xAF, 0x39, 0x5A, 0x75, 0x9C,
+            0x3E, 0x34, 0xF8, 0x5E, 0x79, 0x08, 0xEB, 0x79, 0x02, 0xCC, 0x2B, 0x3F, 0xBE, 0x46,
+            0xD5, 0x42, 0x18, 0xF6, 0x27, 0xCD, 0x7F, 0x2B, 0xFB, 0x38, 0xF1, 0x53, 0xFF, 0xC6,
+            0xBB, 0x7F, 0x25, 0x6F, 0x14, 0xD2, 0xA1, 0x22, 0xF3, 0x3D, 0xBA, 0x52, 0x21, 0x42,
+            0x2C, 0x62, 0xBB, 0xD2, 0x6C, 0x0D, 0x02, 0x96, 0x5B, 0x5C, 0xB6, 0x58, 0x09, 0xBD,
+            0xD3, 0x0A, 0x1D, 0x8E, 0xA0, 0xD6, 0x0E, 0x94, 0x9A, 0xD5, 0x03, 0x2F, 0x7C, 0xE6,
+            0x10, 0xD8, 0x57, 0xD8, 0x49, 0xDA, 0x53, 0xED, 0xB9, 0x61, 0x32, 0xDF, 0xCC, 0xB3,
+            0x0C, 0x03, 0x50, 0x9B,
+        ]);
+        let signature = [
+            0xB6, 0x17, 0x9E, 0x61, 0xA4, 0x07, 0x10, 0x64, 0x54, 0x1F, 0xF0, 0xB6, 0xCC, 0x7B,
+            0xBA, 0xA1, 0x23, 0x1D, 0x50, 0x13, 0x82, 0xD1, 0xA6, 0xFF, 0xD3, 0x12, 0x21, 0x74,
+            0x07, 0xAC, 0xA2, 0x2E, 0x7F, 0xC3, 0xBD, 0xF8, 0xFB, 0xDB, 0x6E, 0xFA, 0xA0, 0xD0,
+            0x2F, 0x23, 0x0F, 0x37, 0x8F, 0xB4, 0xB0, 0x3F, 0x98, 0xFC, 0xDB, 0xE1, 0x82, 0x6D,
+            0xBE, 0xC3, 0x77, 0x36, 0xCE, 0x8C, 0x70, 0xF8, 0x80, 0x49, 0x2A, 0xFD, 0x1B, 0x16,
+            0x0E, 0xFB, 0x41, 0xD3, 0xF0, 0x7D, 0x8C, 0x41, 0xC6, 0x04, 0xFA, 0x82, 0xF9, 0x51,
+            0x86, 0x59, 0x97, 0xB6, 0x6C, 0x2E, 0x6D, 0x2E, 0xD9, 0x58, 0x69, 0x92, 0x6C, 0xFB,
+            0xFD, 0xA9, 0xD3, 0x90, 0xB6, 0x83, 0x07, 0x59, 0xD6, 0x18, 0x85, 0xA1, 0xED, 0x09,
+            0xA9, 0x2B, 0xDD, 0x92, 0x0A, 0x50, 0x68, 0x3C, 0x19, 0x84, 0x3F, 0xF8, 0xA5, 0xED,
+            0xAB, 0x31, 0x24, 0x84, 0x41, 0xC7, 0xEB, 0x2B, 0x8E, 0xC5, 0x99, 0xC1, 0x0D, 0x51,
+            0xBC, 0xA4, 0xAE, 0xDC, 0x3F, 0xD0, 0xFC, 0xFA, 0x1B, 0xA1, 0x10, 0x5A, 0x53, 0x88,
+            0xF9, 0xA1, 0xFE, 0xDE, 0x94, 0xAB, 0x68, 0x15, 0x85, 0x9F, 0x68, 0x95, 0xA6, 0x97,
+            0x29, 0xCB, 0xC0, 0xE8, 0x56, 0x3A, 0x9F, 0x69, 0xC4, 0xBD, 0xD0, 0xF7, 0xDA, 0xA8,
+            0x02, 0xC7, 0x96, 0xF3, 0xB8, 0xC5, 0xF5, 0x3A, 0xE7, 0xF0, 0xE6, 0xC1, 0x3D, 0xBB,
+            0xAA, 0x91, 0xE4, 0xA4, 0x2D, 0xDA, 0x33, 0x49, 0x73, 0xA8, 0xCF, 0xC6, 0xC5, 0xAB,
+            0x32, 0x74, 0x07, 0x48, 0x3D, 0x4B, 0x16, 0x40, 0x8E, 0xF7, 0xD7, 0xCC, 0xFF, 0xE4,
+            0xB8, 0xCE, 0x5A, 0xFA, 0x52, 0x3C, 0x79, 0x23, 0x85, 0x78, 0xAB, 0x66, 0xBF, 0xB2,
+            0xFC, 0x1A, 0xBD, 0x00, 0x1C, 0xE1, 0x9F, 0x4B, 0xAD, 0xD5, 0x53, 0x94, 0x22, 0x99,
+            0xD8, 0x2E, 0x52, 0x91,
+        ];
+        assert!(modulus.verify_pss_sha256(&signature, b"linkle package2 header"));
+        assert!(!modulus.verify_pss_sha256(&signature, b"linkle package2 headers"));
+    }
+
+    #[test]
+    fn key_parents_are_named() {
+        let mut keys = Keys::default();
+        keys.master_keks[0xA] = Some(Aes128Key([0; 0x10]));
+        let parent = |name: &str, present| (Some(String::from(name)), present);
+
+        let i = 0xA;
+        assert_eq!(
+            key_parents!(keys; master_key_source, master_keks[i], keyblob_keys[0], tsec_root_key[i - 6] if i >= 6),
+            vec![
+                parent("master_key_source", false),
+                parent("master_kek_0a", true),
+                parent("keyblob_key_00", false),
+                parent("tsec_root_key_04", false),
+            ]
+        );
+        let i = 5;
+        assert_eq!(
+            key_parents!(keys; tsec_root_key[i - 6] if i >= 6),
+            vec![(None, false)]
+        );
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231_vector() {
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            [
+                0x5B, 0xDC, 0xC1, 0x46, 0xBF, 0x60, 0x75, 0x4E, 0x6A, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xC7, 0x5A, 0x00, 0x3F, 0x08, 0x9D, 0x27, 0x39, 0x83, 0x9D, 0xEC, 0x58, 0xB9,
+                0x64, 0xEC, 0x38, 0x43,
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_keys_are_caught_by_their_fingerprint() {
+        let mut keys = Keys::default();
+        keys.master_key_source = Some(Aes128Key([0; 0x10]));
+        keys.keyblob_key_sources[1] = Some(Aes128Key([0; 0x10]));
+        keys.tsec_root_kek = Some(Aes128Key([0; 0x10]));
+        assert_eq!(
+            keys.wrong_keys(),
+            vec!["keyblob_key_source_01", "master_key_source"]
+        );
+        assert!(keys.check_fingerprints().is_err());
+    }
+
+    #[test]
+    fn right_keys_pass_their_fingerprint() {
+        let mut keys = Keys::default();
+        let mut master_key_source = [0; 0x10];
+        parse_hex("D8A2410AC6C59001C61D6A267C513F3C", &mut master_key_source).unwrap();
+        keys.master_key_source = Some(Aes128Key(master_key_source));
+        assert!(keys.wrong_keys().is_empty());
+        assert!(keys.check_fingerprints().is_ok());
+    }
+
+    #[test]
+    fn wrong_derived_keys_are_caught_by_their_fingerprint() {
+        let mut keys = Keys {
+            derived_key_fingerprints: &[(
+                "titlekek_00",
+                "b8f12ea8c9a95d4b4641b03d9fa5a71ad30b44ed6cd4bf793bbe1a5801b986d4",
+            )],
+            ..Default::default()
+        };
+        keys.titlekeks[0] = Some(Aes128Key([0x11; 0x10]));
+        keys.titlekeks[1] = Some(Aes128Key([0x22; 0x10]));
+        assert!(keys.wrong_keys().is_empty());
+        keys.titlekeks[0] = Some(Aes128Key([0x22; 0x10]));
+        assert_eq!(keys.wrong_keys(), vec!["titlekek_00"]);
+    }
+
+    #[test]
+    fn json_keyset_roundtrip() {
+        let mut keys = Keys::default();
+        keys.master_key_source = Some(Aes128Key([0x11; 0x10]));
+        keys.header_key = Some(AesXtsKey([0x22; 0x20]));
+        keys.keyblob_keys[2] = Some(Aes128Key([0x33; 0x10]));
+        keys.add_title_key([0x44; 0x10], [0x55; 0x10]);
+
+        let mut data = Vec::new();
+        keys.write_json(&mut data, false).unwrap();
+        let json: KeysJson = serde_json::from_slice(&data).unwrap();
+        assert_eq!(json.keys.len(), 2);
+
+        let mut new_keys = Keys::default();
+        new_keys
+            .read_from_section(&json.keys.into_iter().collect())
+            .unwrap();
+        new_keys
+            .read_title_keys_from_section(&json.title_keys.into_iter().collect())
+            .unwrap();
+        assert_eq!(new_keys.master_key_source.as_ref().unwrap().0, [0x11; 0x10]);
+        assert_eq!(new_keys.header_key.as_ref().unwrap().0, [0x22; 0x20]);
+        assert!(new_keys.keyblob_keys[2].is_none());
+        assert_eq!(new_keys.title_key(&[0x44; 0x10]).unwrap(), [0x55; 0x10]);
+    }
+}
@@ -10,6 +10,11 @@ pub fn align_up<T: Num + Not<Output = T> + BitAnd<Output = T> + Copy>(addr: T, a
     align_down(addr + (align - T::one()), align)
 }
 
+/// Formats the bytes as a lowercase hexstring.
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 // Why is this not a trait...
 pub trait TryClone: Sized {
     fn try_clone(&self) -> std::io::Result<Self>;
@@ -21,6 +26,27 @@ impl TryClone for std::fs::File {
     }
 }
 
+impl<T: Clone> TryClone for io::Cursor<T> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// Computes the position a seek on a stream of `size` bytes ends up at.
+pub fn seek_position(pos: u64, size: u64, from: io::SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match from {
+        io::SeekFrom::Start(val) => return Ok(val),
+        io::SeekFrom::Current(val) => (pos, val),
+        io::SeekFrom::End(val) => (size, val),
+    };
+    if offset < 0 {
+        base.checked_sub(-offset as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before position 0"))
+    } else {
+        Ok(base + offset as u64)
+    }
+}
+
 pub struct ReadRange<R> {
     inner: R,
     start_from: u64,
@@ -44,6 +70,17 @@ impl<R> ReadRange<R> {
     }
 }
 
+impl<R: TryClone> TryClone for ReadRange<R> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(ReadRange {
+            inner: self.inner.try_clone()?,
+            start_from: self.start_from,
+            size: self.size,
+            inner_pos: self.inner_pos,
+        })
+    }
+}
+
 impl<R: io::Read> io::Read for ReadRange<R> {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
         if self.size < self.inner_pos + buf.len() as u64 {